@@ -1,36 +1,39 @@
 use actix_web::{web, App, HttpResponse, HttpServer, Responder};
 use futures::StreamExt;
-use rdkafka::config::ClientConfig;
-use rdkafka::consumer::{Consumer, StreamConsumer};
+use kafka_support::commit::{self, CommitOnRevoke, CommittingConsumer, OffsetCommitter};
+use kafka_support::config::Settings;
+use kafka_support::dead_letter::DeadLetterQueue;
+use kafka_support::headers;
+use kafka_support::health::{self, ConsumerProbe, Progress};
+use kafka_support::shutdown::{self, Shutdown};
+use product_domain::{
+    DecodeError, EventMetadata, ProcessError, ProcessOutcome, Product, ProductEvent, ProductStore,
+    UnknownEventPolicy,
+};
+use rdkafka::consumer::{CommitMode, Consumer};
 use rdkafka::message::Message;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Mutex;
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Product {
-    id: String,
-    r#type: String,
-    name: String,
-    version: String,
-}
+use rdkafka::producer::FutureProducer;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize)]
-pub struct ProductEvent {
-    id: String,
-    name: String,
-    r#type: String,
-    version: String,
-    event: String,
+pub struct AppState {
+    products: Mutex<ProductStore>,
+    unknown_event_policy: UnknownEventPolicy,
 }
 
-pub struct AppState {
-    products: Mutex<HashMap<String, Product>>,
+impl AppState {
+    pub fn new(unknown_event_policy: UnknownEventPolicy) -> Self {
+        AppState {
+            products: Mutex::new(ProductStore::new()),
+            unknown_event_policy,
+        }
+    }
 }
 
 async fn get_all(data: web::Data<AppState>) -> impl Responder {
     let products = data.products.lock().unwrap();
-    let products: Vec<&Product> = products.values().collect();
+    let products: Vec<&Product> = products.products().collect();
     HttpResponse::Ok().json(products)
 }
 
@@ -43,88 +46,198 @@ async fn get_by_id(data: web::Data<AppState>, product_id: web::Path<String>) ->
     }
 }
 
-pub fn product_event_processor(data: &web::Data<AppState>, payload: &[u8]) {
-    let product_event: ProductEvent =
-        serde_json::from_slice(payload).expect("Error deserializing product");
-    let product = Product {
-        id: product_event.id.clone(),
-        r#type: product_event.r#type.clone(),
-        name: product_event.name.clone(),
-        version: product_event.version.clone(),
+/// Applies a product event to the store, skipping redelivered and stale
+/// versions.
+///
+/// Events with an unknown type are skipped when the policy is
+/// [`UnknownEventPolicy::Ignore`] and returned as an error otherwise, leaving it
+/// to the caller to reject or dead-letter them. So are events whose headers
+/// name a payload schema this consumer cannot read.
+pub fn product_event_processor(
+    data: &web::Data<AppState>,
+    payload: &[u8],
+    metadata: &EventMetadata,
+) -> Result<ProcessOutcome, ProcessError> {
+    metadata.check_schema()?;
+    let product_event = match ProductEvent::from_slice(payload) {
+        Ok(product_event) => product_event,
+        Err(DecodeError::UnknownEventType(e))
+            if data.unknown_event_policy == UnknownEventPolicy::Ignore =>
+        {
+            println!(
+                "Ignoring event {}: {}",
+                metadata.event_id.as_deref().unwrap_or("without an event id"),
+                e
+            );
+            return Ok(ProcessOutcome::Ignored);
+        }
+        Err(e) => return Err(e.into()),
     };
     let mut products = data.products.lock().unwrap();
-    match product_event.event.as_str() {
-        "CREATED" | "UPDATED" => {
-            products.insert(product_event.id.clone(), product);
-        }
-        "DELETED" => {
-            products.remove(&product.id);
-        }
-        _ => {
-            eprintln!("Unknown event type");
-        }
-    }
+    products.apply(product_event)
 }
 
-async fn kafka_consumer(data: web::Data<AppState>) {
-    let consumer: StreamConsumer = ClientConfig::new()
-        .set("group.id", "products-group")
-        .set("bootstrap.servers", "localhost:9092")
+/// Consumes until `shutdown` is triggered, finishing the message in hand
+/// before draining. Offsets are committed once their messages have been
+/// processed, so a crash replays what was not. Returns whether the drain was
+/// clean.
+async fn kafka_consumer(
+    data: web::Data<AppState>,
+    settings: Settings,
+    consumer: Arc<CommittingConsumer>,
+    progress: Arc<Progress>,
+    shutdown: Shutdown,
+) -> bool {
+    let dead_letter_producer: FutureProducer = settings
+        .client_config()
         .create()
-        .expect("Consumer creation failed");
+        .expect("Producer creation failed");
+    let dead_letter_queue = DeadLetterQueue::new(
+        dead_letter_producer,
+        settings.topic("dead_letter"),
+        settings.unknown_event_policy(),
+    );
 
     consumer
-        .subscribe(&["products"])
+        .subscribe(&[settings.topic("products")])
         .expect("Can't subscribe to topic");
 
     let mut message_stream = consumer.stream();
+    let mut committer = OffsetCommitter::new(settings.commit_strategy());
 
-    while let Some(message) = message_stream.next().await {
+    loop {
+        let message = tokio::select! {
+            _ = shutdown.triggered() => break,
+            _ = committer.due() => {
+                if let Err(e) = committer.commit(consumer.as_ref(), CommitMode::Sync) {
+                    eprintln!("Error committing offsets: {}", e);
+                }
+                continue;
+            }
+            message = message_stream.next() => match message {
+                Some(message) => message,
+                None => break,
+            },
+        };
         match message {
             Ok(m) => {
+                let mut handled = Ok(());
                 if let Some(payload) = m.payload() {
-                    product_event_processor(&data, payload);
+                    let metadata = EventMetadata::from_headers(headers::pairs(&m));
+                    if let Err(e) = product_event_processor(&data, payload, &metadata) {
+                        handled = dead_letter_queue.handle_process_error(&m, &e).await;
+                    }
+                }
+                if let Err(e) = handled {
+                    eprintln!(
+                        "Error sending event to {} topic, consuming it again: {}",
+                        dead_letter_queue.topic(),
+                        e
+                    );
+                    if let Err(e) = commit::rewind(consumer.as_ref(), &m) {
+                        eprintln!("Error rewinding to event: {}", e);
+                    }
+                    continue;
+                }
+                progress.record(&m);
+                if let Err(e) = committer.processed(consumer.as_ref(), &m) {
+                    eprintln!("Error committing offsets: {}", e);
                 }
             }
             Err(e) => eprintln!("Kafka error: {}", e),
         }
     }
+
+    drop(message_stream);
+    shutdown::drain(
+        consumer.as_ref(),
+        &dead_letter_queue,
+        settings.shutdown_timeout(),
+    )
 }
 
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let products = Mutex::new(HashMap::new());
-    let data = web::Data::new(AppState { products });
+async fn main() -> std::io::Result<ExitCode> {
+    let settings = Settings::builder("127.0.0.1:8080")
+        .group_id("products-group")
+        .topic("products", "products")
+        .topic("dead_letter", "products_dead_letter")
+        .load()
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(2)
+        });
+    println!("Kafka client settings: {}", settings);
+    let bind = settings.bind();
+    let shutdown_timeout = settings.shutdown_timeout();
+    let data = web::Data::new(AppState::new(settings.unknown_event_policy()));
+
+    let consumer =
+        commit::committing_consumer(&settings.consumer_config()).expect("Consumer creation failed");
+    let progress = Arc::new(Progress::new());
+    let shutdown = Shutdown::new();
+    let probe = web::Data::new(ConsumerProbe::new(
+        consumer.clone(),
+        progress.clone(),
+        settings.topics().map(str::to_string).collect(),
+        shutdown.clone(),
+        Duration::from_secs(5),
+    ));
 
     // Start Kafka consumer
-    let data_clone = data.clone();
-    actix_rt::spawn(async move {
-        kafka_consumer(data_clone).await;
-    });
+    let kafka = actix_rt::spawn(kafka_consumer(
+        data.clone(),
+        settings,
+        consumer,
+        progress,
+        shutdown.clone(),
+    ));
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(data.clone())
+            .app_data(probe.clone())
+            .route("/healthz", web::get().to(health::healthz))
+            .route("/readyz", web::get().to(health::readyz::<ConsumerProbe<CommitOnRevoke>>))
             .route("/products", web::get().to(get_all))
             .route("/products/{id}", web::get().to(get_by_id))
             .route("/product/{id}", web::get().to(get_by_id))
     })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+    .bind(bind)?
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout.as_secs())
+    .run();
+
+    // Stop accepting HTTP requests first, then let the consumer drain
+    let server_handle = server.handle();
+    let on_signal = shutdown.clone();
+    actix_rt::spawn(shutdown::stop_on_signal(async move {
+        server_handle.stop(true).await;
+        on_signal.trigger();
+    }));
+
+    server.await?;
+    shutdown.trigger();
+    let clean = kafka.await.unwrap_or(false);
+    Ok(shutdown::exit_code(clean))
 }
 
 #[cfg(test)]
 mod tests {
 
-use expectest::{expect, prelude::be_some};
+use expectest::{expect, prelude::{be_ok, be_some}};
 use pact_consumer::{matching_regex, prelude::*};
 use serde_json::Value;
+use pact_consumer::builders::MessageInteractionBuilder;
+use pact_models::matchingrules::{MatchingRule, RuleLogic};
+use pact_models::path_exp::DocPath;
+use product_domain::{EventMetadata, ProcessError, ProcessOutcome, ProductVersion, UnknownEventPolicy};
 use crate::{product_event_processor, AppState};
-use std::collections::HashMap;
-use std::sync::Mutex;
 use actix_web::web;
 use expectest::matchers::be_equal_to;
+const UUID: &str = "^[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}$";
+const ISO_8601: &str = r"^\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(\.\d+)?Z$";
+
 #[test]
 fn consumes_a_product_event_update_message() {
     // Define the Pact for the test (you can setup multiple interactions by chaining the given or message_interaction calls)
@@ -132,35 +245,45 @@ fn consumes_a_product_event_update_message() {
     let mut pact_builder =
         // Define the message consumer and provider by name
         pact_consumer::builders::PactBuilder::new_v4("pactflow-example-consumer-rust-kafka-async", "pactflow-example-provider-rust-kafka-async");
-    pact_builder
-
-        // Adds an interaction given the message description and type.
-        .message_interaction("a product event update", |mut i| {
-            // Can set the test name (optional)
-            i.test_name("consumes_a_product_event_update_message");
-            // // defines a provider state. It is optional.
-            // i.given("some state");
-            // // defines a provider state with parameters. It is optional.
-            // i.given_with_params("some state with params {param}",&json!({
-            //     "param": "some param"
-            //   }));
-            // Set the contents of the message. Here we use a JSON pattern, so that matching rules are applied
-            i.json_body(json_pattern!({
-              "id": like!("some-uuid-1234-5678"),
-              "type": like!("Product Range"),
-              "name": like!("Some Product"),
-              "version": like!("v1"),
-              "event": matching_regex!("^(CREATED|UPDATED|DELETED)$","UPDATED")
-            }));
-            // Set any required metadata
-            i.metadata("kafka_topic", "products");
-            // Need to return the mutated interaction builder
-            i
-        });
+    // Builds an interaction given the message description. We build it
+    // ourselves rather than with message_interaction, so we can add the
+    // metadata matching rules the builder does not keep.
+    let mut interaction = MessageInteractionBuilder::new("a product event update");
+    let i = &mut interaction;
+    // Can set the test name (optional)
+    i.test_name("consumes_a_product_event_update_message");
+    // // defines a provider state. It is optional.
+    // i.given("some state");
+    // // defines a provider state with parameters. It is optional.
+    // i.given_with_params("some state with params {param}",&json!({
+    //     "param": "some param"
+    //   }));
+    // Set the contents of the message. Here we use a JSON pattern, so that matching rules are applied
+    i.json_body(json_pattern!({
+      "id": like!("some-uuid-1234-5678"),
+      "type": like!("Product Range"),
+      "name": like!("Some Product"),
+      "version": like!("v1"),
+      "event": matching_regex!("^(CREATED|UPDATED|DELETED)$","UPDATED")
+    }));
+    // Set any required metadata
+    i.metadata("kafka_topic", "products");
+    // The standard headers of every product event
+    i.metadata(EventMetadata::CONTENT_TYPE, "application/json");
+    i.metadata(EventMetadata::EVENT_TYPE, "UPDATED");
+    i.metadata(EventMetadata::SCHEMA_VERSION, EventMetadata::CURRENT_SCHEMA_VERSION);
+    i.metadata(EventMetadata::PRODUCER_ID, "provider-rust-kafka-async");
+    i.metadata(EventMetadata::EVENT_ID, "5b1c1f6e-3f43-4d8e-9a1c-0e4c2f7b8d21");
+    i.metadata(EventMetadata::PRODUCED_AT, "2024-05-01T12:00:00.000Z");
+    let mut message = interaction.build();
+    // The event id and timestamp differ for every event, so only their format is part of the contract
+    let metadata_rules = message.contents.matching_rules.add_category("metadata");
+    metadata_rules.add_rule(DocPath::root().join(EventMetadata::EVENT_ID), MatchingRule::Regex(UUID.to_string()), RuleLogic::And);
+    metadata_rules.add_rule(DocPath::root().join(EventMetadata::PRODUCED_AT), MatchingRule::Regex(ISO_8601.to_string()), RuleLogic::And);
+    pact_builder.push_interaction(&message);
 
     // Arrange. setup product database
-    let products = Mutex::new(HashMap::new());
-    let data = web::Data::new(AppState { products });
+    let data = web::Data::new(AppState::new(UnknownEventPolicy::Reject));
     
     // This will return each message configured with the Pact builder. We need to process them
     // with out message handler (it should be the one used to actually process your messages).
@@ -168,19 +291,22 @@ fn consumes_a_product_event_update_message() {
         // Process the message here as it would if it came off the queue
         let message_bytes = message.contents.contents.value().unwrap();
         let kafka_topic = message.contents.metadata.get("kafka_topic");
+        let metadata = EventMetadata::from_headers(message.contents.metadata.iter()
+            .filter_map(|(name, value)| value.as_str().map(|value| (name.as_str(), value.as_bytes()))));
         let _message: Value = serde_json::from_slice(&message_bytes).unwrap();
 
-        // Send the message to our message processor
-        product_event_processor(&data,&message_bytes);
+        // Send the message to our message processor, with the headers it came with
+        expect!(metadata.schema_version.as_deref()).to(be_some().value("1"));
+        expect!(product_event_processor(&data, &message_bytes, &metadata)).to(be_ok());
         
         // assert of the state of our product database, after processing the message
         let products = data.products.lock().unwrap();
         let product = products.get("some-uuid-1234-5678").unwrap();
         println!("{:?}", product);
-        expect!(product.id.as_str()).to(be_equal_to("some-uuid-1234-5678".to_string()));
+        expect!(product.id.clone()).to(be_equal_to(Some("some-uuid-1234-5678".to_string())));
         expect!(product.name.clone()).to(be_equal_to("Some Product".to_string()));
         expect!(product.r#type.clone()).to(be_equal_to("Product Range".to_string()));
-        expect!(product.version.clone()).to(be_equal_to("v1".to_string()));
+        expect!(product.version).to(be_equal_to(Some(ProductVersion::FIRST)));
 
         // assert the correct topic is included in our message
         expect!(kafka_topic)
@@ -188,4 +314,53 @@ fn consumes_a_product_event_update_message() {
     }
 }
 
-}
\ No newline at end of file
+#[test]
+fn rejects_unknown_event_types_unless_configured_to_ignore_them() {
+    let payload = br#"{"id":"some-uuid-1234-5678","type":"Product Range","name":"Some Product","version":"v1","event":"ARCHIVED"}"#;
+
+    let rejecting = web::Data::new(AppState::new(UnknownEventPolicy::Reject));
+    let error = product_event_processor(&rejecting, payload, &EventMetadata::default()).unwrap_err();
+    expect!(matches!(error, ProcessError::UnknownEventType(e) if e.0 == "ARCHIVED")).to(be_equal_to(true));
+
+    let ignoring = web::Data::new(AppState::new(UnknownEventPolicy::Ignore));
+    expect!(product_event_processor(&ignoring, payload, &EventMetadata::default())).to(be_ok().value(ProcessOutcome::Ignored));
+    expect!(ignoring.products.lock().unwrap().is_empty()).to(be_equal_to(true));
+}
+
+#[test]
+fn reports_malformed_events_instead_of_panicking() {
+    let data = web::Data::new(AppState::new(UnknownEventPolicy::Reject));
+    let result = product_event_processor(&data, b"not a product event", &EventMetadata::default());
+    expect!(matches!(result, Err(ProcessError::Decode(_)))).to(be_equal_to(true));
+    expect!(data.products.lock().unwrap().is_empty()).to(be_equal_to(true));
+}
+
+#[test]
+fn rejects_events_in_an_unsupported_schema() {
+    let data = web::Data::new(AppState::new(UnknownEventPolicy::Reject));
+    let payload = br#"{"id":"some-uuid-1234-5678","type":"Product Range","name":"Some Product","version":"v1","event":"CREATED"}"#;
+    let metadata = EventMetadata::from_headers([(EventMetadata::SCHEMA_VERSION, &b"2"[..])]);
+
+    let error = product_event_processor(&data, payload, &metadata).unwrap_err();
+    expect!(matches!(error, ProcessError::UnsupportedSchema { .. })).to(be_equal_to(true));
+    expect!(error.is_poison(UnknownEventPolicy::Reject)).to(be_equal_to(true));
+    expect!(data.products.lock().unwrap().is_empty()).to(be_equal_to(true));
+}
+
+#[test]
+fn skips_redelivered_and_out_of_order_events() {
+    let data = web::Data::new(AppState::new(UnknownEventPolicy::Reject));
+    let event = |event: &str, version: &str| {
+        format!(r#"{{"id":"some-uuid-1234-5678","type":"Product Range","name":"Some Product","version":"{}","event":"{}"}}"#, version, event)
+    };
+
+    expect!(product_event_processor(&data, event("CREATED", "v1").as_bytes(), &EventMetadata::default())).to(be_ok().value(ProcessOutcome::Applied));
+    expect!(product_event_processor(&data, event("DELETED", "v2").as_bytes(), &EventMetadata::default())).to(be_ok().value(ProcessOutcome::Applied));
+    expect!(product_event_processor(&data, event("DELETED", "v2").as_bytes(), &EventMetadata::default())).to(be_ok().value(ProcessOutcome::Duplicate));
+
+    let late_create = product_event_processor(&data, event("CREATED", "v1").as_bytes(), &EventMetadata::default());
+    expect!(matches!(late_create, Err(ProcessError::StaleVersion { .. }))).to(be_equal_to(true));
+    expect!(data.products.lock().unwrap().get("some-uuid-1234-5678").is_none()).to(be_equal_to(true));
+}
+
+}
@@ -0,0 +1,126 @@
+//! Runs the consumer against an in-process mock broker until it is ready, stops
+//! it with SIGTERM and checks that it drained: the process exits cleanly and
+//! the offset of the event it consumed is committed.
+#![cfg(unix)]
+
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rdkafka::mocking::MockCluster;
+use rdkafka::producer::{BaseProducer, BaseRecord, Producer};
+use rdkafka::{ClientConfig, Offset, TopicPartitionList};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, ExitStatus};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const EVENT: &str = r#"{"id":"some-uuid-1234-5678","type":"Product Range","name":"Some Product","version":"v1","event":"CREATED"}"#;
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn http_status(port: u16, path: &str) -> Option<u16> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).ok()?;
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+    )
+    .ok()?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok()?;
+    response.split_whitespace().nth(1)?.parse().ok()
+}
+
+fn wait_until(timeout: Duration, mut done: impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if done() {
+            return true;
+        }
+        sleep(Duration::from_millis(100));
+    }
+    false
+}
+
+fn wait_for_exit(child: &mut Child, timeout: Duration) -> Option<ExitStatus> {
+    let mut status = None;
+    wait_until(timeout, || {
+        status = child.try_wait().unwrap();
+        status.is_some()
+    });
+    status
+}
+
+#[test]
+fn drains_and_commits_on_sigterm() {
+    let cluster = MockCluster::new(1).unwrap();
+    cluster.create_topic("products", 1, 1).unwrap();
+    cluster.create_topic("products_dead_letter", 1, 1).unwrap();
+    let brokers = cluster.bootstrap_servers();
+
+    let producer: BaseProducer = ClientConfig::new()
+        .set("bootstrap.servers", &brokers)
+        .create()
+        .unwrap();
+    producer
+        .send(BaseRecord::<(), str>::to("products").payload(EVENT))
+        .unwrap();
+    producer.flush(Duration::from_secs(10)).unwrap();
+
+    let port = free_port();
+    let mut consumer = Command::new(env!("CARGO_BIN_EXE_consumer-rust-kafka-async"))
+        .env("KAFKA_BOOTSTRAP_SERVERS", &brokers)
+        .env("HTTP_BIND", format!("127.0.0.1:{}", port))
+        .env("KAFKA_PROPERTY_AUTO_OFFSET_RESET", "earliest")
+        .env("SHUTDOWN_TIMEOUT_SECS", "5")
+        .spawn()
+        .unwrap();
+
+    let consumed = wait_until(Duration::from_secs(30), || {
+        http_status(port, "/products/some-uuid-1234-5678") == Some(200)
+    });
+    if !consumed {
+        consumer.kill().unwrap();
+        panic!("the consumer never applied the event");
+    }
+
+    let ready = wait_until(Duration::from_secs(30), || {
+        http_status(port, "/readyz") == Some(200)
+    });
+    if !ready {
+        consumer.kill().unwrap();
+        panic!("the consumer never became ready");
+    }
+
+    let killed = Command::new("kill")
+        .args(["-TERM", &consumer.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(killed.success());
+
+    let status = wait_for_exit(&mut consumer, Duration::from_secs(15));
+    if status.is_none() {
+        consumer.kill().unwrap();
+    }
+    assert_eq!(status.and_then(|status| status.code()), Some(0));
+
+    let group: BaseConsumer = ClientConfig::new()
+        .set("bootstrap.servers", &brokers)
+        .set("group.id", "products-group")
+        .create()
+        .unwrap();
+    let mut partitions = TopicPartitionList::new();
+    partitions.add_partition("products", 0);
+    let committed = group
+        .committed_offsets(partitions, Duration::from_secs(10))
+        .unwrap();
+    assert_eq!(
+        committed.find_partition("products", 0).unwrap().offset(),
+        Offset::Offset(1)
+    );
+}
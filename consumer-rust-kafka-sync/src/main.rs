@@ -1,38 +1,71 @@
 use actix_web::{web, App, HttpResponse, HttpServer, Responder};
 use futures::StreamExt;
-use rdkafka::config::ClientConfig;
-use rdkafka::consumer::{Consumer, StreamConsumer};
-use rdkafka::message::Message;
+use kafka_support::commit::{self, CommitOnRevoke, CommittingConsumer, OffsetCommitter};
+use kafka_support::config::{ProducerMode, Settings};
+use kafka_support::dead_letter::DeadLetterQueue;
+use kafka_support::headers;
+use kafka_support::health::{self, ConsumerProbe, Progress};
+use kafka_support::request_reply::{self, ReplyPublisher};
+use kafka_support::shutdown::{self, Shutdown};
+use kafka_support::transaction::Transactions;
+use product_domain::{
+    DecodeError, EventMetadata, ProcessError, ProcessOutcome, Product, ProductEvent, ProductReply,
+    ProductStore, UnknownEventPolicy,
+};
+use rdkafka::consumer::{CommitMode, Consumer};
+use rdkafka::error::KafkaError;
+use rdkafka::message::{BorrowedMessage, Message};
 use rdkafka::producer::{FutureProducer, FutureRecord};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Mutex;
+use rdkafka::{Offset, TopicPartitionList};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Product {
-    id: String,
-    r#type: String,
-    name: String,
-    version: String,
+/// How many replies may await their delivery report before the consumer
+/// waits for one.
+const MAX_REPLIES_IN_FLIGHT: usize = 1000;
+
+/// How long each call to the brokers in a reply transaction may take.
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How replies are published.
+enum Replies {
+    /// Without waiting for each delivery, while the consumer commits the
+    /// requests' offsets following its commit strategy once their replies
+    /// are delivered, and consumes again the requests whose replies fail.
+    Pipelined(ReplyPublisher),
+    /// Each in a transaction with its request's offset, so a request is
+    /// answered exactly once: a reply is only visible to requesters reading
+    /// committed records once its request is committed, and a request whose
+    /// transaction fails is consumed again.
+    Transactional(Transactions),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ProductEvent {
-    id: String,
-    name: String,
-    r#type: String,
-    version: String,
-    event: String,
+/// What is left to do for a request that was handled.
+enum Handled {
+    /// Nothing: it gets no reply, or its reply is committed with it.
+    Done,
+    /// Its reply is queued, and it is done once that is delivered.
+    ReplyQueued,
 }
 
 pub struct AppState {
-    products: Mutex<HashMap<String, Product>>,
+    products: Mutex<ProductStore>,
+    unknown_event_policy: UnknownEventPolicy,
+}
+
+impl AppState {
+    pub fn new(unknown_event_policy: UnknownEventPolicy) -> Self {
+        AppState {
+            products: Mutex::new(ProductStore::new()),
+            unknown_event_policy,
+        }
+    }
 }
 
 async fn get_all(data: web::Data<AppState>) -> impl Responder {
     let products = data.products.lock().unwrap();
-    let products: Vec<&Product> = products.values().collect();
+    let products: Vec<&Product> = products.products().collect();
     HttpResponse::Ok().json(products)
 }
 
@@ -45,118 +78,392 @@ async fn get_by_id(data: web::Data<AppState>, product_id: web::Path<String>) ->
     }
 }
 
-pub fn product_event_processor(data: &web::Data<AppState>, payload: &[u8]) {
-    let product_event: ProductEvent =
-        serde_json::from_slice(payload).expect("Error deserializing product");
-    let product = Product {
-        id: product_event.id.clone(),
-        r#type: product_event.r#type.clone(),
-        name: product_event.name.clone(),
-        version: product_event.version.clone(),
+/// Applies a product event to the store, skipping redelivered and stale
+/// versions. Returns the event, redelivered ones included, so the request can
+/// be answered from it, or `None` if it was ignored.
+///
+/// Events with an unknown type are skipped when the policy is
+/// [`UnknownEventPolicy::Ignore`] and returned as an error otherwise, leaving it
+/// to the caller to reject or dead-letter them. So are events whose headers
+/// name a payload schema this consumer cannot read.
+pub fn product_event_processor(
+    data: &web::Data<AppState>,
+    payload: &[u8],
+    metadata: &EventMetadata,
+) -> Result<Option<ProductEvent>, ProcessError> {
+    metadata.check_schema()?;
+    let product_event = match ProductEvent::from_slice(payload) {
+        Ok(product_event) => product_event,
+        Err(DecodeError::UnknownEventType(e))
+            if data.unknown_event_policy == UnknownEventPolicy::Ignore =>
+        {
+            println!(
+                "Ignoring event {}: {}",
+                metadata.event_id.as_deref().unwrap_or("without an event id"),
+                e
+            );
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
     };
     let mut products = data.products.lock().unwrap();
-    match product_event.event.as_str() {
-        "CREATED" | "UPDATED" => {
-            products.insert(product_event.id.clone(), product);
+    match products.apply(product_event.clone())? {
+        ProcessOutcome::Applied | ProcessOutcome::Duplicate => Ok(Some(product_event)),
+        ProcessOutcome::Ignored => Ok(None),
+    }
+}
+
+fn product_event_reply_generator(reply: &ProductReply) -> Vec<u8> {
+    serde_json::to_vec(reply).expect("Error serializing reply")
+}
+
+/// Applies a request and publishes the product it produced, or why it could
+/// not be applied, to the reply topic. An error means the request has to be
+/// consumed again.
+async fn handle_request(
+    data: &web::Data<AppState>,
+    settings: &Settings,
+    consumer: &CommittingConsumer,
+    dead_letter_queue: &DeadLetterQueue,
+    replies: &mut Replies,
+    message: &BorrowedMessage<'_>,
+) -> Result<Handled, KafkaError> {
+    let Some(payload) = message.payload() else {
+        return Ok(Handled::Done);
+    };
+    // Nobody is waiting for the reply, so don't apply a change the requester
+    // has already reported as failed
+    if request_reply::expired(message) {
+        println!(
+            "Dropping expired request {}",
+            request_reply::correlation_id(message).unwrap_or("without a correlation id")
+        );
+        return Ok(Handled::Done);
+    }
+    let metadata = EventMetadata::from_headers(headers::pairs(message));
+    let reply = match product_event_processor(data, payload, &metadata) {
+        // redelivered requests are answered again
+        Ok(Some(product_event)) => {
+            println!("incoming event {:?}", product_event);
+            ProductReply::from(Ok(Product::from(product_event)))
+        }
+        // ignored events get no reply
+        Ok(None) => return Ok(Handled::Done),
+        // failed requests are answered with why they failed, once parked if
+        // they are poison
+        Err(e) => {
+            if let Err(dead_letter_error) =
+                dead_letter_queue.handle_process_error(message, &e).await
+            {
+                eprintln!(
+                    "Error sending request to {} topic: {}",
+                    dead_letter_queue.topic(),
+                    dead_letter_error
+                );
+                return Err(dead_letter_error);
+            }
+            ProductReply::from(Err(&e))
         }
-        "DELETED" => {
-            products.remove(&product.id);
+    };
+    let reply_payload = product_event_reply_generator(&reply);
+    // Reply where the requester asked, keyed and tagged like the request so
+    // the requester can match the reply
+    let reply_topic = request_reply::reply_topic(message, settings.topic("reply"));
+    let mut reply = FutureRecord::<[u8], Vec<u8>>::to(reply_topic)
+        .payload(&reply_payload)
+        .headers(request_reply::reply_headers(message));
+    if let Some(key) = message.key() {
+        reply = reply.key(key);
+    }
+    match replies {
+        // Delivery is reported to the publisher, not awaited here
+        Replies::Pipelined(reply_publisher) => {
+            match reply_publisher.send_reply(message, reply).await {
+                Ok(()) => {
+                    println!("Product response queued for {} topic", reply_topic);
+                    Ok(Handled::ReplyQueued)
+                }
+                // Consume the request again rather than commit it unanswered
+                Err(e) => {
+                    eprintln!("Error sending product response: {:?}", e);
+                    Err(e)
+                }
+            }
         }
-        _ => {
-            eprintln!("Unknown event type");
+        Replies::Transactional(transactions) => {
+            match reply_in_transaction(transactions, consumer, message, reply).await {
+                Ok(()) => {
+                    println!("Product response committed to {} topic", reply_topic);
+                    Ok(Handled::Done)
+                }
+                // Consume the request again rather than let the next
+                // transaction commit past it
+                Err(e) => {
+                    eprintln!("Error committing product response: {}", e);
+                    Err(e)
+                }
+            }
         }
     }
 }
 
-fn product_event_reply_generator(product: &Product) -> Vec<u8> {
-    serde_json::to_vec(product).expect("Error serializing product")
+/// Lets `committer` commit the requests whose replies have been delivered,
+/// and consumes those whose replies failed again.
+fn settle_replies(
+    reply_publisher: &mut ReplyPublisher,
+    committer: &mut OffsetCommitter,
+    consumer: &CommittingConsumer,
+) {
+    for settled in reply_publisher.settled() {
+        let result = match settled.result {
+            Ok(()) => committer.resolve(consumer, &settled.request),
+            Err(_) => {
+                println!(
+                    "Consuming request at offset {} again",
+                    settled.request.offset
+                );
+                committer.retry(consumer, &settled.request)
+            }
+        };
+        if let Err(e) = result {
+            eprintln!("Error committing offsets: {}", e);
+        }
+    }
 }
 
-async fn kafka_consumer(data: web::Data<AppState>) {
-    let consumer: StreamConsumer = ClientConfig::new()
-        .set("group.id", "products-group")
-        .set("bootstrap.servers", "localhost:9092")
+/// Resolves when the next reply is reported, never for transactions.
+async fn next_report(replies: &mut Replies) {
+    match replies {
+        Replies::Pipelined(reply_publisher) => reply_publisher.next_report().await,
+        Replies::Transactional(_) => std::future::pending().await,
+    }
+}
+
+/// Sends `reply` and commits `request` in one transaction.
+async fn reply_in_transaction(
+    transactions: &Transactions,
+    consumer: &CommittingConsumer,
+    request: &BorrowedMessage<'_>,
+    reply: FutureRecord<'_, [u8], Vec<u8>>,
+) -> Result<(), KafkaError> {
+    let mut offsets = TopicPartitionList::new();
+    offsets.add_partition_offset(
+        request.topic(),
+        request.partition(),
+        Offset::Offset(request.offset() + 1),
+    )?;
+    let transaction = transactions.begin().await?;
+    // Delivery is confirmed by the commit
+    if let Err((e, _)) = transaction.producer().send_result(reply) {
+        return Err(transaction.abort_with(e).await);
+    }
+    if let Err(e) = transaction.send_offsets(&offsets, consumer).await {
+        return Err(transaction.abort_with(e).await);
+    }
+    transaction.commit().await
+}
+
+/// Consumes until `shutdown` is triggered, finishing the message in hand
+/// before draining. Offsets are committed once their requests have been
+/// answered, so a crash replays what was not. Returns whether the drain was
+/// clean.
+async fn kafka_consumer(
+    data: web::Data<AppState>,
+    settings: Settings,
+    consumer: Arc<CommittingConsumer>,
+    progress: Arc<Progress>,
+    shutdown: Shutdown,
+) -> bool {
+    let dead_letter_producer: FutureProducer = settings
+        .client_config()
+        .create()
+        .expect("Producer creation failed");
+    let dead_letter_queue = DeadLetterQueue::new(
+        dead_letter_producer,
+        settings.topic("dead_letter"),
+        settings.unknown_event_policy(),
+    );
+    let reply_producer: FutureProducer = settings
+        .producer_config()
         .create()
-        .expect("Consumer creation failed");
+        .expect("Producer creation failed");
+    let mut replies = match settings.producer_mode() {
+        ProducerMode::Transactional => Replies::Transactional(
+            Transactions::init(reply_producer, TRANSACTION_TIMEOUT)
+                .await
+                .expect("Transactional producer initialisation failed"),
+        ),
+        _ => Replies::Pipelined(ReplyPublisher::new(reply_producer, MAX_REPLIES_IN_FLIGHT)),
+    };
 
     consumer
-        .subscribe(&["product_request"])
+        .subscribe(&[settings.topic("request")])
         .expect("Can't subscribe to topic");
 
     let mut message_stream = consumer.stream();
 
-    while let Some(message) = message_stream.next().await {
+    // Transactions commit offsets with their replies, so with them nothing
+    // is reported to the committer and no batch ever comes due
+    let mut committer = OffsetCommitter::new(settings.commit_strategy());
+
+    loop {
+        let message = tokio::select! {
+            _ = shutdown.triggered() => break,
+            _ = committer.due() => {
+                if let Err(e) = committer.commit(consumer.as_ref(), CommitMode::Sync) {
+                    eprintln!("Error committing offsets: {}", e);
+                }
+                continue;
+            }
+            _ = next_report(&mut replies) => {
+                if let Replies::Pipelined(reply_publisher) = &mut replies {
+                    settle_replies(reply_publisher, &mut committer, consumer.as_ref());
+                }
+                continue;
+            }
+            message = message_stream.next() => match message {
+                Some(message) => message,
+                None => break,
+            },
+        };
         match message {
             Ok(m) => {
-                if let Some(payload) = m.payload() {
-                    product_event_processor(&data, payload);
-                    // Process the request and prepare the response
-                    let product_event: ProductEvent =
-                        serde_json::from_slice(payload).expect("Error deserializing product");
-                    println!("incoming event {:?}",product_event);
-                    let product = Product {
-                        id: product_event.id.clone(),
-                        r#type: product_event.r#type.clone(),
-                        name: product_event.name.clone(),
-                        version: product_event.version.clone(),
-                    };
-                    let reply_payload = product_event_reply_generator(&product);
-                    // Publish the response to the reply topic
-                    let producer: FutureProducer = ClientConfig::new()
-                        .set("bootstrap.servers", "localhost:9092")
-                        .create()
-                        .expect("Producer creation failed");
-
-                    let delivery_status = producer
-                        .send(
-                            FutureRecord::<Vec<u8>, Vec<u8>>::to("product_reply")
-                                .payload(&reply_payload),
-                            Duration::from_secs(0),
-                        )
-                        .await;
-
-                    match delivery_status {
-                        Ok(_) => {
-                            println!("Product response sent to product_reply topic");
+                let handled = match handle_request(
+                    &data,
+                    &settings,
+                    &consumer,
+                    &dead_letter_queue,
+                    &mut replies,
+                    &m,
+                )
+                .await
+                {
+                    Ok(handled) => handled,
+                    Err(_) => {
+                        println!("Consuming request at offset {} again", m.offset());
+                        if let Err(e) = commit::rewind(consumer.as_ref(), &m) {
+                            eprintln!("Error rewinding to request: {}", e);
                         }
-                        Err(e) => {
-                            eprintln!("Error sending product response: {:?}", e);
+                        continue;
+                    }
+                };
+                progress.record(&m);
+                if let Replies::Pipelined(reply_publisher) = &mut replies {
+                    let processed = match handled {
+                        Handled::ReplyQueued => {
+                            committer.defer(&m);
+                            Ok(())
                         }
+                        Handled::Done => committer.processed(consumer.as_ref(), &m),
+                    };
+                    if let Err(e) = processed {
+                        eprintln!("Error committing offsets: {}", e);
                     }
+                    settle_replies(reply_publisher, &mut committer, consumer.as_ref());
                 }
             }
             Err(e) => eprintln!("Kafka error: {}", e),
         }
     }
+
+    drop(message_stream);
+    // Deliver the replies still in flight before committing their requests.
+    // Committed transactions have nothing left to deliver.
+    let replies_flushed = match &mut replies {
+        Replies::Pipelined(reply_publisher) => {
+            let flushed = match reply_publisher.flush(settings.shutdown_timeout()).await {
+                Ok(()) => true,
+                Err(e) => {
+                    eprintln!("Error flushing replies on shutdown: {}", e);
+                    false
+                }
+            };
+            settle_replies(reply_publisher, &mut committer, consumer.as_ref());
+            flushed
+        }
+        Replies::Transactional(_) => true,
+    };
+    // Requests answered in a transaction are already committed, and those
+    // without a reply stay uncommitted and are consumed, and dropped or
+    // ignored, again on the next start
+    shutdown::drain(
+        consumer.as_ref(),
+        &dead_letter_queue,
+        settings.shutdown_timeout(),
+    ) && replies_flushed
 }
 
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let products = Mutex::new(HashMap::new());
-    let data = web::Data::new(AppState { products });
+async fn main() -> std::io::Result<ExitCode> {
+    let settings = Settings::builder("127.0.0.1:8080")
+        .group_id("products-group")
+        .topic("request", "product_request")
+        .topic("reply", "product_reply")
+        .topic("dead_letter", "product_request_dead_letter")
+        .load()
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(2)
+        });
+    println!("Kafka client settings: {}", settings);
+    let bind = settings.bind();
+    let shutdown_timeout = settings.shutdown_timeout();
+    let data = web::Data::new(AppState::new(settings.unknown_event_policy()));
+
+    let consumer =
+        commit::committing_consumer(&settings.consumer_config()).expect("Consumer creation failed");
+    let progress = Arc::new(Progress::new());
+    let shutdown = Shutdown::new();
+    let probe = web::Data::new(ConsumerProbe::new(
+        consumer.clone(),
+        progress.clone(),
+        settings.topics().map(str::to_string).collect(),
+        shutdown.clone(),
+        Duration::from_secs(5),
+    ));
 
     // Start Kafka consumer
-    let data_clone = data.clone();
-    actix_rt::spawn(async move {
-        kafka_consumer(data_clone).await;
-    });
-
-    HttpServer::new(move || {
+    let kafka = actix_rt::spawn(kafka_consumer(
+        data.clone(),
+        settings,
+        consumer,
+        progress,
+        shutdown.clone(),
+    ));
+
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(data.clone())
+            .app_data(probe.clone())
+            .route("/healthz", web::get().to(health::healthz))
+            .route("/readyz", web::get().to(health::readyz::<ConsumerProbe<CommitOnRevoke>>))
             .route("/products", web::get().to(get_all))
             .route("/products/{id}", web::get().to(get_by_id))
             .route("/product/{id}", web::get().to(get_by_id))
     })
-    .bind("127.0.0.1:8080")?
-    .run()
-    .await
+    .bind(bind)?
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout.as_secs())
+    .run();
+
+    // Stop accepting HTTP requests first, then let the consumer drain
+    let server_handle = server.handle();
+    let on_signal = shutdown.clone();
+    actix_rt::spawn(shutdown::stop_on_signal(async move {
+        server_handle.stop(true).await;
+        on_signal.trigger();
+    }));
+
+    server.await?;
+    shutdown.trigger();
+    let clean = kafka.await.unwrap_or(false);
+    Ok(shutdown::exit_code(clean))
 }
 
 #[cfg(test)]
 mod tests {
 
-use expectest::{expect, prelude::be_some};
+use expectest::{expect, prelude::{be_ok, be_some}};
 use pact_consumer::{matching_regex, prelude::*};
 use pact_models::{bodies::OptionalBody, prelude::MatchingRules};
 use pact_models::path_exp::DocPath;
@@ -164,10 +471,9 @@ use pact_models::prelude::{Generators, MatchingRuleCategory};
 use pact_models::v4::message_parts::MessageContents;
 // use pact_models::{MessageContents};
 use serde_json::Value;
+use product_domain::{EventMetadata, ProcessError, ProductReply, ProductVersion, UnknownEventPolicy};
 use crate::{product_event_processor, product_event_reply_generator, AppState};
-use std::collections::HashMap;
 use maplit::hashmap;
-use std::sync::Mutex;
 use actix_web::web;
 use expectest::matchers::be_equal_to;
 #[test]
@@ -200,6 +506,13 @@ fn consumes_a_product_event_update_message_and_responds() {
 
             // Set any required metadata
             i.request_metadata("kafka_request_topic", "product_request");
+            // The standard headers of every product event
+            i.request_metadata(EventMetadata::CONTENT_TYPE, "application/json");
+            i.request_metadata(EventMetadata::EVENT_TYPE, "UPDATED");
+            i.request_metadata(EventMetadata::SCHEMA_VERSION, EventMetadata::CURRENT_SCHEMA_VERSION);
+            i.request_metadata(EventMetadata::PRODUCER_ID, "provider-rust-kafka-sync");
+            i.request_metadata(EventMetadata::EVENT_ID, "5b1c1f6e-3f43-4d8e-9a1c-0e4c2f7b8d21");
+            i.request_metadata(EventMetadata::PRODUCED_AT, "2024-05-01T12:00:00.000Z");
 
 
             // Setup our response
@@ -247,8 +560,7 @@ fn consumes_a_product_event_update_message_and_responds() {
         });
 
     // Arrange. setup product database
-    let products = Mutex::new(HashMap::new());
-    let data = web::Data::new(AppState { products });
+    let data = web::Data::new(AppState::new(UnknownEventPolicy::Reject));
     
     // This will return each message configured with the Pact builder. We need to process them
     // with out message handler (it should be the one used to actually process your messages).
@@ -262,23 +574,26 @@ fn consumes_a_product_event_update_message_and_responds() {
 
         // get message metadata
         let kafka_request_topic = message.request.metadata.get("kafka_request_topic");
+        let metadata = EventMetadata::from_headers(message.request.metadata.iter()
+            .filter_map(|(name, value)| value.as_str().map(|value| (name.as_str(), value.as_bytes()))));
         // let kafka_reply_topic = message.response.metadata.get("kafka_reply_topic");
 
         // you may want to process the bytes into a Value
         let _request_message: Value = serde_json::from_slice(&request_message_bytes).unwrap();
         let _response_message: Value = serde_json::from_slice(&response_message_bytes).unwrap();
 
-        // Send the message to our message processor 
-        product_event_processor(&data,&request_message_bytes);
+        // Send the message to our message processor, with the headers it came with
+        expect!(metadata.event_type.as_deref()).to(be_some().value("UPDATED"));
+        expect!(product_event_processor(&data, &request_message_bytes, &metadata)).to(be_ok());
         
         // assert of the state of our product database, after processing the message
         let products = data.products.lock().unwrap();
         let product = products.get("some-uuid-1234-5678").unwrap();
         println!("{:?}", product);
-        expect!(product.id.as_str()).to(be_equal_to("some-uuid-1234-5678".to_string()));
+        expect!(product.id.clone()).to(be_equal_to(Some("some-uuid-1234-5678".to_string())));
         expect!(product.name.clone()).to(be_equal_to("Some Product".to_string()));
         expect!(product.r#type.clone()).to(be_equal_to("Product Range".to_string()));
-        expect!(product.version.clone()).to(be_equal_to("v1".to_string()));
+        expect!(product.version).to(be_equal_to(Some(ProductVersion::FIRST)));
 
         // assert the correct topics are included in our message
         expect!(kafka_request_topic)
@@ -287,10 +602,97 @@ fn consumes_a_product_event_update_message_and_responds() {
         //     .to(be_some().value("product_reply"));
 
         // we should now call our event reply generator and ensure it can create the appropriate message
-        let actual_response: Value = serde_json::from_slice(&product_event_reply_generator(product)).unwrap();
+        let reply = ProductReply::Product(product.clone());
+        let actual_response: Value = serde_json::from_slice(&product_event_reply_generator(&reply)).unwrap();
         let expected_response: Value = serde_json::from_slice(&response_message_bytes).unwrap();
         assert_eq!(expected_response, actual_response);
     }
 }
 
-}
\ No newline at end of file
+#[test]
+fn consumes_a_stale_product_event_update_message_and_responds_with_an_error() {
+    let mut pact_builder =
+        pact_consumer::builders::PactBuilder::new_v4("pactflow-example-consumer-rust-kafka-sync", "pactflow-example-provider-rust-kafka-sync");
+    pact_builder
+        .synchronous_message_interaction("a stale product event update with error reply", |mut i| {
+            i.test_name("consumes_a_stale_product_event_update_message_and_responds_with_an_error");
+            i.given("product some-uuid-1234-5678 is at version v2");
+            i.request_json_body(json_pattern!({
+              "id": like!("some-uuid-1234-5678"),
+              "type": like!("Product Range"),
+              "name": like!("Some Product"),
+              "version": like!("v1"),
+              "event": matching_regex!("^(CREATED|UPDATED|DELETED)$","UPDATED")
+            }));
+            i.request_metadata("kafka_request_topic", "product_request");
+
+            // Error replies carry a code the provider maps to a status, and a
+            // message for people
+            let body = json_pattern!({
+                "error": {
+                    "code": matching_regex!("^(NOT_FOUND|STALE_VERSION|UNKNOWN_EVENT_TYPE|MALFORMED_EVENT)$", "STALE_VERSION"),
+                    "message": like!("stale event for product some-uuid-1234-5678: received v1 but v2 is already stored")
+                }
+            });
+            let message_body = OptionalBody::Present(body.to_example().to_string().into(), Some("application/json".into()), None);
+            let mut rules = MatchingRuleCategory::empty("content");
+            body.extract_matching_rules(DocPath::root(), &mut rules);
+
+            i.response_contents(&MessageContents{
+                contents: message_body,
+                metadata: hashmap! {
+                    "kafka_reply_topic".to_string() => serde_json::Value::String("product_reply".to_string())
+                },
+                matching_rules: {
+                    let mut matching_rules = MatchingRules::default();
+                    matching_rules.add_rules("content", rules);
+                    matching_rules
+                },
+                generators: Generators::default(),
+            });
+            i
+        });
+
+    // Arrange. the product is already at v2
+    let data = web::Data::new(AppState::new(UnknownEventPolicy::Reject));
+    let current = br#"{"id":"some-uuid-1234-5678","type":"Product Range","name":"Some Product","version":"v2","event":"UPDATED"}"#;
+    expect!(product_event_processor(&data, current, &EventMetadata::default())).to(be_ok());
+
+    for message in pact_builder.synchronous_messages() {
+        let request_message_bytes = message.request.contents.value().unwrap();
+        let response_message_bytes = message.response.first().unwrap().contents.value().unwrap();
+
+        let error = product_event_processor(&data, &request_message_bytes, &EventMetadata::default()).unwrap_err();
+        expect!(matches!(error, ProcessError::StaleVersion { .. })).to(be_equal_to(true));
+
+        // the stored product is untouched and the reply explains why
+        let stored = data.products.lock().unwrap().get("some-uuid-1234-5678").unwrap().version;
+        expect!(stored).to(be_equal_to(Some(ProductVersion::new(2))));
+        let actual_response: Value = serde_json::from_slice(&product_event_reply_generator(&ProductReply::from(Err(&error)))).unwrap();
+        let expected_response: Value = serde_json::from_slice(&response_message_bytes).unwrap();
+        assert_eq!(expected_response, actual_response);
+    }
+}
+
+#[test]
+fn rejects_unknown_event_types_unless_configured_to_ignore_them() {
+    let payload = br#"{"id":"some-uuid-1234-5678","type":"Product Range","name":"Some Product","version":"v1","event":"ARCHIVED"}"#;
+
+    let rejecting = web::Data::new(AppState::new(UnknownEventPolicy::Reject));
+    let error = product_event_processor(&rejecting, payload, &EventMetadata::default()).unwrap_err();
+    expect!(matches!(error, ProcessError::UnknownEventType(e) if e.0 == "ARCHIVED")).to(be_equal_to(true));
+
+    let ignoring = web::Data::new(AppState::new(UnknownEventPolicy::Ignore));
+    expect!(product_event_processor(&ignoring, payload, &EventMetadata::default())).to(be_ok().value(None));
+    expect!(ignoring.products.lock().unwrap().is_empty()).to(be_equal_to(true));
+}
+
+#[test]
+fn reports_malformed_events_instead_of_panicking() {
+    let data = web::Data::new(AppState::new(UnknownEventPolicy::Reject));
+    let result = product_event_processor(&data, b"not a product event", &EventMetadata::default());
+    expect!(matches!(result, Err(ProcessError::Decode(_)))).to(be_equal_to(true));
+    expect!(data.products.lock().unwrap().is_empty()).to(be_equal_to(true));
+}
+
+}
@@ -0,0 +1,84 @@
+//! Compares sending replies through a new producer per reply, as the sync
+//! consumer used to, with a shared, pipelined [`ReplyPublisher`].
+//!
+//! Runs against an in-process mock cluster, so it measures client-side costs
+//! such as connection setup and per-reply round trips rather than a real
+//! broker:
+//!
+//! ```sh
+//! cargo bench -p kafka-support --bench reply_publisher
+//! ```
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kafka_support::request_reply::ReplyPublisher;
+use rdkafka::mocking::MockCluster;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::ClientConfig;
+use std::time::Duration;
+
+const REPLY_TOPIC: &str = "product_reply";
+const REPLY: &str =
+    r#"{"id":"some-uuid-1234-5678","name":"Some Product","type":"Product Range","version":"v1"}"#;
+
+fn producer(bootstrap_servers: &str) -> FutureProducer {
+    ClientConfig::new()
+        .set("bootstrap.servers", bootstrap_servers)
+        .create()
+        .expect("Producer creation failed")
+}
+
+fn replies(c: &mut Criterion) {
+    let cluster = MockCluster::new(1).unwrap();
+    cluster.create_topic(REPLY_TOPIC, 1, 1).unwrap();
+    let bootstrap_servers = cluster.bootstrap_servers();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("replies");
+    group.sample_size(10);
+    for count in [10u64, 100] {
+        group.throughput(Throughput::Elements(count));
+
+        group.bench_with_input(
+            BenchmarkId::new("producer_per_reply", count),
+            &count,
+            |b, &count| {
+                b.iter(|| {
+                    runtime.block_on(async {
+                        for _ in 0..count {
+                            producer(&bootstrap_servers)
+                                .send(
+                                    FutureRecord::<(), str>::to(REPLY_TOPIC).payload(REPLY),
+                                    Duration::from_secs(5),
+                                )
+                                .await
+                                .unwrap();
+                        }
+                    })
+                })
+            },
+        );
+
+        let mut publisher = ReplyPublisher::new(producer(&bootstrap_servers), 1000);
+        group.bench_with_input(
+            BenchmarkId::new("shared_publisher", count),
+            &count,
+            |b, &count| {
+                b.iter(|| {
+                    runtime.block_on(async {
+                        for _ in 0..count {
+                            publisher
+                                .send(FutureRecord::<(), str>::to(REPLY_TOPIC).payload(REPLY))
+                                .await
+                                .unwrap();
+                        }
+                        publisher.flush(Duration::from_secs(5)).await.unwrap();
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, replies);
+criterion_main!(benches);
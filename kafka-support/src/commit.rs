@@ -0,0 +1,467 @@
+//! Committing consumer offsets once their messages have been processed.
+//!
+//! Consumers created from [`Settings::consumer_config`] neither store nor
+//! commit offsets on their own, so nothing is committed before it has been
+//! applied. The consumer loop reports each message to an [`OffsetCommitter`]
+//! once it is done with it, and the committer commits following the
+//! service's [`CommitStrategy`]. Delivery is at least once: a crash replays
+//! every message processed since the last commit.
+//!
+//! A message that could not be processed is consumed again with [`rewind`]
+//! instead, so neither it nor anything after it on its partition is
+//! committed before it has been.
+//!
+//! Some messages are only done once something they started finishes, like a
+//! reply still being delivered. The loop reports those with
+//! [`OffsetCommitter::defer`], and nothing from their offset onwards on
+//! their partition is committed until they are resolved, or retried from
+//! their offset if what they started failed.
+//!
+//! A [`CommittingConsumer`] also commits what it has processed before its
+//! partitions are revoked in a rebalance, so their next owner does not
+//! replay them. When the consumer is dropped without draining, as in a
+//! crash, nothing more is committed.
+//!
+//! [`Settings::consumer_config`]: crate::config::Settings::consumer_config
+
+use crate::shutdown;
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{CommitMode, Consumer, ConsumerContext, Rebalance, StreamConsumer};
+use rdkafka::error::KafkaResult;
+use rdkafka::message::Message;
+use rdkafka::{ClientContext, Offset};
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How long [`rewind`] waits for the consumer to seek.
+const SEEK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// When processed offsets are committed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommitStrategy {
+    /// After every message, waiting for the broker.
+    PerMessage,
+    /// Once `messages` messages are uncommitted, or `interval` after the
+    /// oldest of them was processed, waiting for the broker.
+    Batch { messages: usize, interval: Duration },
+    /// After every message, without waiting for the broker.
+    Async,
+}
+
+impl Default for CommitStrategy {
+    fn default() -> Self {
+        CommitStrategy::Batch {
+            messages: 100,
+            interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Where a message was read from.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Position {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+}
+
+impl Position {
+    pub fn of<M: Message>(message: &M) -> Self {
+        Position {
+            topic: message.topic().to_string(),
+            partition: message.partition(),
+            offset: message.offset(),
+        }
+    }
+}
+
+/// The deferred messages of one partition.
+struct Deferred {
+    offsets: BTreeSet<i64>,
+    /// The last offset processed or resolved.
+    done: i64,
+}
+
+/// Commits offsets following a [`CommitStrategy`].
+pub struct OffsetCommitter {
+    strategy: CommitStrategy,
+    uncommitted: usize,
+    oldest: Option<Instant>,
+    deferred: HashMap<(String, i32), Deferred>,
+}
+
+impl OffsetCommitter {
+    pub fn new(strategy: CommitStrategy) -> Self {
+        OffsetCommitter {
+            strategy,
+            uncommitted: 0,
+            oldest: None,
+            deferred: HashMap::new(),
+        }
+    }
+
+    /// Records that `message` has been processed, so its offset may be
+    /// committed once no earlier message of its partition is deferred, and
+    /// commits if the strategy says so.
+    pub fn processed<X: ConsumerContext, C: Consumer<X>, M: Message>(
+        &mut self,
+        consumer: &C,
+        message: &M,
+    ) -> KafkaResult<()> {
+        let key = (message.topic().to_string(), message.partition());
+        match self.deferred.get_mut(&key) {
+            Some(deferred) => deferred.done = deferred.done.max(message.offset()),
+            None => {
+                consumer.store_offset(message.topic(), message.partition(), message.offset())?
+            }
+        }
+        self.counted(consumer)
+    }
+
+    /// Records that `message` has been handled but is not done until
+    /// [`OffsetCommitter::resolve`] or [`OffsetCommitter::retry`] is called
+    /// with its position. Until then neither it nor anything after it on
+    /// its partition is committed.
+    pub fn defer<M: Message>(&mut self, message: &M) {
+        self.deferred
+            .entry((message.topic().to_string(), message.partition()))
+            .or_insert_with(|| Deferred {
+                offsets: BTreeSet::new(),
+                done: message.offset() - 1,
+            })
+            .offsets
+            .insert(message.offset());
+    }
+
+    /// Records that the deferred message at `position` is done, so it and
+    /// the processed messages after it up to the next deferred one may be
+    /// committed, and commits if the strategy says so. Positions that are
+    /// not deferred, because they were retried, are ignored.
+    pub fn resolve<X: ConsumerContext, C: Consumer<X>>(
+        &mut self,
+        consumer: &C,
+        position: &Position,
+    ) -> KafkaResult<()> {
+        let key = (position.topic.clone(), position.partition);
+        let Some(deferred) = self.deferred.get_mut(&key) else {
+            return Ok(());
+        };
+        if !deferred.offsets.remove(&position.offset) {
+            return Ok(());
+        }
+        deferred.done = deferred.done.max(position.offset);
+        // Offsets are stored as the last one done, and committed as the next
+        let last_done = match deferred.offsets.first() {
+            Some(first) => first - 1,
+            None => {
+                let done = deferred.done;
+                self.deferred.remove(&key);
+                done
+            }
+        };
+        consumer.store_offset(&position.topic, position.partition, last_done)?;
+        self.counted(consumer)
+    }
+
+    /// Makes `consumer` consume the deferred message at `position`, and
+    /// everything after it on its partition, again, because what it started
+    /// failed. Positions that are not deferred are ignored.
+    pub fn retry<X: ConsumerContext, C: Consumer<X>>(
+        &mut self,
+        consumer: &C,
+        position: &Position,
+    ) -> KafkaResult<()> {
+        let key = (position.topic.clone(), position.partition);
+        let Some(deferred) = self.deferred.get_mut(&key) else {
+            return Ok(());
+        };
+        if !deferred.offsets.contains(&position.offset) {
+            return Ok(());
+        }
+        // Consumed again, and deferred again if need be
+        deferred.offsets.retain(|offset| *offset < position.offset);
+        deferred.done = deferred.done.min(position.offset - 1);
+        if deferred.offsets.is_empty() {
+            self.deferred.remove(&key);
+        }
+        seek(consumer, position)
+    }
+
+    fn counted<X: ConsumerContext, C: Consumer<X>>(&mut self, consumer: &C) -> KafkaResult<()> {
+        self.uncommitted += 1;
+        self.oldest.get_or_insert_with(Instant::now);
+        match self.strategy {
+            CommitStrategy::PerMessage => self.commit(consumer, CommitMode::Sync),
+            CommitStrategy::Async => self.commit(consumer, CommitMode::Async),
+            CommitStrategy::Batch { messages, .. } if self.uncommitted >= messages => {
+                self.commit(consumer, CommitMode::Sync)
+            }
+            CommitStrategy::Batch { .. } => Ok(()),
+        }
+    }
+
+    /// Resolves when a batch is due because of its interval. Never resolves
+    /// for the other strategies, or while nothing is uncommitted.
+    pub fn due(&self) -> impl Future<Output = ()> + 'static {
+        let deadline = match (self.strategy, self.oldest) {
+            (CommitStrategy::Batch { interval, .. }, Some(oldest)) => Some(oldest + interval),
+            _ => None,
+        };
+        async move {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    /// Commits every processed offset.
+    pub fn commit<X: ConsumerContext, C: Consumer<X>>(
+        &mut self,
+        consumer: &C,
+        mode: CommitMode,
+    ) -> KafkaResult<()> {
+        self.uncommitted = 0;
+        self.oldest = None;
+        match mode {
+            CommitMode::Sync => shutdown::commit(consumer),
+            CommitMode::Async => consumer.commit_consumer_state(CommitMode::Async),
+        }
+    }
+}
+
+/// Makes `consumer` consume `message` again. The messages after it that were
+/// already fetched are discarded and fetched again too.
+pub fn rewind<X: ConsumerContext, C: Consumer<X>, M: Message>(
+    consumer: &C,
+    message: &M,
+) -> KafkaResult<()> {
+    seek(consumer, &Position::of(message))
+}
+
+fn seek<X: ConsumerContext, C: Consumer<X>>(consumer: &C, position: &Position) -> KafkaResult<()> {
+    consumer.seek(
+        &position.topic,
+        position.partition,
+        Offset::Offset(position.offset),
+        SEEK_TIMEOUT,
+    )
+}
+
+/// A consumer that commits its processed offsets before its partitions are
+/// revoked.
+pub type CommittingConsumer = StreamConsumer<CommitOnRevoke>;
+
+/// Creates a [`CommittingConsumer`] from `config`.
+pub fn committing_consumer(config: &ClientConfig) -> KafkaResult<Arc<CommittingConsumer>> {
+    let consumer: Arc<CommittingConsumer> =
+        Arc::new(config.create_with_context(CommitOnRevoke::default())?);
+    let _ = consumer.context().consumer.set(Arc::downgrade(&consumer));
+    Ok(consumer)
+}
+
+/// The context of a [`CommittingConsumer`]. It holds a weak reference to the
+/// consumer, which owns it, so it can commit from the rebalance callback.
+#[derive(Default)]
+pub struct CommitOnRevoke {
+    consumer: OnceLock<Weak<CommittingConsumer>>,
+}
+
+impl ClientContext for CommitOnRevoke {}
+
+impl ConsumerContext for CommitOnRevoke {
+    fn pre_rebalance(&self, rebalance: &Rebalance<'_>) {
+        if let Rebalance::Revoke(_) = rebalance {
+            // Gone once the consumer is being dropped
+            let Some(consumer) = self.consumer.get().and_then(Weak::upgrade) else {
+                return;
+            };
+            if let Err(e) = shutdown::commit(consumer.as_ref()) {
+                eprintln!("Error committing offsets of revoked partitions: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdkafka::mocking::MockCluster;
+    use rdkafka::producer::{DefaultProducerContext, FutureProducer, FutureRecord};
+    use rdkafka::TopicPartitionList;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    type Cluster = MockCluster<'static, DefaultProducerContext>;
+
+    async fn cluster_with_messages(count: usize) -> Cluster {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("products", 1, 1).unwrap();
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .create()
+            .unwrap();
+        for i in 0..count {
+            producer
+                .send(
+                    FutureRecord::<(), str>::to("products").payload(&i.to_string()),
+                    TIMEOUT,
+                )
+                .await
+                .unwrap();
+        }
+        cluster
+    }
+
+    fn consumer(cluster: &Cluster) -> Arc<CommittingConsumer> {
+        let consumer = committing_consumer(
+            ClientConfig::new()
+                .set("bootstrap.servers", cluster.bootstrap_servers())
+                .set("group.id", "products-group")
+                .set("auto.offset.reset", "earliest")
+                .set("enable.auto.commit", "false")
+                .set("enable.auto.offset.store", "false")
+                // A crashed consumer holds its partitions until its session
+                // times out
+                .set("session.timeout.ms", "6000")
+                .set("heartbeat.interval.ms", "500"),
+        )
+        .unwrap();
+        consumer.subscribe(&["products"]).unwrap();
+        consumer
+    }
+
+    /// Processes messages until the one at `crash_at`, then drops the
+    /// consumer without draining it, as a crash would.
+    async fn crash(cluster: &Cluster, strategy: CommitStrategy, crash_at: i64) {
+        let consumer = consumer(cluster);
+        let mut committer = OffsetCommitter::new(strategy);
+        loop {
+            let message = tokio::time::timeout(TIMEOUT, consumer.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if message.offset() == crash_at {
+                break;
+            }
+            committer.processed(consumer.as_ref(), &message).unwrap();
+        }
+    }
+
+    async fn first_offset(cluster: &Cluster) -> i64 {
+        let consumer = consumer(cluster);
+        let message = tokio::time::timeout(TIMEOUT, consumer.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        message.offset()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_crash_replays_the_uncommitted_messages() {
+        let cluster = cluster_with_messages(5).await;
+        crash(&cluster, CommitStrategy::PerMessage, 3).await;
+        assert_eq!(first_offset(&cluster).await, 3);
+
+        let cluster = cluster_with_messages(5).await;
+        let batch = CommitStrategy::Batch {
+            messages: 2,
+            interval: Duration::from_secs(3600),
+        };
+        crash(&cluster, batch, 3).await;
+        // Message 2 was processed, but its batch was not full
+        assert_eq!(first_offset(&cluster).await, 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn batches_are_committed_when_their_interval_passes() {
+        let cluster = cluster_with_messages(1).await;
+        let consumer = consumer(&cluster);
+        let mut committer = OffsetCommitter::new(CommitStrategy::Batch {
+            messages: 100,
+            interval: Duration::from_millis(100),
+        });
+        let message = tokio::time::timeout(TIMEOUT, consumer.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        committer.processed(consumer.as_ref(), &message).unwrap();
+        drop(message);
+
+        tokio::time::timeout(TIMEOUT, committer.due())
+            .await
+            .unwrap();
+        committer
+            .commit(consumer.as_ref(), CommitMode::Sync)
+            .unwrap();
+        let committed = consumer.committed(TIMEOUT).unwrap();
+        assert_eq!(
+            committed.find_partition("products", 0).unwrap().offset(),
+            Offset::Offset(1)
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), committer.due())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn commits_processed_offsets_when_partitions_are_revoked() {
+        let cluster = cluster_with_messages(3).await;
+        let consumer = consumer(&cluster);
+        let mut committer = OffsetCommitter::new(CommitStrategy::default());
+        for _ in 0..2 {
+            let message = tokio::time::timeout(TIMEOUT, consumer.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            committer.processed(consumer.as_ref(), &message).unwrap();
+        }
+
+        consumer.unsubscribe();
+        // Serve the revoke; no more messages arrive once unsubscribed
+        let _ = tokio::time::timeout(Duration::from_secs(1), consumer.recv()).await;
+
+        let mut partitions = TopicPartitionList::new();
+        partitions.add_partition("products", 0);
+        let committed = consumer.committed_offsets(partitions, TIMEOUT).unwrap();
+        assert_eq!(
+            committed.find_partition("products", 0).unwrap().offset(),
+            Offset::Offset(2)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_rewound_message_is_consumed_again_before_it_is_committed() {
+        let cluster = cluster_with_messages(3).await;
+        let consumer = consumer(&cluster);
+        let mut committer = OffsetCommitter::new(CommitStrategy::PerMessage);
+        let processed = tokio::time::timeout(TIMEOUT, consumer.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        committer.processed(consumer.as_ref(), &processed).unwrap();
+        drop(processed);
+        let failed = tokio::time::timeout(TIMEOUT, consumer.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        rewind(consumer.as_ref(), &failed).unwrap();
+        drop(failed);
+
+        let again = tokio::time::timeout(TIMEOUT, consumer.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(again.offset(), 1);
+        let committed = consumer.committed(TIMEOUT).unwrap();
+        assert_eq!(
+            committed.find_partition("products", 0).unwrap().offset(),
+            Offset::Offset(1)
+        );
+    }
+}
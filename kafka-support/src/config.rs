@@ -0,0 +1,1229 @@
+//! Startup configuration for the Rust services.
+//!
+//! Settings are layered, each layer overriding the one before it:
+//!
+//! 1. the defaults compiled into the binary,
+//! 2. a TOML file named by `--config` or `CONFIG_FILE`,
+//! 3. environment variables,
+//! 4. command line flags.
+//!
+//! | TOML                    | Environment               | Flag                      |
+//! |-------------------------|---------------------------|---------------------------|
+//! | `bootstrap_servers`     | `KAFKA_BOOTSTRAP_SERVERS` | `--bootstrap-servers`     |
+//! | `group_id`              | `KAFKA_GROUP_ID`          | `--group-id`              |
+//! | `bind`                  | `HTTP_BIND`               | `--bind`                  |
+//! | `shutdown_timeout_secs` | `SHUTDOWN_TIMEOUT_SECS`   | `--shutdown-timeout-secs` |
+//! | `reply_timeout_ms`      | `REPLY_TIMEOUT_MS`        | `--reply-timeout-ms`      |
+//! | `producer_mode`         | `KAFKA_PRODUCER_MODE`     | `--producer-mode`         |
+//! | `transactional_id`      | `KAFKA_TRANSACTIONAL_ID`  | `--transactional-id`      |
+//! | `commit_strategy`       | `KAFKA_COMMIT_STRATEGY`   | `--commit-strategy`       |
+//! | `commit_batch_size`     | `KAFKA_COMMIT_BATCH_SIZE` | `--commit-batch-size`     |
+//! | `commit_interval_ms`    | `KAFKA_COMMIT_INTERVAL_MS`| `--commit-interval-ms`    |
+//! | `unknown_event_policy`  | `UNKNOWN_EVENT_POLICY`    | `--unknown-event-policy`  |
+//! | `partitioning`          | `KAFKA_PARTITIONING`      | `--partitioning`          |
+//! | `outbox_path`           | `OUTBOX_PATH`             | `--outbox-path`           |
+//! | `[topics] <role>`       | `KAFKA_TOPIC_<ROLE>`      | `--topic <role>=<name>`   |
+//! | `[kafka] <key>`         | `KAFKA_PROPERTY_<KEY>`    | `-X <key>=<value>`        |
+//!
+//! Each binary names the topics it uses by role, for example `products` or
+//! `dead_letter`, and only those roles are accepted. The `[kafka]` table passes
+//! arbitrary librdkafka properties through to every client. In environment
+//! variable names roles are upper-cased and property keys use `_` for `.`, so
+//! `KAFKA_PROPERTY_SESSION_TIMEOUT_MS` sets `session.timeout.ms`.
+//!
+//! The producer mode is `default`, `idempotent` or `transactional`; see
+//! [`ProducerMode`]. Transactional producers need a `transactional_id` that
+//! is unique to each instance of the service.
+//!
+//! Consumers commit the offsets of messages they have processed with the
+//! commit strategy `per_message`, `async` or `batch`, the default, which
+//! commits every `commit_batch_size` messages (100) or `commit_interval_ms`
+//! (5000) after the oldest uncommitted one; see [`crate::commit`]. They
+//! `reject` events of a type they do not know, or `dead-letter` or `ignore`
+//! them, following the `unknown_event_policy`. Producers publish to the
+//! partition their key hashes to, or with `partitioning = "explicit:<n>"` to
+//! partition `n`; see [`Partitioning`]. A producer given an `outbox_path`
+//! stores events in an outbox there for a relay to publish, which cannot be
+//! combined with the transactional producer mode since the relay publishes
+//! outside transactions.
+//!
+//! TLS and SASL settings go in a `[security]` table, described in
+//! [`crate::security`].
+//!
+//! ```toml
+//! bootstrap_servers = "broker-1:9092,broker-2:9092"
+//! group_id = "products-group"
+//! bind = "0.0.0.0:8080"
+//!
+//! [topics]
+//! products = "products"
+//!
+//! [kafka]
+//! "session.timeout.ms" = 6000
+//! ```
+
+use crate::commit::CommitStrategy;
+use crate::security::{self, PropertyValue, Security, SecurityLayer};
+use product_domain::UnknownEventPolicy;
+use rdkafka::config::ClientConfig;
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Properties the typed settings own, which may not be passed through.
+const MANAGED_PROPERTIES: [&str; 5] = [
+    "bootstrap.servers",
+    "group.id",
+    "transactional.id",
+    "enable.auto.commit",
+    "enable.auto.offset.store",
+];
+
+/// How a service's producer delivers records.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProducerMode {
+    /// librdkafka's defaults.
+    #[default]
+    Default,
+    /// `enable.idempotence`, so retries never duplicate or reorder records.
+    Idempotent,
+    /// Idempotent, and records are sent in Kafka transactions through
+    /// [`crate::transaction::Transactions`].
+    Transactional,
+}
+
+impl ProducerMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProducerMode::Default => "default",
+            ProducerMode::Idempotent => "idempotent",
+            ProducerMode::Transactional => "transactional",
+        }
+    }
+}
+
+impl FromStr for ProducerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "default" => Ok(ProducerMode::Default),
+            "idempotent" => Ok(ProducerMode::Idempotent),
+            "transactional" => Ok(ProducerMode::Transactional),
+            _ => Err(format!(
+                "{:?} is not one of default, idempotent, transactional",
+                s
+            )),
+        }
+    }
+}
+
+/// Which partition a producer publishes each record to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Partitioning {
+    /// `key-hash`: the producer's partitioner hashes the record key.
+    #[default]
+    KeyHash,
+    /// `explicit:<n>`: every record goes to partition `n`.
+    Explicit(i32),
+}
+
+impl FromStr for Partitioning {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lowercase = s.to_ascii_lowercase();
+        if lowercase == "key-hash" {
+            return Ok(Partitioning::KeyHash);
+        }
+        match lowercase.strip_prefix("explicit:").map(str::parse) {
+            Some(Ok(partition)) if partition >= 0 => Ok(Partitioning::Explicit(partition)),
+            Some(_) => Err(format!("{:?} does not name a partition", s)),
+            None => Err(format!("{:?} is not one of key-hash, explicit:<n>", s)),
+        }
+    }
+}
+
+/// Validated settings for one service.
+///
+/// Printing the settings shows the client properties they produce, with
+/// passwords redacted, so they are safe to log at startup.
+#[derive(Clone, PartialEq)]
+pub struct Settings {
+    bootstrap_servers: String,
+    group_id: Option<String>,
+    bind: SocketAddr,
+    shutdown_timeout: Duration,
+    reply_timeout: Duration,
+    producer_mode: ProducerMode,
+    transactional_id: Option<String>,
+    commit_strategy: CommitStrategy,
+    unknown_event_policy: UnknownEventPolicy,
+    partitioning: Partitioning,
+    outbox_path: Option<PathBuf>,
+    topics: BTreeMap<String, String>,
+    kafka: BTreeMap<String, String>,
+    security: Security,
+}
+
+impl Settings {
+    /// Starts from the defaults of a service that serves HTTP on `bind`.
+    pub fn builder(bind: &str) -> SettingsBuilder {
+        SettingsBuilder {
+            defaults: Layer {
+                bootstrap_servers: Some("localhost:9092".to_string()),
+                bind: Some(bind.to_string()),
+                shutdown_timeout_secs: Some(10),
+                reply_timeout_ms: Some(5000),
+                commit_strategy: Some("batch".to_string()),
+                commit_batch_size: Some(100),
+                commit_interval_ms: Some(5000),
+                ..Layer::default()
+            },
+        }
+    }
+
+    pub fn bootstrap_servers(&self) -> &str {
+        &self.bootstrap_servers
+    }
+
+    /// The consumer group, set when the service has a default one.
+    pub fn group_id(&self) -> Option<&str> {
+        self.group_id.as_deref()
+    }
+
+    pub fn bind(&self) -> SocketAddr {
+        self.bind
+    }
+
+    /// Every topic the service uses, whatever its role.
+    pub fn topics(&self) -> impl Iterator<Item = &str> {
+        self.topics.values().map(String::as_str)
+    }
+
+    /// How long a service may spend draining Kafka work once asked to stop.
+    pub fn shutdown_timeout(&self) -> Duration {
+        self.shutdown_timeout
+    }
+
+    /// How long a request/reply service waits for a reply, unless a request
+    /// asks for less or more.
+    pub fn reply_timeout(&self) -> Duration {
+        self.reply_timeout
+    }
+
+    /// The topic configured for `role`.
+    ///
+    /// # Panics
+    ///
+    /// If the service did not declare `role` when it was built.
+    pub fn topic(&self, role: &str) -> &str {
+        self.topics
+            .get(role)
+            .unwrap_or_else(|| panic!("no topic declared for role {:?}", role))
+    }
+
+    pub fn security(&self) -> &Security {
+        &self.security
+    }
+
+    pub fn producer_mode(&self) -> ProducerMode {
+        self.producer_mode
+    }
+
+    /// When consumers commit the offsets of processed messages.
+    pub fn commit_strategy(&self) -> CommitStrategy {
+        self.commit_strategy
+    }
+
+    /// What consumers do with events of a type they do not know.
+    pub fn unknown_event_policy(&self) -> UnknownEventPolicy {
+        self.unknown_event_policy
+    }
+
+    /// Which partition producers publish each record to.
+    pub fn partitioning(&self) -> Partitioning {
+        self.partitioning
+    }
+
+    /// Where a producer keeps its outbox, if it has one.
+    pub fn outbox_path(&self) -> Option<&Path> {
+        self.outbox_path.as_deref()
+    }
+
+    /// A client config for producers: the brokers, the security settings and
+    /// every passthrough property.
+    pub fn client_config(&self) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", &self.bootstrap_servers);
+        for property in self.security.properties() {
+            match property.value {
+                PropertyValue::Plain(value) => config.set(property.key, value),
+                PropertyValue::Secret(secret) => config.set(property.key, secret.expose()),
+            };
+        }
+        for (key, value) in &self.kafka {
+            config.set(key, value);
+        }
+        config
+    }
+
+    /// A client config for the producer that follows the producer mode.
+    ///
+    /// A service creates at most one producer from it, since two producers
+    /// with one transactional id fence each other off.
+    pub fn producer_config(&self) -> ClientConfig {
+        let mut config = self.client_config();
+        if self.producer_mode != ProducerMode::Default {
+            config.set("enable.idempotence", "true");
+        }
+        if let Some(transactional_id) = &self.transactional_id {
+            config.set("transactional.id", transactional_id);
+        }
+        config
+    }
+
+    /// A client config for consumers, which also sets the consumer group.
+    /// Offsets are neither stored nor committed automatically, so the
+    /// service commits them once it has processed their messages.
+    pub fn consumer_config(&self) -> ClientConfig {
+        let mut config = self.client_config();
+        if let Some(group_id) = &self.group_id {
+            config.set("group.id", group_id);
+        }
+        config
+            .set("enable.auto.commit", "false")
+            .set("enable.auto.offset.store", "false");
+        config
+    }
+}
+
+impl fmt::Display for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bootstrap.servers={}", self.bootstrap_servers)?;
+        if let Some(group_id) = &self.group_id {
+            write!(f, " group.id={}", group_id)?;
+        }
+        if self.producer_mode != ProducerMode::Default {
+            write!(f, " producer_mode={}", self.producer_mode.as_str())?;
+        }
+        if let Some(transactional_id) = &self.transactional_id {
+            write!(f, " transactional.id={}", transactional_id)?;
+        }
+        for property in self.security.properties() {
+            match property.value {
+                PropertyValue::Plain(value) => write!(f, " {}={}", property.key, value)?,
+                PropertyValue::Secret(secret) => write!(f, " {}={}", property.key, secret)?,
+            }
+        }
+        for (key, value) in &self.kafka {
+            if looks_secret(key) {
+                write!(f, " {}=[redacted]", key)?;
+            } else {
+                write!(f, " {}={}", key, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Settings({}, bind={}, topics={:?})",
+            self, self.bind, self.topics
+        )
+    }
+}
+
+/// Passthrough properties the typed settings do not know about, such as
+/// `sasl.oauthbearer.client.secret`, are redacted by name.
+fn looks_secret(key: &str) -> bool {
+    key.contains("password") || key.contains("secret")
+}
+
+/// The defaults of a service, from which its [`Settings`] are loaded.
+pub struct SettingsBuilder {
+    defaults: Layer,
+}
+
+impl SettingsBuilder {
+    /// Makes the service a consumer in `group_id` by default. Services without
+    /// a group reject one in their configuration.
+    pub fn group_id(mut self, group_id: &str) -> Self {
+        self.defaults.group_id = Some(group_id.to_string());
+        self
+    }
+
+    /// Declares the topic the service uses for `role`.
+    pub fn topic(mut self, role: &str, name: &str) -> Self {
+        self.defaults
+            .topics
+            .insert(role.to_string(), name.to_string());
+        self
+    }
+
+    /// Loads the settings from the process environment and command line.
+    pub fn load(self) -> Result<Settings, ConfigError> {
+        self.load_from(std::env::vars(), std::env::args().skip(1))
+    }
+
+    /// Loads the settings from the given environment variables and command
+    /// line arguments, without the program name.
+    pub fn load_from<E, A>(self, env: E, args: A) -> Result<Settings, ConfigError>
+    where
+        E: IntoIterator<Item = (String, String)>,
+        A: IntoIterator<Item = String>,
+    {
+        let env: HashMap<String, String> = env.into_iter().collect();
+        let (config_file, cli) = Layer::from_args(args)?;
+        let (env_config_file, env_layer) = Layer::from_env(&env)?;
+
+        let file = match config_file.or(env_config_file) {
+            Some(path) => Layer::from_file(path)?,
+            None => Layer::default(),
+        };
+
+        let roles: Vec<String> = self.defaults.topics.keys().cloned().collect();
+        let consumer = self.defaults.group_id.is_some();
+        let merged = self.defaults.merge(file).merge(env_layer).merge(cli);
+        merged.validate(&roles, consumer, &env)
+    }
+}
+
+/// One source of settings. Anything left unset falls through to the layer
+/// below.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Layer {
+    bootstrap_servers: Option<String>,
+    group_id: Option<String>,
+    bind: Option<String>,
+    shutdown_timeout_secs: Option<u64>,
+    reply_timeout_ms: Option<u64>,
+    producer_mode: Option<ProducerMode>,
+    transactional_id: Option<String>,
+    commit_strategy: Option<String>,
+    commit_batch_size: Option<usize>,
+    commit_interval_ms: Option<u64>,
+    unknown_event_policy: Option<String>,
+    partitioning: Option<String>,
+    outbox_path: Option<PathBuf>,
+    #[serde(default)]
+    topics: BTreeMap<String, String>,
+    #[serde(default, deserialize_with = "properties")]
+    kafka: BTreeMap<String, String>,
+    #[serde(default)]
+    security: SecurityLayer,
+}
+
+impl Layer {
+    fn merge(mut self, over: Layer) -> Layer {
+        self.bootstrap_servers = over.bootstrap_servers.or(self.bootstrap_servers);
+        self.group_id = over.group_id.or(self.group_id);
+        self.bind = over.bind.or(self.bind);
+        self.shutdown_timeout_secs = over.shutdown_timeout_secs.or(self.shutdown_timeout_secs);
+        self.reply_timeout_ms = over.reply_timeout_ms.or(self.reply_timeout_ms);
+        self.producer_mode = over.producer_mode.or(self.producer_mode);
+        self.transactional_id = over.transactional_id.or(self.transactional_id);
+        self.commit_strategy = over.commit_strategy.or(self.commit_strategy);
+        self.commit_batch_size = over.commit_batch_size.or(self.commit_batch_size);
+        self.commit_interval_ms = over.commit_interval_ms.or(self.commit_interval_ms);
+        self.unknown_event_policy = over.unknown_event_policy.or(self.unknown_event_policy);
+        self.partitioning = over.partitioning.or(self.partitioning);
+        self.outbox_path = over.outbox_path.or(self.outbox_path);
+        self.topics.extend(over.topics);
+        self.kafka.extend(over.kafka);
+        self.security = self.security.merge(over.security);
+        self
+    }
+
+    fn from_file(path: PathBuf) -> Result<Layer, ConfigError> {
+        let contents = std::fs::read_to_string(&path).map_err(|source| ConfigError::Io {
+            path: path.clone(),
+            source,
+        })?;
+        toml::from_str(&contents).map_err(|source| ConfigError::Toml { path, source })
+    }
+
+    /// Reads the environment, returning the config file it names, if any.
+    fn from_env(env: &HashMap<String, String>) -> Result<(Option<PathBuf>, Layer), ConfigError> {
+        let mut config_file = None;
+        let mut layer = Layer::default();
+        for (name, value) in env {
+            let value = value.clone();
+            match name.as_str() {
+                "CONFIG_FILE" => config_file = Some(PathBuf::from(value)),
+                "KAFKA_BOOTSTRAP_SERVERS" => layer.bootstrap_servers = Some(value),
+                "KAFKA_GROUP_ID" => layer.group_id = Some(value),
+                "HTTP_BIND" => layer.bind = Some(value),
+                "SHUTDOWN_TIMEOUT_SECS" => {
+                    layer.shutdown_timeout_secs = Some(value.parse().map_err(|_| {
+                        invalid(
+                            "shutdown_timeout_secs",
+                            format!("{:?} is not a number of seconds", value),
+                        )
+                    })?)
+                }
+                "REPLY_TIMEOUT_MS" => {
+                    layer.reply_timeout_ms = Some(value.parse().map_err(|_| {
+                        invalid(
+                            "reply_timeout_ms",
+                            format!("{:?} is not a number of milliseconds", value),
+                        )
+                    })?)
+                }
+                "KAFKA_PRODUCER_MODE" => {
+                    layer.producer_mode =
+                        Some(value.parse().map_err(|e| invalid("producer_mode", e))?)
+                }
+                "KAFKA_TRANSACTIONAL_ID" => layer.transactional_id = Some(value),
+                "KAFKA_COMMIT_STRATEGY" => layer.commit_strategy = Some(value),
+                "UNKNOWN_EVENT_POLICY" => layer.unknown_event_policy = Some(value),
+                "KAFKA_PARTITIONING" => layer.partitioning = Some(value),
+                "OUTBOX_PATH" => layer.outbox_path = Some(PathBuf::from(value)),
+                "KAFKA_COMMIT_BATCH_SIZE" => {
+                    layer.commit_batch_size = Some(value.parse().map_err(|_| {
+                        invalid(
+                            "commit_batch_size",
+                            format!("{:?} is not a number of messages", value),
+                        )
+                    })?)
+                }
+                "KAFKA_COMMIT_INTERVAL_MS" => {
+                    layer.commit_interval_ms = Some(value.parse().map_err(|_| {
+                        invalid(
+                            "commit_interval_ms",
+                            format!("{:?} is not a number of milliseconds", value),
+                        )
+                    })?)
+                }
+                _ => {
+                    if layer.security.set_from_env(name, &value)? {
+                        continue;
+                    }
+                    if let Some(role) = name.strip_prefix("KAFKA_TOPIC_") {
+                        layer.topics.insert(role.to_lowercase(), value);
+                    } else if let Some(key) = name.strip_prefix("KAFKA_PROPERTY_") {
+                        layer
+                            .kafka
+                            .insert(key.to_lowercase().replace('_', "."), value);
+                    }
+                }
+            }
+        }
+        Ok((config_file, layer))
+    }
+
+    /// Parses the command line, returning the config file it names, if any.
+    fn from_args<A>(args: A) -> Result<(Option<PathBuf>, Layer), ConfigError>
+    where
+        A: IntoIterator<Item = String>,
+    {
+        let mut config_file = None;
+        let mut layer = Layer::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value)),
+                _ => (arg.clone(), None),
+            };
+            let mut value = || match inline {
+                Some(value) => Ok(value.to_string()),
+                None => args
+                    .next()
+                    .ok_or_else(|| ConfigError::Usage(format!("{} needs a value", flag))),
+            };
+            match flag.as_str() {
+                "--config" => config_file = Some(PathBuf::from(value()?)),
+                "--bootstrap-servers" => layer.bootstrap_servers = Some(value()?),
+                "--group-id" => layer.group_id = Some(value()?),
+                "--bind" => layer.bind = Some(value()?),
+                "--shutdown-timeout-secs" => {
+                    let secs = value()?;
+                    layer.shutdown_timeout_secs = Some(secs.parse().map_err(|_| {
+                        ConfigError::Usage(format!(
+                            "{} expects a number of seconds, got {:?}",
+                            flag, secs
+                        ))
+                    })?)
+                }
+                "--reply-timeout-ms" => {
+                    let millis = value()?;
+                    layer.reply_timeout_ms = Some(millis.parse().map_err(|_| {
+                        ConfigError::Usage(format!(
+                            "{} expects a number of milliseconds, got {:?}",
+                            flag, millis
+                        ))
+                    })?)
+                }
+                "--producer-mode" => {
+                    layer.producer_mode = Some(value()?.parse().map_err(ConfigError::Usage)?)
+                }
+                "--transactional-id" => layer.transactional_id = Some(value()?),
+                "--commit-strategy" => layer.commit_strategy = Some(value()?),
+                "--unknown-event-policy" => layer.unknown_event_policy = Some(value()?),
+                "--partitioning" => layer.partitioning = Some(value()?),
+                "--outbox-path" => layer.outbox_path = Some(PathBuf::from(value()?)),
+                "--commit-batch-size" => {
+                    let messages = value()?;
+                    layer.commit_batch_size = Some(messages.parse().map_err(|_| {
+                        ConfigError::Usage(format!(
+                            "{} expects a number of messages, got {:?}",
+                            flag, messages
+                        ))
+                    })?)
+                }
+                "--commit-interval-ms" => {
+                    let millis = value()?;
+                    layer.commit_interval_ms = Some(millis.parse().map_err(|_| {
+                        ConfigError::Usage(format!(
+                            "{} expects a number of milliseconds, got {:?}",
+                            flag, millis
+                        ))
+                    })?)
+                }
+                "--topic" => {
+                    let (role, name) = key_value(&flag, &value()?)?;
+                    layer.topics.insert(role, name);
+                }
+                flag if SecurityLayer::takes_flag(flag) => {
+                    layer.security.set_from_flag(flag, value()?)?
+                }
+                "-X" | "--kafka" => {
+                    let (key, property) = key_value(&flag, &value()?)?;
+                    layer.kafka.insert(key, property);
+                }
+                _ => return Err(ConfigError::Usage(format!("unknown argument {}", arg))),
+            }
+        }
+        Ok((config_file, layer))
+    }
+
+    fn validate(
+        self,
+        roles: &[String],
+        consumer: bool,
+        env: &HashMap<String, String>,
+    ) -> Result<Settings, ConfigError> {
+        let bootstrap_servers = self.bootstrap_servers.unwrap_or_default();
+        if bootstrap_servers.trim().is_empty() {
+            return Err(invalid(
+                "bootstrap_servers",
+                "must name at least one broker",
+            ));
+        }
+
+        let group_id = match self.group_id {
+            Some(group_id) if !consumer => {
+                return Err(invalid(
+                    "group_id",
+                    format!("{:?} given, but this service does not consume", group_id),
+                ))
+            }
+            Some(group_id) if group_id.trim().is_empty() => {
+                return Err(invalid("group_id", "must not be empty"))
+            }
+            group_id => group_id,
+        };
+
+        let bind = self.bind.unwrap_or_default();
+        let bind = bind
+            .parse()
+            .map_err(|_| invalid("bind", format!("{:?} is not a socket address", bind)))?;
+
+        for (role, name) in &self.topics {
+            let key = format!("topics.{}", role);
+            if !roles.contains(role) {
+                return Err(invalid(
+                    &key,
+                    format!("unknown topic role, expected one of {}", roles.join(", ")),
+                ));
+            }
+            if let Err(reason) = validate_topic_name(name) {
+                return Err(invalid(&key, reason));
+            }
+        }
+
+        for key in self.kafka.keys() {
+            if MANAGED_PROPERTIES.contains(&key.as_str())
+                || security::MANAGED_PROPERTIES.contains(&key.as_str())
+            {
+                return Err(invalid(
+                    &format!("kafka.{}", key),
+                    "is set by the typed settings and cannot be passed through",
+                ));
+            }
+        }
+
+        let producer_mode = self.producer_mode.unwrap_or_default();
+        let transactional_id = match (producer_mode, self.transactional_id) {
+            (ProducerMode::Transactional, Some(id)) if !id.trim().is_empty() => Some(id),
+            (ProducerMode::Transactional, _) => {
+                return Err(invalid(
+                    "transactional_id",
+                    "is required when producer_mode is transactional",
+                ))
+            }
+            (_, Some(id)) => {
+                return Err(invalid(
+                    "transactional_id",
+                    format!("{:?} given, but producer_mode is not transactional", id),
+                ))
+            }
+            (_, None) => None,
+        };
+        if producer_mode != ProducerMode::Default && self.kafka.contains_key("enable.idempotence") {
+            return Err(invalid(
+                "kafka.enable.idempotence",
+                "is set by producer_mode and cannot be passed through",
+            ));
+        }
+
+        match &self.outbox_path {
+            Some(path) if path.as_os_str().is_empty() => {
+                return Err(invalid("outbox_path", "must not be empty"))
+            }
+            Some(_) if producer_mode == ProducerMode::Transactional => {
+                return Err(invalid(
+                    "outbox_path",
+                    "cannot be used with a transactional producer",
+                ))
+            }
+            _ => {}
+        }
+
+        let commit_strategy = match self.commit_strategy.as_deref() {
+            Some("per_message") => CommitStrategy::PerMessage,
+            Some("async") => CommitStrategy::Async,
+            Some("batch") | None => CommitStrategy::Batch {
+                messages: match self.commit_batch_size {
+                    Some(0) | None => return Err(invalid("commit_batch_size", "must be positive")),
+                    Some(messages) => messages,
+                },
+                interval: match self.commit_interval_ms {
+                    Some(0) | None => {
+                        return Err(invalid("commit_interval_ms", "must be positive"))
+                    }
+                    Some(millis) => Duration::from_millis(millis),
+                },
+            },
+            Some(strategy) => {
+                return Err(invalid(
+                    "commit_strategy",
+                    format!("{:?} is not one of per_message, batch, async", strategy),
+                ))
+            }
+        };
+
+        let unknown_event_policy = match &self.unknown_event_policy {
+            Some(policy) => policy
+                .parse()
+                .map_err(|e| invalid("unknown_event_policy", e))?,
+            None => UnknownEventPolicy::default(),
+        };
+        let partitioning = match &self.partitioning {
+            Some(partitioning) => partitioning
+                .parse()
+                .map_err(|e| invalid("partitioning", e))?,
+            None => Partitioning::default(),
+        };
+
+        let shutdown_timeout = Duration::from_secs(self.shutdown_timeout_secs.unwrap_or_default());
+        let reply_timeout = match self.reply_timeout_ms {
+            Some(0) | None => return Err(invalid("reply_timeout_ms", "must be positive")),
+            Some(millis) => Duration::from_millis(millis),
+        };
+        let security = self.security.resolve(env)?;
+
+        Ok(Settings {
+            bootstrap_servers,
+            group_id,
+            bind,
+            shutdown_timeout,
+            reply_timeout,
+            producer_mode,
+            transactional_id,
+            commit_strategy,
+            unknown_event_policy,
+            partitioning,
+            outbox_path: self.outbox_path,
+            topics: self.topics,
+            kafka: self.kafka,
+            security,
+        })
+    }
+}
+
+fn key_value(flag: &str, value: &str) -> Result<(String, String), ConfigError> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(ConfigError::Usage(format!(
+            "{} expects key=value, got {:?}",
+            flag, value
+        ))),
+    }
+}
+
+/// Kafka's own limits on topic names.
+fn validate_topic_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(format!("{:?} is not a valid topic name", name));
+    }
+    if name.len() > 249 {
+        return Err(format!("{:?} is longer than 249 characters", name));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')))
+    {
+        return Err(format!("{:?} contains {:?}", name, c));
+    }
+    Ok(())
+}
+
+/// Accepts TOML strings, numbers and booleans as librdkafka property values.
+fn properties<'de, D>(deserializer: D) -> Result<BTreeMap<String, String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Property {
+        String(String),
+        Integer(i64),
+        Float(f64),
+        Boolean(bool),
+    }
+
+    let properties = BTreeMap::<String, Property>::deserialize(deserializer)?;
+    Ok(properties
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Property::String(s) => s,
+                Property::Integer(i) => i.to_string(),
+                Property::Float(f) => f.to_string(),
+                Property::Boolean(b) => b.to_string(),
+            };
+            (key, value)
+        })
+        .collect())
+}
+
+pub(crate) fn invalid(key: &str, reason: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid {
+        key: key.to_string(),
+        reason: reason.into(),
+    }
+}
+
+/// Why a service could not load its settings.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file could not be read.
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// The config file is not valid TOML or has unknown keys.
+    Toml {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    /// The command line could not be parsed.
+    Usage(String),
+    /// A setting has a value the service cannot run with.
+    Invalid { key: String, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "cannot read {}: {}", path.display(), source)
+            }
+            ConfigError::Toml { path, source } => {
+                write!(f, "invalid config file {}: {}", path.display(), source)
+            }
+            ConfigError::Usage(message) => message.fmt(f),
+            ConfigError::Invalid { key, reason } => write!(f, "invalid {}: {}", key, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            ConfigError::Toml { source, .. } => Some(source),
+            ConfigError::Usage(_) | ConfigError::Invalid { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn builder() -> SettingsBuilder {
+        Settings::builder("127.0.0.1:8080")
+            .group_id("products-group")
+            .topic("products", "products")
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn defaults_to_the_compiled_in_settings() {
+        let settings = builder().load_from(env(&[]), args(&[])).unwrap();
+
+        assert_eq!(settings.bootstrap_servers(), "localhost:9092");
+        assert_eq!(settings.group_id(), Some("products-group"));
+        assert_eq!(settings.bind(), "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(settings.topic("products"), "products");
+        assert_eq!(settings.reply_timeout(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn file_is_overridden_by_env_which_is_overridden_by_flags() {
+        let mut file = tempfile();
+        writeln!(
+            file,
+            r#"
+            bootstrap_servers = "file:9092"
+            group_id = "file-group"
+            bind = "127.0.0.1:9000"
+
+            [topics]
+            products = "file-products"
+
+            [kafka]
+            "session.timeout.ms" = 6000
+            "enable.idempotence" = true
+            "#
+        )
+        .unwrap();
+
+        let settings = builder()
+            .load_from(
+                env(&[
+                    ("CONFIG_FILE", file.path().to_str().unwrap()),
+                    ("KAFKA_BOOTSTRAP_SERVERS", "env:9092"),
+                    ("KAFKA_GROUP_ID", "env-group"),
+                    ("KAFKA_PROPERTY_SESSION_TIMEOUT_MS", "7000"),
+                ]),
+                args(&[
+                    "--bootstrap-servers",
+                    "cli:9092",
+                    "--topic=products=cli-products",
+                    "--reply-timeout-ms=250",
+                ]),
+            )
+            .unwrap();
+
+        assert_eq!(settings.bootstrap_servers(), "cli:9092");
+        assert_eq!(settings.group_id(), Some("env-group"));
+        assert_eq!(settings.bind(), "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(settings.topic("products"), "cli-products");
+        assert_eq!(settings.reply_timeout(), Duration::from_millis(250));
+
+        let config = settings.consumer_config();
+        assert_eq!(config.get("bootstrap.servers"), Some("cli:9092"));
+        assert_eq!(config.get("group.id"), Some("env-group"));
+        assert_eq!(config.get("session.timeout.ms"), Some("7000"));
+        assert_eq!(config.get("enable.idempotence"), Some("true"));
+    }
+
+    #[test]
+    fn passes_properties_through_from_flags() {
+        let settings = builder()
+            .load_from(env(&[]), args(&["-X", "client.id=catalogue"]))
+            .unwrap();
+
+        assert_eq!(settings.client_config().get("client.id"), Some("catalogue"));
+        assert_eq!(settings.client_config().get("group.id"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_settings_at_startup() {
+        let error = |vars: &[(&str, &str)], flags: &[&str]| {
+            builder()
+                .load_from(env(vars), args(flags))
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            error(&[("HTTP_BIND", "localhost")], &[]),
+            r#"invalid bind: "localhost" is not a socket address"#
+        );
+        assert_eq!(
+            error(&[("KAFKA_BOOTSTRAP_SERVERS", " ")], &[]),
+            "invalid bootstrap_servers: must name at least one broker"
+        );
+        assert_eq!(
+            error(&[("KAFKA_TOPIC_PRODUCTS", "products/v2")], &[]),
+            r#"invalid topics.products: "products/v2" contains '/'"#
+        );
+        assert_eq!(
+            error(&[], &["--topic", "replies=product_reply"]),
+            "invalid topics.replies: unknown topic role, expected one of products"
+        );
+        assert_eq!(
+            error(&[], &["-X", "group.id=other"]),
+            "invalid kafka.group.id: is set by the typed settings and cannot be passed through"
+        );
+        assert_eq!(
+            error(&[("REPLY_TIMEOUT_MS", "0")], &[]),
+            "invalid reply_timeout_ms: must be positive"
+        );
+        assert_eq!(error(&[], &["--bind"]), "--bind needs a value");
+        assert_eq!(error(&[], &["--verbose"]), "unknown argument --verbose");
+    }
+
+    #[test]
+    fn producer_mode_sets_idempotence_and_the_transactional_id() {
+        let settings = builder()
+            .load_from(
+                env(&[
+                    ("KAFKA_PRODUCER_MODE", "transactional"),
+                    ("KAFKA_TRANSACTIONAL_ID", "products-1"),
+                ]),
+                args(&[]),
+            )
+            .unwrap();
+        assert_eq!(settings.producer_mode(), ProducerMode::Transactional);
+        let config = settings.producer_config();
+        assert_eq!(config.get("enable.idempotence"), Some("true"));
+        assert_eq!(config.get("transactional.id"), Some("products-1"));
+        assert_eq!(settings.client_config().get("transactional.id"), None);
+
+        let settings = builder()
+            .load_from(env(&[]), args(&["--producer-mode", "idempotent"]))
+            .unwrap();
+        let config = settings.producer_config();
+        assert_eq!(config.get("enable.idempotence"), Some("true"));
+        assert_eq!(config.get("transactional.id"), None);
+
+        let error = |vars: &[(&str, &str)], flags: &[&str]| {
+            builder()
+                .load_from(env(vars), args(flags))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(&[], &["--producer-mode=transactional"]),
+            "invalid transactional_id: is required when producer_mode is transactional"
+        );
+        assert_eq!(
+            error(&[("KAFKA_TRANSACTIONAL_ID", "products-1")], &[]),
+            r#"invalid transactional_id: "products-1" given, but producer_mode is not transactional"#
+        );
+        assert_eq!(
+            error(
+                &[("KAFKA_PRODUCER_MODE", "idempotent")],
+                &["-X", "enable.idempotence=false"]
+            ),
+            "invalid kafka.enable.idempotence: is set by producer_mode and cannot be passed through"
+        );
+        assert_eq!(
+            error(&[], &["--producer-mode", "exactly-once"]),
+            r#""exactly-once" is not one of default, idempotent, transactional"#
+        );
+    }
+
+    #[test]
+    fn consumers_commit_with_the_configured_strategy() {
+        let settings = builder().load_from(env(&[]), args(&[])).unwrap();
+        assert_eq!(settings.commit_strategy(), CommitStrategy::default());
+        let config = settings.consumer_config();
+        assert_eq!(config.get("enable.auto.commit"), Some("false"));
+        assert_eq!(config.get("enable.auto.offset.store"), Some("false"));
+
+        let settings = builder()
+            .load_from(
+                env(&[("KAFKA_COMMIT_BATCH_SIZE", "10")]),
+                args(&["--commit-interval-ms=250"]),
+            )
+            .unwrap();
+        assert_eq!(
+            settings.commit_strategy(),
+            CommitStrategy::Batch {
+                messages: 10,
+                interval: Duration::from_millis(250)
+            }
+        );
+
+        let settings = builder()
+            .load_from(env(&[("KAFKA_COMMIT_STRATEGY", "per_message")]), args(&[]))
+            .unwrap();
+        assert_eq!(settings.commit_strategy(), CommitStrategy::PerMessage);
+
+        let error = |vars: &[(&str, &str)], flags: &[&str]| {
+            builder()
+                .load_from(env(vars), args(flags))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(&[], &["--commit-strategy", "auto"]),
+            r#"invalid commit_strategy: "auto" is not one of per_message, batch, async"#
+        );
+        assert_eq!(
+            error(&[("KAFKA_COMMIT_BATCH_SIZE", "0")], &[]),
+            "invalid commit_batch_size: must be positive"
+        );
+        assert_eq!(
+            error(&[], &["-X", "enable.auto.commit=true"]),
+            "invalid kafka.enable.auto.commit: is set by the typed settings and cannot be passed through"
+        );
+    }
+
+    #[test]
+    fn parses_the_unknown_event_policy() {
+        let settings = builder().load_from(env(&[]), args(&[])).unwrap();
+        assert_eq!(settings.unknown_event_policy(), UnknownEventPolicy::Reject);
+
+        let mut file = tempfile();
+        writeln!(file, r#"unknown_event_policy = "ignore""#).unwrap();
+        let settings = builder()
+            .load_from(
+                env(&[("CONFIG_FILE", file.path().to_str().unwrap())]),
+                args(&[]),
+            )
+            .unwrap();
+        assert_eq!(settings.unknown_event_policy(), UnknownEventPolicy::Ignore);
+
+        let settings = builder()
+            .load_from(
+                env(&[("UNKNOWN_EVENT_POLICY", "ignore")]),
+                args(&["--unknown-event-policy=dead-letter"]),
+            )
+            .unwrap();
+        assert_eq!(
+            settings.unknown_event_policy(),
+            UnknownEventPolicy::DeadLetter
+        );
+
+        let error = builder()
+            .load_from(env(&[("UNKNOWN_EVENT_POLICY", "drop")]), args(&[]))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            r#"invalid unknown_event_policy: unknown event policy "drop", expected one of reject, dead-letter or ignore"#
+        );
+    }
+
+    #[test]
+    fn parses_the_partitioning() {
+        let settings = builder().load_from(env(&[]), args(&[])).unwrap();
+        assert_eq!(settings.partitioning(), Partitioning::KeyHash);
+
+        let mut file = tempfile();
+        writeln!(file, r#"partitioning = "explicit:2""#).unwrap();
+        let settings = builder()
+            .load_from(
+                env(&[("CONFIG_FILE", file.path().to_str().unwrap())]),
+                args(&[]),
+            )
+            .unwrap();
+        assert_eq!(settings.partitioning(), Partitioning::Explicit(2));
+
+        let settings = builder()
+            .load_from(
+                env(&[("KAFKA_PARTITIONING", "explicit:2")]),
+                args(&["--partitioning", "key-hash"]),
+            )
+            .unwrap();
+        assert_eq!(settings.partitioning(), Partitioning::KeyHash);
+
+        for (partitioning, reason) in [
+            ("explicit:-1", r#""explicit:-1" does not name a partition"#),
+            (
+                "round-robin",
+                r#""round-robin" is not one of key-hash, explicit:<n>"#,
+            ),
+        ] {
+            let error = builder()
+                .load_from(env(&[("KAFKA_PARTITIONING", partitioning)]), args(&[]))
+                .unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("invalid partitioning: {}", reason)
+            );
+        }
+    }
+
+    #[test]
+    fn outboxes_are_not_transactional() {
+        let mut file = tempfile();
+        writeln!(file, r#"outbox_path = "/var/lib/provider/outbox.db""#).unwrap();
+        let settings = builder()
+            .load_from(
+                env(&[("CONFIG_FILE", file.path().to_str().unwrap())]),
+                args(&[]),
+            )
+            .unwrap();
+        assert_eq!(
+            settings.outbox_path(),
+            Some(Path::new("/var/lib/provider/outbox.db"))
+        );
+
+        let error = builder()
+            .load_from(
+                env(&[
+                    ("OUTBOX_PATH", "outbox.db"),
+                    ("KAFKA_PRODUCER_MODE", "transactional"),
+                    ("KAFKA_TRANSACTIONAL_ID", "products-1"),
+                ]),
+                args(&[]),
+            )
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid outbox_path: cannot be used with a transactional producer"
+        );
+    }
+
+    #[test]
+    fn only_consumers_take_a_group_id() {
+        let error = Settings::builder("127.0.0.1:8081")
+            .load_from(env(&[("KAFKA_GROUP_ID", "products-group")]), args(&[]))
+            .unwrap_err();
+
+        assert!(matches!(error, ConfigError::Invalid { key, .. } if key == "group_id"));
+    }
+
+    #[test]
+    fn reports_unknown_keys_in_the_config_file() {
+        let mut file = tempfile();
+        writeln!(file, r#"bootstrap_server = "typo:9092""#).unwrap();
+
+        let error = builder()
+            .load_from(env(&[]), args(&["--config", file.path().to_str().unwrap()]))
+            .unwrap_err();
+
+        assert!(matches!(error, ConfigError::Toml { .. }));
+    }
+
+    fn tempfile() -> NamedTempFile {
+        tempfile::Builder::new().suffix(".toml").tempfile().unwrap()
+    }
+}
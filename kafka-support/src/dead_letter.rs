@@ -0,0 +1,224 @@
+//! Parking messages a consumer cannot handle on a dead-letter topic.
+//!
+//! The dead-letter record keeps the original key, payload and headers, so it
+//! can be inspected and replayed onto the source topic unchanged. Where it came
+//! from and why it was rejected travel in extra headers.
+//!
+//! A poison message is only committed once it has been parked. If it cannot
+//! be, [`DeadLetterQueue::handle_process_error`] returns the error and the
+//! consumer consumes the message again rather than losing it.
+
+use product_domain::{ProcessError, UnknownEventPolicy};
+use rdkafka::error::KafkaError;
+use rdkafka::message::{Header, Headers, Message, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord, Producer};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+pub const ORIGINAL_TOPIC_HEADER: &str = "dlt-original-topic";
+pub const ORIGINAL_PARTITION_HEADER: &str = "dlt-original-partition";
+pub const ORIGINAL_OFFSET_HEADER: &str = "dlt-original-offset";
+pub const REASON_HEADER: &str = "dlt-reason";
+
+/// How often a poison message is sent before giving up on it for now.
+const SEND_ATTEMPTS: u32 = 3;
+
+/// How long to wait before sending a poison message again, doubled after
+/// each attempt.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+pub struct DeadLetterQueue {
+    producer: FutureProducer,
+    topic: String,
+    unknown_event_policy: UnknownEventPolicy,
+    failed_events: AtomicU64,
+}
+
+impl DeadLetterQueue {
+    /// Parks poison messages on `topic`, including events of an unknown type
+    /// if the `unknown_event_policy` says so.
+    pub fn new(
+        producer: FutureProducer,
+        topic: &str,
+        unknown_event_policy: UnknownEventPolicy,
+    ) -> Self {
+        DeadLetterQueue {
+            producer,
+            topic: topic.to_string(),
+            unknown_event_policy,
+            failed_events: AtomicU64::new(0),
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Publishes a copy of `message` with `reason` attached and waits for the
+    /// broker to acknowledge it.
+    pub async fn send<M: Message>(&self, message: &M, reason: &str) -> Result<(), KafkaError> {
+        let mut record = FutureRecord::<[u8], [u8]>::to(&self.topic)
+            .headers(dead_letter_headers(message, reason));
+        if let Some(payload) = message.payload() {
+            record = record.payload(payload);
+        }
+        if let Some(key) = message.key() {
+            record = record.key(key);
+        }
+
+        self.producer
+            .send(record, Duration::from_secs(0))
+            .await
+            .map(|_| ())
+            .map_err(|(e, _)| e)
+    }
+
+    /// Counts and logs a failed event, and parks `message` here if it is
+    /// poison, retrying with backoff. An error means a poison message could
+    /// not be parked, so it must not be committed.
+    pub async fn handle_process_error<M: Message>(
+        &self,
+        message: &M,
+        error: &ProcessError,
+    ) -> Result<(), KafkaError> {
+        let failed = self.failed_events.fetch_add(1, Ordering::Relaxed) + 1;
+        eprintln!(
+            "Error processing event ({} failed so far): {}",
+            failed, error
+        );
+        if !error.is_poison(self.unknown_event_policy) {
+            return Ok(());
+        }
+
+        let reason = error.to_string();
+        let mut backoff = RETRY_BACKOFF;
+        for attempt in 1.. {
+            match self.send(message, &reason).await {
+                Ok(()) => {
+                    println!("Event sent to {} topic", self.topic);
+                    break;
+                }
+                Err(e) if attempt < SEND_ATTEMPTS => {
+                    eprintln!(
+                        "Error sending event to {} topic, retrying in {:?}: {}",
+                        self.topic, backoff, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for records still queued to be delivered.
+    pub fn flush(&self, timeout: Duration) -> Result<(), KafkaError> {
+        self.producer.flush(timeout)
+    }
+}
+
+/// The original headers of `message`, followed by the dead-letter headers.
+pub fn dead_letter_headers<M: Message>(message: &M, reason: &str) -> OwnedHeaders {
+    let mut headers = OwnedHeaders::new();
+    if let Some(original) = message.headers() {
+        for header in original.iter() {
+            headers = headers.insert(header);
+        }
+    }
+    headers
+        .insert(Header {
+            key: ORIGINAL_TOPIC_HEADER,
+            value: Some(message.topic()),
+        })
+        .insert(Header {
+            key: ORIGINAL_PARTITION_HEADER,
+            value: Some(&message.partition().to_string()),
+        })
+        .insert(Header {
+            key: ORIGINAL_OFFSET_HEADER,
+            value: Some(&message.offset().to_string()),
+        })
+        .insert(Header {
+            key: REASON_HEADER,
+            value: Some(reason),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdkafka::message::{OwnedMessage, Timestamp};
+    use rdkafka::ClientConfig;
+
+    fn message() -> OwnedMessage {
+        OwnedMessage::new(
+            Some(b"{}".to_vec()),
+            None,
+            "products".to_string(),
+            Timestamp::NotAvailable,
+            0,
+            7,
+            None,
+        )
+    }
+
+    #[test]
+    fn dead_letter_headers_keep_the_original_headers_and_record_the_source() {
+        let original = OwnedHeaders::new().insert(Header {
+            key: "content-type",
+            value: Some("application/json"),
+        });
+        let message = OwnedMessage::new(
+            Some(b"not json".to_vec()),
+            Some(b"some-key".to_vec()),
+            "products".to_string(),
+            Timestamp::NotAvailable,
+            2,
+            42,
+            Some(original),
+        );
+
+        let headers = dead_letter_headers(&message, "malformed product event");
+        let headers: Vec<(&str, Option<&[u8]>)> =
+            headers.iter().map(|h| (h.key, h.value)).collect();
+
+        assert_eq!(
+            headers,
+            vec![
+                ("content-type", Some(&b"application/json"[..])),
+                (ORIGINAL_TOPIC_HEADER, Some(&b"products"[..])),
+                (ORIGINAL_PARTITION_HEADER, Some(&b"2"[..])),
+                (ORIGINAL_OFFSET_HEADER, Some(&b"42"[..])),
+                (REASON_HEADER, Some(&b"malformed product event"[..])),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_poison_messages_it_could_not_park() {
+        // Nothing listens on port 1, so every send times out
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", "127.0.0.1:1")
+            .set("message.timeout.ms", "100")
+            .create()
+            .unwrap();
+        let queue = DeadLetterQueue::new(producer, "dead_letter", UnknownEventPolicy::Reject);
+
+        let poison = ProcessError::UnsupportedSchema {
+            version: "2".to_string(),
+        };
+        assert!(queue
+            .handle_process_error(&message(), &poison)
+            .await
+            .is_err());
+
+        let not_poison = ProcessError::NotFound {
+            id: "some-uuid-1234-5678".to_string(),
+        };
+        assert!(queue
+            .handle_process_error(&message(), &not_poison)
+            .await
+            .is_ok());
+    }
+}
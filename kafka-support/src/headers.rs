@@ -0,0 +1,64 @@
+//! Message headers as name/value pairs, so crates that describe their messages
+//! with headers do not need to depend on rdkafka's header types.
+
+use rdkafka::message::{Header, Headers, Message, OwnedHeaders};
+
+/// `headers` followed by `pairs`.
+pub fn with_pairs<'k, 'v, I>(headers: OwnedHeaders, pairs: I) -> OwnedHeaders
+where
+    I: IntoIterator<Item = (&'k str, &'v str)>,
+{
+    pairs.into_iter().fold(headers, |headers, (key, value)| {
+        headers.insert(Header {
+            key,
+            value: Some(value),
+        })
+    })
+}
+
+/// The headers of `message` that have a value, in order.
+pub fn pairs<M: Message>(message: &M) -> Vec<(&str, &[u8])> {
+    match message.headers() {
+        Some(headers) => headers
+            .iter()
+            .filter_map(|header| header.value.map(|value| (header.key, value)))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdkafka::message::{OwnedMessage, Timestamp};
+
+    #[test]
+    fn pairs_round_trip_through_headers() {
+        let headers = with_pairs(
+            OwnedHeaders::new().insert(Header {
+                key: "empty",
+                value: None::<&str>,
+            }),
+            [
+                ("content-type", "application/json"),
+                ("event-type", "UPDATED"),
+            ],
+        );
+        let message = OwnedMessage::new(
+            None,
+            None,
+            "products".to_string(),
+            Timestamp::NotAvailable,
+            0,
+            0,
+            Some(headers),
+        );
+        assert_eq!(
+            pairs(&message),
+            vec![
+                ("content-type", &b"application/json"[..]),
+                ("event-type", &b"UPDATED"[..]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,437 @@
+//! Liveness and readiness checks for the services' `/healthz` and `/readyz`
+//! endpoints.
+//!
+//! Liveness only says the process is serving HTTP. Readiness checks Kafka:
+//!
+//! - every configured topic has metadata on the brokers,
+//! - a consumer has been assigned partitions by its group,
+//! - a consumer has processed its partitions up to the high-watermarks they had
+//!   when it first saw them assigned, so a consumer still replaying its topic
+//!   is not ready. Once caught up it stays so, and a consumer that falls a
+//!   little behind under load is not taken out of service,
+//! - the service is not shutting down.
+//!
+//! The checks make blocking calls to the brokers, so [`readyz`] runs them on a
+//! blocking thread.
+
+use crate::shutdown::Shutdown;
+use actix_web::{web, HttpResponse};
+use rdkafka::client::{Client, ClientContext};
+use rdkafka::consumer::{Consumer, ConsumerContext, DefaultConsumerContext, StreamConsumer};
+use rdkafka::message::Message;
+use rdkafka::producer::{FutureProducer, Producer};
+use rdkafka::Offset;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The outcome of one readiness check.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String,
+}
+
+/// The outcome of all readiness checks, serialized as the `/readyz` body.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Report {
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    fn new() -> Self {
+        Report {
+            ready: true,
+            checks: Vec::new(),
+        }
+    }
+
+    fn check(mut self, name: &'static str, result: Result<String, String>) -> Self {
+        let (ok, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        self.ready &= ok;
+        self.checks.push(Check { name, ok, detail });
+        self
+    }
+}
+
+/// A service's readiness checks.
+pub trait Probe: Send + Sync + 'static {
+    fn report(&self) -> Report;
+}
+
+/// `GET /healthz`: the process is serving HTTP.
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+/// `GET /readyz`: the [`Report`] of the service's probe, with 200 if it is
+/// ready and 503 if not.
+pub async fn readyz<P: Probe>(probe: web::Data<P>) -> HttpResponse {
+    match web::block(move || probe.report()).await {
+        Ok(report) if report.ready => HttpResponse::Ok().json(report),
+        Ok(report) => HttpResponse::ServiceUnavailable().json(report),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+/// The next offset to process on each partition, as recorded by a consumer
+/// loop once it has finished with a message.
+#[derive(Debug, Default)]
+pub struct Progress {
+    offsets: Mutex<HashMap<(String, i32), i64>>,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that `message` has been processed.
+    pub fn record<M: Message>(&self, message: &M) {
+        self.offsets.lock().unwrap().insert(
+            (message.topic().to_string(), message.partition()),
+            message.offset() + 1,
+        );
+    }
+
+    fn get(&self, topic: &str, partition: i32) -> Option<i64> {
+        self.offsets
+            .lock()
+            .unwrap()
+            .get(&(topic.to_string(), partition))
+            .copied()
+    }
+}
+
+/// How far each partition has to be replayed before a consumer is first
+/// ready.
+#[derive(Debug, Default)]
+struct Replay {
+    /// The low and high watermarks of each partition when it was first seen
+    /// assigned.
+    watermarks: HashMap<(String, i32), (i64, i64)>,
+    done: bool,
+}
+
+/// Readiness of a service that consumes its topics into local state.
+pub struct ConsumerProbe<X: ConsumerContext + 'static = DefaultConsumerContext> {
+    consumer: Arc<StreamConsumer<X>>,
+    progress: Arc<Progress>,
+    topics: Vec<String>,
+    shutdown: Shutdown,
+    timeout: Duration,
+    replay: Mutex<Replay>,
+}
+
+impl<X: ConsumerContext + 'static> ConsumerProbe<X> {
+    /// `topics` are all the topics the service uses, not only the ones it
+    /// consumes.
+    pub fn new(
+        consumer: Arc<StreamConsumer<X>>,
+        progress: Arc<Progress>,
+        topics: Vec<String>,
+        shutdown: Shutdown,
+        timeout: Duration,
+    ) -> Self {
+        ConsumerProbe {
+            consumer,
+            progress,
+            topics,
+            shutdown,
+            timeout,
+            replay: Mutex::new(Replay::default()),
+        }
+    }
+
+    /// A partition is caught up once the next offset to process reaches the
+    /// high-watermark it had when first seen. Partitions with no processed
+    /// message yet fall back to the consumer's position, which covers a
+    /// restart with nothing new to read.
+    fn caught_up(&self) -> Result<String, String> {
+        let mut replay = self.replay.lock().unwrap();
+        if replay.done {
+            return Ok("caught up".to_string());
+        }
+        let position = self
+            .consumer
+            .position()
+            .map_err(|e| format!("cannot read position: {}", e))?;
+        if position.count() == 0 {
+            return Err("no partitions assigned yet".to_string());
+        }
+
+        let mut lagging = Vec::new();
+        for partition in position.elements() {
+            let (topic, number) = (partition.topic(), partition.partition());
+            let key = (topic.to_string(), number);
+            let (low, high) = match replay.watermarks.get(&key) {
+                Some(watermarks) => *watermarks,
+                None => {
+                    let watermarks = self
+                        .consumer
+                        .fetch_watermarks(topic, number, self.timeout)
+                        .map_err(|e| {
+                            format!("cannot fetch watermarks for {}/{}: {}", topic, number, e)
+                        })?;
+                    replay.watermarks.insert(key, watermarks);
+                    watermarks
+                }
+            };
+            if high <= low {
+                continue;
+            }
+            let next = self
+                .progress
+                .get(topic, number)
+                .or(match partition.offset() {
+                    Offset::Offset(offset) => Some(offset),
+                    _ => None,
+                });
+            match next {
+                Some(next) if next >= high => {}
+                Some(next) => lagging.push(format!("{}/{} lag {}", topic, number, high - next)),
+                None => lagging.push(format!("{}/{} lag {}", topic, number, high - low)),
+            }
+        }
+
+        if lagging.is_empty() {
+            replay.done = true;
+            Ok("caught up".to_string())
+        } else {
+            Err(lagging.join(", "))
+        }
+    }
+}
+
+impl<X: ConsumerContext + 'static> Probe for ConsumerProbe<X> {
+    fn report(&self) -> Report {
+        Report::new()
+            .check("shutdown", running(&self.shutdown))
+            .check(
+                "topics",
+                topics_available(self.consumer.client(), &self.topics, self.timeout),
+            )
+            .check("assignment", assigned(self.consumer.as_ref()))
+            .check("caught_up", self.caught_up())
+    }
+}
+
+/// Readiness of a service that publishes to its topics, optionally reading
+/// replies with a consumer.
+pub struct ProducerProbe {
+    producer: FutureProducer,
+    reply_consumer: Option<Arc<StreamConsumer>>,
+    topics: Vec<String>,
+    shutdown: Shutdown,
+    timeout: Duration,
+}
+
+impl ProducerProbe {
+    pub fn new(
+        producer: FutureProducer,
+        topics: Vec<String>,
+        shutdown: Shutdown,
+        timeout: Duration,
+    ) -> Self {
+        ProducerProbe {
+            producer,
+            reply_consumer: None,
+            topics,
+            shutdown,
+            timeout,
+        }
+    }
+
+    /// Also requires `consumer` to have been assigned its reply partitions.
+    pub fn with_reply_consumer(mut self, consumer: Arc<StreamConsumer>) -> Self {
+        self.reply_consumer = Some(consumer);
+        self
+    }
+}
+
+impl Probe for ProducerProbe {
+    fn report(&self) -> Report {
+        let report = Report::new()
+            .check("shutdown", running(&self.shutdown))
+            .check(
+                "topics",
+                topics_available(self.producer.client(), &self.topics, self.timeout),
+            );
+        match &self.reply_consumer {
+            Some(consumer) => report.check("assignment", assigned(consumer.as_ref())),
+            None => report,
+        }
+    }
+}
+
+fn running(shutdown: &Shutdown) -> Result<String, String> {
+    if shutdown.is_triggered() {
+        Err("shutting down".to_string())
+    } else {
+        Ok("running".to_string())
+    }
+}
+
+fn topics_available<C: ClientContext>(
+    client: &Client<C>,
+    topics: &[String],
+    timeout: Duration,
+) -> Result<String, String> {
+    for topic in topics {
+        let metadata = client
+            .fetch_metadata(Some(topic), timeout)
+            .map_err(|e| format!("cannot fetch metadata for {}: {}", topic, e))?;
+        match metadata.topics().first() {
+            Some(found) if found.error().is_none() && !found.partitions().is_empty() => {}
+            Some(found) => {
+                return Err(format!(
+                    "{} is unavailable: {:?}",
+                    topic,
+                    found.error().map(rdkafka::types::RDKafkaErrorCode::from)
+                ))
+            }
+            None => return Err(format!("{} is unavailable", topic)),
+        }
+    }
+    Ok(format!("{} reachable", topics.join(", ")))
+}
+
+fn assigned<X: ConsumerContext, C: Consumer<X>>(consumer: &C) -> Result<String, String> {
+    let assignment = consumer
+        .assignment()
+        .map_err(|e| format!("cannot read assignment: {}", e))?;
+    if assignment.count() == 0 {
+        return Err("no partitions assigned yet".to_string());
+    }
+    let partitions: Vec<String> = assignment
+        .elements()
+        .iter()
+        .map(|p| format!("{}/{}", p.topic(), p.partition()))
+        .collect();
+    Ok(partitions.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdkafka::mocking::MockCluster;
+    use rdkafka::producer::FutureRecord;
+    use rdkafka::ClientConfig;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn check<'a>(report: &'a Report, name: &str) -> &'a Check {
+        report.checks.iter().find(|c| c.name == name).unwrap()
+    }
+
+    #[tokio::test]
+    async fn producers_are_ready_once_their_topics_exist() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("products", 1, 1).unwrap();
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .set("allow.auto.create.topics", "false")
+            .create()
+            .unwrap();
+        let shutdown = Shutdown::new();
+
+        let probe = ProducerProbe::new(
+            producer.clone(),
+            vec!["products".to_string()],
+            shutdown.clone(),
+            TIMEOUT,
+        );
+        assert!(probe.report().ready);
+
+        let probe = ProducerProbe::new(
+            producer,
+            vec!["products".to_string(), "missing".to_string()],
+            shutdown.clone(),
+            TIMEOUT,
+        );
+        let report = probe.report();
+        assert!(!report.ready);
+        assert!(!check(&report, "topics").ok);
+
+        shutdown.trigger();
+        assert!(!check(&probe.report(), "shutdown").ok);
+    }
+
+    #[tokio::test]
+    async fn consumers_are_not_ready_while_replaying_their_topic() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("products", 1, 1).unwrap();
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .create()
+            .unwrap();
+        for event in ["first", "second"] {
+            producer
+                .send(
+                    FutureRecord::<(), str>::to("products").payload(event),
+                    TIMEOUT,
+                )
+                .await
+                .unwrap();
+        }
+
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .set("group.id", "products-group")
+            .set("auto.offset.reset", "earliest")
+            .create()
+            .unwrap();
+        consumer.subscribe(&["products"]).unwrap();
+        let consumer = Arc::new(consumer);
+        let progress = Arc::new(Progress::new());
+        let probe = ConsumerProbe::new(
+            consumer.clone(),
+            progress.clone(),
+            vec!["products".to_string()],
+            Shutdown::new(),
+            TIMEOUT,
+        );
+
+        let first = consumer.recv().await.unwrap();
+        progress.record(&first);
+        let probe = Arc::new(probe);
+        let report = tokio::task::spawn_blocking({
+            let probe = probe.clone();
+            move || probe.report()
+        })
+        .await
+        .unwrap();
+        assert!(check(&report, "assignment").ok);
+        assert_eq!(check(&report, "caught_up").detail, "products/0 lag 1");
+        assert!(!report.ready);
+
+        let second = consumer.recv().await.unwrap();
+        progress.record(&second);
+        let report = tokio::task::spawn_blocking({
+            let probe = probe.clone();
+            move || probe.report()
+        })
+        .await
+        .unwrap();
+        assert!(report.ready, "{:?}", report);
+
+        // Lagging behind new events once caught up does not flap readiness
+        producer
+            .send(
+                FutureRecord::<(), str>::to("products").payload("third"),
+                TIMEOUT,
+            )
+            .await
+            .unwrap();
+        let report = tokio::task::spawn_blocking(move || probe.report())
+            .await
+            .unwrap();
+        assert!(report.ready, "{:?}", report);
+    }
+}
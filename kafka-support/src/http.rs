@@ -0,0 +1,128 @@
+//! HTTP pieces shared by the providers' product endpoints. Errors are
+//! answered with RFC 7807 problem details.
+
+use actix_web::error::{InternalError, JsonPayloadError};
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+use product_domain::{IdMismatch, Product};
+use std::fmt;
+
+/// An RFC 7807 problem details response.
+pub fn problem(status: StatusCode, title: &str, detail: &str) -> HttpResponse {
+    HttpResponse::build(status)
+        .content_type("application/problem+json")
+        .json(serde_json::json!({
+            "type": "about:blank",
+            "title": title,
+            "status": status.as_u16(),
+            "detail": detail,
+        }))
+}
+
+/// Turns request bodies that fail to deserialize, such as a product with a
+/// malformed version, into a 400 problem response.
+pub fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let response = problem(StatusCode::BAD_REQUEST, "Invalid product", &err.to_string());
+    InternalError::from_response(err, response).into()
+}
+
+/// Why a `DELETE /products/{id}` does not name the product it removes.
+#[derive(Debug)]
+pub enum InvalidDelete {
+    /// The body is not a product.
+    Product(serde_json::Error),
+    /// `If-Match` does not hold a product version.
+    Version(String),
+    /// The body names a different product than the path.
+    IdMismatch(IdMismatch),
+}
+
+impl fmt::Display for InvalidDelete {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidDelete::Product(e) => e.fmt(f),
+            InvalidDelete::Version(if_match) => {
+                write!(f, "If-Match {:?} is not a product version", if_match)
+            }
+            InvalidDelete::IdMismatch(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for InvalidDelete {}
+
+impl ResponseError for InvalidDelete {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let title = match self {
+            InvalidDelete::Product(_) => "Invalid product",
+            InvalidDelete::Version(_) => "Invalid product version",
+            InvalidDelete::IdMismatch(_) => "Product id mismatch",
+        };
+        problem(self.status_code(), title, &self.to_string())
+    }
+}
+
+/// The product a `DELETE /products/{id}` removes. The body is optional; a
+/// request without one may name the version it deletes in `If-Match`, and
+/// otherwise deletes whatever version is stored.
+pub fn product_to_delete(
+    req: &HttpRequest,
+    id: &str,
+    body: &[u8],
+) -> Result<Product, InvalidDelete> {
+    if !body.is_empty() {
+        let product: Product = serde_json::from_slice(body).map_err(InvalidDelete::Product)?;
+        return product.with_id(id).map_err(InvalidDelete::IdMismatch);
+    }
+    let Some(if_match) = req.headers().get(header::IF_MATCH) else {
+        return Ok(Product::reference(id, None));
+    };
+    let version = if_match
+        .to_str()
+        .ok()
+        .and_then(|version| version.trim().trim_matches('"').parse().ok())
+        .ok_or_else(|| {
+            InvalidDelete::Version(String::from_utf8_lossy(if_match.as_bytes()).into_owned())
+        })?;
+    Ok(Product::reference(id, Some(version)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn deletes_need_no_body() {
+        let id = "some-uuid-1234-5678";
+        let without_version = TestRequest::delete().to_http_request();
+        assert_eq!(
+            product_to_delete(&without_version, id, b"").unwrap(),
+            Product::reference(id, None)
+        );
+
+        let with_version = TestRequest::delete()
+            .insert_header(("If-Match", "\"v3\""))
+            .to_http_request();
+        assert_eq!(
+            product_to_delete(&with_version, id, b"").unwrap(),
+            Product::reference(id, Some("v3".parse().unwrap()))
+        );
+
+        let invalid_version = TestRequest::delete()
+            .insert_header(("If-Match", "\"vX\""))
+            .to_http_request();
+        let error = product_to_delete(&invalid_version, id, b"").unwrap_err();
+        assert!(matches!(error, InvalidDelete::Version(_)), "{:?}", error);
+        assert_eq!(error.status_code(), StatusCode::BAD_REQUEST);
+
+        let body =
+            br#"{"id":"other-uuid","name":"Some Product","type":"Product Range","version":"v1"}"#;
+        let error = product_to_delete(&with_version, id, body).unwrap_err();
+        assert!(matches!(error, InvalidDelete::IdMismatch(_)), "{:?}", error);
+    }
+}
@@ -0,0 +1,13 @@
+//! Kafka plumbing, and the HTTP pieces around it, shared by the Rust consumers
+//! and providers.
+
+pub mod commit;
+pub mod config;
+pub mod dead_letter;
+pub mod headers;
+pub mod health;
+pub mod http;
+pub mod request_reply;
+pub mod security;
+pub mod shutdown;
+pub mod transaction;
@@ -0,0 +1,779 @@
+//! Request/reply over Kafka.
+//!
+//! A requester registers a [`PendingReply`] with the [`ReplyRouter`] before
+//! sending its request, and tags the request with the pending reply's
+//! correlation id and the topic replies should go to. The replier echoes the
+//! correlation id on its reply. One background listener reads the reply topic
+//! and hands each reply to the request that is waiting for it, so concurrent
+//! requests never see each other's replies.
+//!
+//! Requests also carry their deadline, as milliseconds since the Unix epoch,
+//! so repliers can drop requests nobody is waiting for any more instead of
+//! doing the work. That comparison uses the wall clocks of both hosts, so it
+//! is only as precise as their clocks are in sync.
+//!
+//! Each service instance has to read every reply sent to it, so its listener
+//! consumes the whole reply topic in a consumer group of its own, from where
+//! the topic ends when it starts; see [`listener_config`].
+//!
+//! Entries are removed when their reply arrives, when the requester stops
+//! waiting, and by a periodic sweep of requests whose deadline has passed.
+//! Replies nobody is waiting for any more are dropped.
+//!
+//! Repliers send through a [`ReplyPublisher`], which keeps one producer for
+//! the life of the service and does not wait for each reply to be delivered
+//! before sending the next. It reports which requests' replies were
+//! delivered, so a replier commits a request only once its reply is, and
+//! consumes it again if the reply fails.
+
+use crate::commit::Position;
+use crate::config::Settings;
+use crate::shutdown::Shutdown;
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::StreamConsumer;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::message::{Header, Headers, Message, OwnedHeaders, OwnedMessage, ToBytes};
+use rdkafka::producer::{DeliveryFuture, FutureProducer, FutureRecord};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+
+pub const CORRELATION_ID_HEADER: &str = "correlation_id";
+pub const REPLY_TO_HEADER: &str = "reply_to";
+pub const DEADLINE_HEADER: &str = "deadline";
+
+/// How often the listener sweeps out requests whose deadline has passed.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Entry {
+    sender: oneshot::Sender<OwnedMessage>,
+    deadline: Instant,
+}
+
+type Pending = Arc<Mutex<HashMap<String, Entry>>>;
+
+/// A client config for the consumer a [`ReplyRouter`] listens with. It joins
+/// a group named after the configured one but unique to this instance, so
+/// instances sharing a reply topic do not split its partitions, and starts
+/// at the end of the topic instead of replaying old replies.
+pub fn listener_config(settings: &Settings) -> ClientConfig {
+    let group_id = format!(
+        "{}-{}",
+        settings.group_id().unwrap_or("reply-listener"),
+        uuid::Uuid::new_v4()
+    );
+    let mut config = settings.consumer_config();
+    config
+        .set("group.id", group_id)
+        .set("auto.offset.reset", "latest");
+    config
+}
+
+/// Routes replies to the requests waiting for them by correlation id.
+pub struct ReplyRouter {
+    reply_topic: String,
+    pending: Pending,
+}
+
+impl ReplyRouter {
+    pub fn new(reply_topic: &str) -> Self {
+        ReplyRouter {
+            reply_topic: reply_topic.to_string(),
+            pending: Arc::default(),
+        }
+    }
+
+    /// The topic requests ask to be replied to on.
+    pub fn reply_topic(&self) -> &str {
+        &self.reply_topic
+    }
+
+    /// Registers a request that will wait up to `timeout` for its reply.
+    pub fn register(&self, timeout: Duration) -> PendingReply {
+        let correlation_id = uuid::Uuid::new_v4().to_string();
+        let deadline = Instant::now() + timeout;
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(correlation_id.clone(), Entry { sender, deadline });
+        PendingReply {
+            correlation_id,
+            deadline,
+            expires_at: SystemTime::now() + timeout,
+            receiver,
+            pending: self.pending.clone(),
+        }
+    }
+
+    /// The headers a request carries so its reply can be routed back here
+    /// before its deadline.
+    pub fn request_headers(&self, pending: &PendingReply) -> OwnedHeaders {
+        let deadline = pending
+            .expires_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+            .to_string();
+        OwnedHeaders::new()
+            .insert(Header {
+                key: CORRELATION_ID_HEADER,
+                value: Some(pending.correlation_id()),
+            })
+            .insert(Header {
+                key: REPLY_TO_HEADER,
+                value: Some(&self.reply_topic),
+            })
+            .insert(Header {
+                key: DEADLINE_HEADER,
+                value: Some(&deadline),
+            })
+    }
+
+    /// Hands `message` to the request waiting for it. Returns false if no
+    /// request is waiting, because it timed out or the reply is a duplicate.
+    pub fn dispatch(&self, message: OwnedMessage) -> bool {
+        let Some(correlation_id) = correlation_id(&message) else {
+            return false;
+        };
+        let entry = self.pending.lock().unwrap().remove(correlation_id);
+        match entry {
+            Some(entry) => entry.sender.send(message).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Removes the requests whose deadline has passed and returns how many.
+    pub fn expire(&self) -> usize {
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+        let before = pending.len();
+        pending.retain(|_, entry| entry.deadline > now);
+        before - pending.len()
+    }
+
+    /// How many requests are waiting for a reply.
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Reads replies from `consumer` and dispatches them until `shutdown` is
+    /// triggered. The consumer must already be subscribed to the reply topic.
+    /// Nothing is committed: the listener's group is not joined again.
+    pub async fn listen(&self, consumer: &StreamConsumer, shutdown: &Shutdown) {
+        let mut sweep = tokio::time::interval(SWEEP_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = shutdown.triggered() => break,
+                _ = sweep.tick() => {
+                    let expired = self.expire();
+                    if expired > 0 {
+                        eprintln!("Dropped {} requests that timed out waiting for a reply", expired);
+                    }
+                }
+                message = consumer.recv() => match message {
+                    Ok(message) => {
+                        if !self.dispatch(message.detach()) {
+                            eprintln!(
+                                "Dropping reply at {}/{}@{}: no request is waiting for it",
+                                message.topic(),
+                                message.partition(),
+                                message.offset()
+                            );
+                        }
+                    }
+                    Err(e) => eprintln!("Kafka error: {}", e),
+                },
+            }
+        }
+    }
+}
+
+/// A registered request waiting for its reply. Dropping it stops waiting.
+pub struct PendingReply {
+    correlation_id: String,
+    deadline: Instant,
+    expires_at: SystemTime,
+    receiver: oneshot::Receiver<OwnedMessage>,
+    pending: Pending,
+}
+
+impl PendingReply {
+    pub fn correlation_id(&self) -> &str {
+        &self.correlation_id
+    }
+
+    /// When the requester stops waiting for the reply.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Waits for the reply until the deadline given at registration.
+    pub async fn wait(mut self) -> Result<OwnedMessage, ReplyError> {
+        let deadline = tokio::time::Instant::from_std(self.deadline);
+        match tokio::time::timeout_at(deadline, &mut self.receiver).await {
+            Ok(Ok(message)) => Ok(message),
+            Ok(Err(_)) => Err(ReplyError::Expired),
+            Err(_) => Err(ReplyError::Timeout),
+        }
+    }
+}
+
+impl Drop for PendingReply {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.correlation_id);
+    }
+}
+
+/// Why a request got no reply.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyError {
+    /// No reply arrived before the deadline.
+    Timeout,
+    /// The request was swept out before its reply arrived.
+    Expired,
+}
+
+impl fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplyError::Timeout => f.write_str("timed out waiting for a reply"),
+            ReplyError::Expired => f.write_str("request expired before its reply arrived"),
+        }
+    }
+}
+
+impl std::error::Error for ReplyError {}
+
+/// What became of the reply to the request at `request`.
+#[derive(Debug)]
+pub struct Settled {
+    pub request: Position,
+    pub result: Result<(), KafkaError>,
+}
+
+/// A reply's delivery report, with the request it answers if it was sent
+/// with [`ReplyPublisher::send_reply`].
+struct Delivery {
+    request: Option<Position>,
+    report: DeliveryFuture,
+}
+
+impl Future for Delivery {
+    type Output = (Option<Position>, <DeliveryFuture as Future>::Output);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let report = std::task::ready!(Pin::new(&mut self.report).poll(cx));
+        Poll::Ready((self.request.take(), report))
+    }
+}
+
+/// Sends replies through one long-lived producer.
+///
+/// [`ReplyPublisher::send`] returns once the reply is queued in the producer,
+/// so replies are batched and pipelined instead of each waiting for its
+/// delivery report. Reports are collected as later replies are sent, or by
+/// [`ReplyPublisher::next_report`], and failures are logged and counted.
+/// Replies sent with [`ReplyPublisher::send_reply`] are also handed out by
+/// [`ReplyPublisher::settled`] once reported.
+pub struct ReplyPublisher {
+    producer: FutureProducer,
+    in_flight: FuturesUnordered<Delivery>,
+    settled: VecDeque<Settled>,
+    max_in_flight: usize,
+    failed: u64,
+}
+
+impl ReplyPublisher {
+    /// Once `max_in_flight` replies await their delivery report, sending
+    /// waits for one to be delivered.
+    pub fn new(producer: FutureProducer, max_in_flight: usize) -> Self {
+        ReplyPublisher {
+            producer,
+            in_flight: FuturesUnordered::new(),
+            settled: VecDeque::new(),
+            max_in_flight: max_in_flight.max(1),
+            failed: 0,
+        }
+    }
+
+    /// Queues `record` for delivery, waiting only while the producer's queue
+    /// or the in-flight limit is full.
+    pub async fn send<K, P>(&mut self, record: FutureRecord<'_, K, P>) -> Result<(), KafkaError>
+    where
+        K: ToBytes + ?Sized,
+        P: ToBytes + ?Sized,
+    {
+        self.queue(None, record).await
+    }
+
+    /// Queues `record` as the reply to `request`, like
+    /// [`ReplyPublisher::send`], and hands out whether it was delivered
+    /// from [`ReplyPublisher::settled`] once it is reported.
+    pub async fn send_reply<M, K, P>(
+        &mut self,
+        request: &M,
+        record: FutureRecord<'_, K, P>,
+    ) -> Result<(), KafkaError>
+    where
+        M: Message,
+        K: ToBytes + ?Sized,
+        P: ToBytes + ?Sized,
+    {
+        self.queue(Some(Position::of(request)), record).await
+    }
+
+    async fn queue<K, P>(
+        &mut self,
+        request: Option<Position>,
+        record: FutureRecord<'_, K, P>,
+    ) -> Result<(), KafkaError>
+    where
+        K: ToBytes + ?Sized,
+        P: ToBytes + ?Sized,
+    {
+        while let Some(Some(report)) = self.in_flight.next().now_or_never() {
+            self.delivered(report);
+        }
+        if self.in_flight.len() >= self.max_in_flight {
+            self.wait_for_one().await;
+        }
+
+        let mut record = record;
+        loop {
+            match self.producer.send_result(record) {
+                Ok(report) => {
+                    self.in_flight.push(Delivery { request, report });
+                    return Ok(());
+                }
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), returned)) => {
+                    record = returned;
+                    if self.in_flight.is_empty() {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    } else {
+                        self.wait_for_one().await;
+                    }
+                }
+                Err((e, _)) => {
+                    self.failed += 1;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// How many replies are waiting for their delivery report.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// How many replies could not be delivered.
+    pub fn failed(&self) -> u64 {
+        self.failed
+    }
+
+    /// Waits for the next delivery report. Never resolves while no reply is
+    /// in flight.
+    pub async fn next_report(&mut self) {
+        match self.in_flight.next().await {
+            Some(report) => self.delivered(report),
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Takes the replies sent with [`ReplyPublisher::send_reply`] that have
+    /// been reported since the last call, collecting the reports that are
+    /// ready first.
+    pub fn settled(&mut self) -> Vec<Settled> {
+        while let Some(Some(report)) = self.in_flight.next().now_or_never() {
+            self.delivered(report);
+        }
+        self.settled.drain(..).collect()
+    }
+
+    /// Waits up to `timeout` for every queued reply to be delivered or fail.
+    pub async fn flush(&mut self, timeout: Duration) -> Result<(), KafkaError> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while !self.in_flight.is_empty() {
+            match tokio::time::timeout_at(deadline, self.in_flight.next()).await {
+                Ok(Some(report)) => self.delivered(report),
+                Ok(None) => break,
+                Err(_) => return Err(KafkaError::Flush(RDKafkaErrorCode::OperationTimedOut)),
+            }
+        }
+        Ok(())
+    }
+
+    async fn wait_for_one(&mut self) {
+        if let Some(report) = self.in_flight.next().await {
+            self.delivered(report);
+        }
+    }
+
+    fn delivered(&mut self, (request, report): <Delivery as Future>::Output) {
+        let result = match report {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err((e, message))) => {
+                self.failed += 1;
+                eprintln!("Error delivering reply to {}: {}", message.topic(), e);
+                Err(e)
+            }
+            Err(_) => {
+                self.failed += 1;
+                eprintln!("Reply was dropped before delivery");
+                Err(KafkaError::Canceled)
+            }
+        };
+        if let Some(request) = request {
+            self.settled.push_back(Settled { request, result });
+        }
+    }
+}
+
+/// The correlation id of a message: its `correlation_id` header, or its key
+/// for repliers that only echo the key.
+pub fn correlation_id<M: Message>(message: &M) -> Option<&str> {
+    header(message, CORRELATION_ID_HEADER)
+        .or_else(|| message.key().and_then(|key| std::str::from_utf8(key).ok()))
+}
+
+/// When the requester stops waiting for a reply to `request`, if it said.
+pub fn deadline<M: Message>(request: &M) -> Option<SystemTime> {
+    let millis = header(request, DEADLINE_HEADER)?.parse().ok()?;
+    Some(UNIX_EPOCH + Duration::from_millis(millis))
+}
+
+/// Whether the requester has stopped waiting for a reply to `request`.
+pub fn expired<M: Message>(request: &M) -> bool {
+    deadline(request).is_some_and(|deadline| deadline <= SystemTime::now())
+}
+
+/// The topic to reply to `request` on: its `reply_to` header, or `default`
+/// for requesters that do not name one.
+pub fn reply_topic<'a, M: Message>(request: &'a M, default: &'a str) -> &'a str {
+    header(request, REPLY_TO_HEADER).unwrap_or(default)
+}
+
+/// The headers a reply to `request` carries: its correlation id, if it has one.
+pub fn reply_headers<M: Message>(request: &M) -> OwnedHeaders {
+    let headers = OwnedHeaders::new();
+    match correlation_id(request) {
+        Some(correlation_id) => headers.insert(Header {
+            key: CORRELATION_ID_HEADER,
+            value: Some(correlation_id),
+        }),
+        None => headers,
+    }
+}
+
+/// The value of the first header called `name`, if it is UTF-8.
+pub fn header<'a, M: Message>(message: &'a M, name: &str) -> Option<&'a str> {
+    message
+        .headers()?
+        .iter()
+        .find(|header| header.key == name)
+        .and_then(|header| header.value)
+        .and_then(|value| std::str::from_utf8(value).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdkafka::message::Timestamp;
+
+    fn reply(correlation_id: &str, payload: &str) -> OwnedMessage {
+        let headers = OwnedHeaders::new().insert(Header {
+            key: CORRELATION_ID_HEADER,
+            value: Some(correlation_id),
+        });
+        OwnedMessage::new(
+            Some(payload.as_bytes().to_vec()),
+            None,
+            "product_reply".to_string(),
+            Timestamp::NotAvailable,
+            0,
+            0,
+            Some(headers),
+        )
+    }
+
+    #[test]
+    fn each_listener_reads_the_reply_topic_in_a_group_of_its_own() {
+        let settings = Settings::builder("127.0.0.1:8081")
+            .group_id("product_group")
+            .topic("reply", "product_reply")
+            .load_from(Vec::new(), Vec::new())
+            .unwrap();
+
+        let first = listener_config(&settings);
+        let second = listener_config(&settings);
+
+        let group_id = first.get("group.id").unwrap();
+        assert!(group_id.starts_with("product_group-"), "{}", group_id);
+        assert_ne!(first.get("group.id"), second.get("group.id"));
+        assert_eq!(first.get("auto.offset.reset"), Some("latest"));
+        assert_eq!(first.get("enable.auto.commit"), Some("false"));
+    }
+
+    #[tokio::test]
+    async fn routes_each_reply_to_its_own_request() {
+        let router = ReplyRouter::new("product_reply");
+        let first = router.register(Duration::from_secs(5));
+        let second = router.register(Duration::from_secs(5));
+
+        assert!(router.dispatch(reply(second.correlation_id(), "second")));
+        assert!(router.dispatch(reply(first.correlation_id(), "first")));
+
+        assert_eq!(first.wait().await.unwrap().payload(), Some(&b"first"[..]));
+        assert_eq!(second.wait().await.unwrap().payload(), Some(&b"second"[..]));
+        assert_eq!(router.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn drops_replies_nobody_is_waiting_for() {
+        let router = ReplyRouter::new("product_reply");
+        let pending = router.register(Duration::from_secs(5));
+        let correlation_id = pending.correlation_id().to_string();
+
+        assert!(!router.dispatch(reply("unknown", "late")));
+        drop(pending);
+        assert_eq!(router.pending(), 0);
+        assert!(!router.dispatch(reply(&correlation_id, "late")));
+    }
+
+    #[tokio::test]
+    async fn times_out_and_forgets_the_request() {
+        let router = ReplyRouter::new("product_reply");
+        let pending = router.register(Duration::from_millis(10));
+
+        assert_eq!(pending.wait().await.unwrap_err(), ReplyError::Timeout);
+        assert_eq!(router.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn sweeps_requests_past_their_deadline() {
+        let router = ReplyRouter::new("product_reply");
+        let expired = router.register(Duration::ZERO);
+        let _waiting = router.register(Duration::from_secs(5));
+
+        assert_eq!(router.expire(), 1);
+        assert_eq!(router.pending(), 1);
+        assert_eq!(expired.wait().await.unwrap_err(), ReplyError::Expired);
+    }
+
+    #[test]
+    fn requests_carry_the_correlation_id_and_reply_topic() {
+        let router = ReplyRouter::new("product_reply_1");
+        let pending = router.register(Duration::from_secs(5));
+        let request = OwnedMessage::new(
+            None,
+            None,
+            "product_request".to_string(),
+            Timestamp::NotAvailable,
+            0,
+            0,
+            Some(router.request_headers(&pending)),
+        );
+
+        assert_eq!(correlation_id(&request), Some(pending.correlation_id()));
+        assert_eq!(reply_topic(&request, "product_reply"), "product_reply_1");
+        assert!(deadline(&request).unwrap() > SystemTime::now());
+        assert!(!expired(&request));
+        let reply = OwnedMessage::new(
+            None,
+            None,
+            "product_reply_1".to_string(),
+            Timestamp::NotAvailable,
+            0,
+            0,
+            Some(reply_headers(&request)),
+        );
+        assert_eq!(correlation_id(&reply), Some(pending.correlation_id()));
+    }
+
+    #[test]
+    fn requests_past_their_deadline_have_expired() {
+        let router = ReplyRouter::new("product_reply");
+        let pending = router.register(Duration::ZERO);
+        let request = OwnedMessage::new(
+            None,
+            None,
+            "product_request".to_string(),
+            Timestamp::NotAvailable,
+            0,
+            0,
+            Some(router.request_headers(&pending)),
+        );
+
+        assert!(expired(&request));
+    }
+
+    #[test]
+    fn replies_to_requests_without_headers_on_the_default_topic() {
+        let request = OwnedMessage::new(
+            None,
+            Some(b"some-correlation-id".to_vec()),
+            "product_request".to_string(),
+            Timestamp::NotAvailable,
+            0,
+            0,
+            None,
+        );
+
+        assert_eq!(reply_topic(&request, "product_reply"), "product_reply");
+        assert!(!expired(&request));
+        let headers = reply_headers(&request);
+        let echoed = headers.iter().next().unwrap();
+        assert_eq!(echoed.key, CORRELATION_ID_HEADER);
+        assert_eq!(echoed.value, Some(&b"some-correlation-id"[..]));
+    }
+
+    #[tokio::test]
+    async fn publishes_replies_without_waiting_for_each_delivery() {
+        use rdkafka::mocking::MockCluster;
+        use rdkafka::producer::{FutureProducer, Producer};
+        use rdkafka::ClientConfig;
+
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("product_reply", 1, 1).unwrap();
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .create()
+            .unwrap();
+        let mut publisher = ReplyPublisher::new(producer.clone(), 4);
+
+        for n in 0..10 {
+            let payload = n.to_string();
+            publisher
+                .send(FutureRecord::<(), str>::to("product_reply").payload(&payload))
+                .await
+                .unwrap();
+            assert!(publisher.in_flight() <= 4);
+        }
+        publisher.flush(Duration::from_secs(5)).await.unwrap();
+
+        assert_eq!(publisher.in_flight(), 0);
+        assert_eq!(publisher.failed(), 0);
+        let (_, high) = producer
+            .client()
+            .fetch_watermarks("product_reply", 0, Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(high, 10);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn requests_whose_replies_fail_are_consumed_again_before_they_are_committed() {
+        use crate::commit::{self, CommitStrategy, OffsetCommitter};
+        use rdkafka::consumer::Consumer;
+        use rdkafka::mocking::MockCluster;
+        use rdkafka::types::{RDKafkaApiKey, RDKafkaRespErr};
+        use rdkafka::{ClientConfig, Offset};
+
+        const TIMEOUT: Duration = Duration::from_secs(10);
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("product_request", 1, 1).unwrap();
+        cluster.create_topic("product_reply", 1, 1).unwrap();
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .create()
+            .unwrap();
+        for request in ["answered", "ignored"] {
+            producer
+                .send(
+                    FutureRecord::<(), str>::to("product_request").payload(request),
+                    TIMEOUT,
+                )
+                .await
+                .unwrap();
+        }
+        let consumer = commit::committing_consumer(
+            ClientConfig::new()
+                .set("bootstrap.servers", cluster.bootstrap_servers())
+                .set("group.id", "products-group")
+                .set("auto.offset.reset", "earliest")
+                .set("enable.auto.commit", "false")
+                .set("enable.auto.offset.store", "false"),
+        )
+        .unwrap();
+        consumer.subscribe(&["product_request"]).unwrap();
+        let mut committer = OffsetCommitter::new(CommitStrategy::PerMessage);
+        let mut publisher = ReplyPublisher::new(producer, 4);
+        let committed = || {
+            consumer
+                .committed(TIMEOUT)
+                .unwrap()
+                .find_partition("product_request", 0)
+                .map(|partition| partition.offset())
+        };
+
+        // The first reply is refused, and the request after it needs none
+        cluster.request_errors(
+            RDKafkaApiKey::Produce,
+            &[RDKafkaRespErr::RD_KAFKA_RESP_ERR_MSG_SIZE_TOO_LARGE],
+        );
+        let answered = tokio::time::timeout(TIMEOUT, consumer.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        publisher
+            .send_reply(
+                &answered,
+                FutureRecord::<(), str>::to("product_reply").payload("reply"),
+            )
+            .await
+            .unwrap();
+        committer.defer(&answered);
+        drop(answered);
+        let ignored = tokio::time::timeout(TIMEOUT, consumer.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        committer.processed(consumer.as_ref(), &ignored).unwrap();
+        drop(ignored);
+        assert_eq!(committed(), Some(Offset::Invalid));
+
+        tokio::time::timeout(TIMEOUT, publisher.next_report())
+            .await
+            .unwrap();
+        let settled = publisher.settled();
+        assert_eq!(settled.len(), 1);
+        assert_eq!(settled[0].request.offset, 0);
+        assert!(settled[0].result.is_err());
+        committer
+            .retry(consumer.as_ref(), &settled[0].request)
+            .unwrap();
+        assert_eq!(publisher.failed(), 1);
+
+        // Answered again, and committed once the reply is delivered
+        let again = tokio::time::timeout(TIMEOUT, consumer.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(again.offset(), 0);
+        assert_eq!(committed(), Some(Offset::Invalid));
+        publisher
+            .send_reply(
+                &again,
+                FutureRecord::<(), str>::to("product_reply").payload("reply"),
+            )
+            .await
+            .unwrap();
+        committer.defer(&again);
+        drop(again);
+        publisher.flush(TIMEOUT).await.unwrap();
+        for settled in publisher.settled() {
+            assert!(settled.result.is_ok());
+            committer
+                .resolve(consumer.as_ref(), &settled.request)
+                .unwrap();
+        }
+        assert_eq!(committed(), Some(Offset::Offset(1)));
+    }
+}
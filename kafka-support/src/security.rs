@@ -0,0 +1,720 @@
+//! TLS and SASL settings for the Kafka clients.
+//!
+//! These live in the `[security]` table of the config file, or come from
+//! environment variables and flags of the same name:
+//!
+//! | TOML                         | Environment                        | Flag                           |
+//! |------------------------------|------------------------------------|--------------------------------|
+//! | `protocol`                   | `KAFKA_SECURITY_PROTOCOL`          | `--security-protocol`          |
+//! | `sasl_mechanism`             | `KAFKA_SASL_MECHANISM`             | `--sasl-mechanism`             |
+//! | `sasl_username`              | `KAFKA_SASL_USERNAME`              | `--sasl-username`              |
+//! | `sasl_password_file`         | `KAFKA_SASL_PASSWORD_FILE`         | `--sasl-password-file`         |
+//! | `sasl_password_env`          | `KAFKA_SASL_PASSWORD`              |                                |
+//! | `ssl_ca_location`            | `KAFKA_SSL_CA_LOCATION`            | `--ssl-ca-location`            |
+//! | `ssl_certificate_location`   | `KAFKA_SSL_CERTIFICATE_LOCATION`   | `--ssl-certificate-location`   |
+//! | `ssl_key_location`           | `KAFKA_SSL_KEY_LOCATION`           | `--ssl-key-location`           |
+//! | `ssl_key_password_file`      | `KAFKA_SSL_KEY_PASSWORD_FILE`      | `--ssl-key-password-file`      |
+//! | `ssl_key_password_env`       | `KAFKA_SSL_KEY_PASSWORD`           |                                |
+//! | `ssl_keystore_location`      | `KAFKA_SSL_KEYSTORE_LOCATION`      | `--ssl-keystore-location`      |
+//! | `ssl_keystore_password_file` | `KAFKA_SSL_KEYSTORE_PASSWORD_FILE` | `--ssl-keystore-password-file` |
+//! | `ssl_keystore_password_env`  | `KAFKA_SSL_KEYSTORE_PASSWORD`      |                                |
+//!
+//! Passwords are never written in the config file or on the command line.
+//! They are read from a file, or from an environment variable: `*_env` in the
+//! config file names the variable to read.
+//!
+//! ```toml
+//! [security]
+//! protocol = "sasl_ssl"
+//! sasl_mechanism = "SCRAM-SHA-512"
+//! sasl_username = "product-catalogue"
+//! sasl_password_file = "/run/secrets/kafka-password"
+//! ssl_ca_location = "/etc/kafka/ca.pem"
+//! ```
+
+use crate::config::{invalid, ConfigError};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// The librdkafka properties owned by [`Security`], which may not be passed
+/// through.
+pub(crate) const MANAGED_PROPERTIES: [&str; 10] = [
+    "security.protocol",
+    "sasl.mechanism",
+    "sasl.username",
+    "sasl.password",
+    "ssl.ca.location",
+    "ssl.certificate.location",
+    "ssl.key.location",
+    "ssl.key.password",
+    "ssl.keystore.location",
+    "ssl.keystore.password",
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityProtocol {
+    #[default]
+    Plaintext,
+    Ssl,
+    SaslPlaintext,
+    SaslSsl,
+}
+
+impl SecurityProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SecurityProtocol::Plaintext => "plaintext",
+            SecurityProtocol::Ssl => "ssl",
+            SecurityProtocol::SaslPlaintext => "sasl_plaintext",
+            SecurityProtocol::SaslSsl => "sasl_ssl",
+        }
+    }
+
+    fn uses_sasl(&self) -> bool {
+        matches!(
+            self,
+            SecurityProtocol::SaslPlaintext | SecurityProtocol::SaslSsl
+        )
+    }
+
+    fn uses_ssl(&self) -> bool {
+        matches!(self, SecurityProtocol::Ssl | SecurityProtocol::SaslSsl)
+    }
+}
+
+impl FromStr for SecurityProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "plaintext" => Ok(SecurityProtocol::Plaintext),
+            "ssl" => Ok(SecurityProtocol::Ssl),
+            "sasl_plaintext" => Ok(SecurityProtocol::SaslPlaintext),
+            "sasl_ssl" => Ok(SecurityProtocol::SaslSsl),
+            _ => Err(format!(
+                "{:?} is not one of plaintext, ssl, sasl_plaintext, sasl_ssl",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum SaslMechanism {
+    #[serde(rename = "PLAIN")]
+    Plain,
+    #[serde(rename = "SCRAM-SHA-512")]
+    ScramSha512,
+}
+
+impl SaslMechanism {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SaslMechanism::Plain => "PLAIN",
+            SaslMechanism::ScramSha512 => "SCRAM-SHA-512",
+        }
+    }
+}
+
+impl FromStr for SaslMechanism {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "PLAIN" => Ok(SaslMechanism::Plain),
+            "SCRAM-SHA-512" => Ok(SaslMechanism::ScramSha512),
+            _ => Err(format!("{:?} is not one of PLAIN, SCRAM-SHA-512", s)),
+        }
+    }
+}
+
+/// A password or other credential. It prints as `[redacted]`, so it cannot
+/// leak into logs through `Debug` or `Display`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+/// Validated security settings, applied to every client a service creates.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Security {
+    protocol: SecurityProtocol,
+    sasl: Option<Sasl>,
+    ssl_ca_location: Option<PathBuf>,
+    ssl_certificate_location: Option<PathBuf>,
+    ssl_key_location: Option<PathBuf>,
+    ssl_key_password: Option<Secret>,
+    ssl_keystore_location: Option<PathBuf>,
+    ssl_keystore_password: Option<Secret>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Sasl {
+    mechanism: SaslMechanism,
+    username: String,
+    password: Secret,
+}
+
+/// A librdkafka property set by [`Security`].
+pub(crate) struct Property<'a> {
+    pub key: &'static str,
+    pub value: PropertyValue<'a>,
+}
+
+pub(crate) enum PropertyValue<'a> {
+    Plain(String),
+    Secret(&'a Secret),
+}
+
+impl Security {
+    pub fn protocol(&self) -> SecurityProtocol {
+        self.protocol
+    }
+
+    pub(crate) fn properties(&self) -> Vec<Property<'_>> {
+        let plain = |key, value: String| Property {
+            key,
+            value: PropertyValue::Plain(value),
+        };
+        let path = |key, path: &Option<PathBuf>| {
+            path.as_ref()
+                .map(|path| plain(key, path.display().to_string()))
+        };
+
+        let mut properties = vec![plain(
+            "security.protocol",
+            self.protocol.as_str().to_string(),
+        )];
+        if let Some(sasl) = &self.sasl {
+            properties.push(plain("sasl.mechanism", sasl.mechanism.as_str().to_string()));
+            properties.push(plain("sasl.username", sasl.username.clone()));
+            properties.extend(secret("sasl.password", Some(&sasl.password)));
+        }
+        properties.extend(path("ssl.ca.location", &self.ssl_ca_location));
+        properties.extend(path(
+            "ssl.certificate.location",
+            &self.ssl_certificate_location,
+        ));
+        properties.extend(path("ssl.key.location", &self.ssl_key_location));
+        properties.extend(secret("ssl.key.password", self.ssl_key_password.as_ref()));
+        properties.extend(path("ssl.keystore.location", &self.ssl_keystore_location));
+        properties.extend(secret(
+            "ssl.keystore.password",
+            self.ssl_keystore_password.as_ref(),
+        ));
+        properties
+    }
+}
+
+fn secret<'a>(key: &'static str, secret: Option<&'a Secret>) -> Option<Property<'a>> {
+    secret.map(|secret| Property {
+        key,
+        value: PropertyValue::Secret(secret),
+    })
+}
+
+/// Where a secret comes from: a file, or the named environment variable.
+/// Setting either in a layer replaces both from the layers below.
+#[derive(Debug, Default)]
+struct SecretSource {
+    file: Option<PathBuf>,
+    env: Option<String>,
+}
+
+impl SecretSource {
+    fn merge(self, over: SecretSource) -> SecretSource {
+        if over.file.is_some() || over.env.is_some() {
+            over
+        } else {
+            self
+        }
+    }
+
+    fn resolve(
+        self,
+        key: &str,
+        env: &HashMap<String, String>,
+    ) -> Result<Option<Secret>, ConfigError> {
+        match (self.file, self.env) {
+            (Some(_), Some(_)) => Err(invalid(
+                key,
+                "read from both a file and an environment variable, choose one",
+            )),
+            (Some(path), None) => {
+                let contents = std::fs::read_to_string(&path)
+                    .map_err(|source| ConfigError::Io { path, source })?;
+                Ok(Some(Secret(
+                    contents.trim_end_matches(['\r', '\n']).to_string(),
+                )))
+            }
+            (None, Some(name)) => match env.get(&name) {
+                Some(value) => Ok(Some(Secret(value.clone()))),
+                None => Err(invalid(key, format!("{} is not set", name))),
+            },
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// The security settings of one configuration layer.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SecurityLayer {
+    protocol: Option<SecurityProtocol>,
+    sasl_mechanism: Option<SaslMechanism>,
+    sasl_username: Option<String>,
+    sasl_password_file: Option<PathBuf>,
+    sasl_password_env: Option<String>,
+    ssl_ca_location: Option<PathBuf>,
+    ssl_certificate_location: Option<PathBuf>,
+    ssl_key_location: Option<PathBuf>,
+    ssl_key_password_file: Option<PathBuf>,
+    ssl_key_password_env: Option<String>,
+    ssl_keystore_location: Option<PathBuf>,
+    ssl_keystore_password_file: Option<PathBuf>,
+    ssl_keystore_password_env: Option<String>,
+}
+
+impl SecurityLayer {
+    pub(crate) fn merge(self, over: SecurityLayer) -> SecurityLayer {
+        let sasl_password = SecretSource {
+            file: self.sasl_password_file,
+            env: self.sasl_password_env,
+        }
+        .merge(SecretSource {
+            file: over.sasl_password_file,
+            env: over.sasl_password_env,
+        });
+        let ssl_key_password = SecretSource {
+            file: self.ssl_key_password_file,
+            env: self.ssl_key_password_env,
+        }
+        .merge(SecretSource {
+            file: over.ssl_key_password_file,
+            env: over.ssl_key_password_env,
+        });
+        let ssl_keystore_password = SecretSource {
+            file: self.ssl_keystore_password_file,
+            env: self.ssl_keystore_password_env,
+        }
+        .merge(SecretSource {
+            file: over.ssl_keystore_password_file,
+            env: over.ssl_keystore_password_env,
+        });
+
+        SecurityLayer {
+            protocol: over.protocol.or(self.protocol),
+            sasl_mechanism: over.sasl_mechanism.or(self.sasl_mechanism),
+            sasl_username: over.sasl_username.or(self.sasl_username),
+            sasl_password_file: sasl_password.file,
+            sasl_password_env: sasl_password.env,
+            ssl_ca_location: over.ssl_ca_location.or(self.ssl_ca_location),
+            ssl_certificate_location: over
+                .ssl_certificate_location
+                .or(self.ssl_certificate_location),
+            ssl_key_location: over.ssl_key_location.or(self.ssl_key_location),
+            ssl_key_password_file: ssl_key_password.file,
+            ssl_key_password_env: ssl_key_password.env,
+            ssl_keystore_location: over.ssl_keystore_location.or(self.ssl_keystore_location),
+            ssl_keystore_password_file: ssl_keystore_password.file,
+            ssl_keystore_password_env: ssl_keystore_password.env,
+        }
+    }
+
+    /// Takes the environment variable if it is a security setting. Password
+    /// variables are recorded by name and read when the settings are resolved.
+    pub(crate) fn set_from_env(&mut self, name: &str, value: &str) -> Result<bool, ConfigError> {
+        match name {
+            "KAFKA_SECURITY_PROTOCOL" => {
+                self.protocol = Some(value.parse().map_err(|e| invalid("security.protocol", e))?)
+            }
+            "KAFKA_SASL_MECHANISM" => {
+                self.sasl_mechanism = Some(
+                    value
+                        .parse()
+                        .map_err(|e| invalid("security.sasl_mechanism", e))?,
+                )
+            }
+            "KAFKA_SASL_USERNAME" => self.sasl_username = Some(value.to_string()),
+            "KAFKA_SASL_PASSWORD" => self.sasl_password_env = Some(name.to_string()),
+            "KAFKA_SASL_PASSWORD_FILE" => self.sasl_password_file = Some(value.into()),
+            "KAFKA_SSL_CA_LOCATION" => self.ssl_ca_location = Some(value.into()),
+            "KAFKA_SSL_CERTIFICATE_LOCATION" => self.ssl_certificate_location = Some(value.into()),
+            "KAFKA_SSL_KEY_LOCATION" => self.ssl_key_location = Some(value.into()),
+            "KAFKA_SSL_KEY_PASSWORD" => self.ssl_key_password_env = Some(name.to_string()),
+            "KAFKA_SSL_KEY_PASSWORD_FILE" => self.ssl_key_password_file = Some(value.into()),
+            "KAFKA_SSL_KEYSTORE_LOCATION" => self.ssl_keystore_location = Some(value.into()),
+            "KAFKA_SSL_KEYSTORE_PASSWORD" => {
+                self.ssl_keystore_password_env = Some(name.to_string())
+            }
+            "KAFKA_SSL_KEYSTORE_PASSWORD_FILE" => {
+                self.ssl_keystore_password_file = Some(value.into())
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Whether `flag` is a security flag taking a value.
+    pub(crate) fn takes_flag(flag: &str) -> bool {
+        matches!(
+            flag,
+            "--security-protocol"
+                | "--sasl-mechanism"
+                | "--sasl-username"
+                | "--sasl-password-file"
+                | "--ssl-ca-location"
+                | "--ssl-certificate-location"
+                | "--ssl-key-location"
+                | "--ssl-key-password-file"
+                | "--ssl-keystore-location"
+                | "--ssl-keystore-password-file"
+        )
+    }
+
+    pub(crate) fn set_from_flag(&mut self, flag: &str, value: String) -> Result<(), ConfigError> {
+        let usage = |e: String| ConfigError::Usage(format!("{}: {}", flag, e));
+        match flag {
+            "--security-protocol" => self.protocol = Some(value.parse().map_err(usage)?),
+            "--sasl-mechanism" => self.sasl_mechanism = Some(value.parse().map_err(usage)?),
+            "--sasl-username" => self.sasl_username = Some(value),
+            "--sasl-password-file" => {
+                self.sasl_password_file = Some(value.into());
+                self.sasl_password_env = None;
+            }
+            "--ssl-ca-location" => self.ssl_ca_location = Some(value.into()),
+            "--ssl-certificate-location" => self.ssl_certificate_location = Some(value.into()),
+            "--ssl-key-location" => self.ssl_key_location = Some(value.into()),
+            "--ssl-key-password-file" => {
+                self.ssl_key_password_file = Some(value.into());
+                self.ssl_key_password_env = None;
+            }
+            "--ssl-keystore-location" => self.ssl_keystore_location = Some(value.into()),
+            "--ssl-keystore-password-file" => {
+                self.ssl_keystore_password_file = Some(value.into());
+                self.ssl_keystore_password_env = None;
+            }
+            _ => unreachable!("{} is not a security flag", flag),
+        }
+        Ok(())
+    }
+
+    /// Checks the settings fit together and reads the secrets they refer to.
+    pub(crate) fn resolve(self, env: &HashMap<String, String>) -> Result<Security, ConfigError> {
+        let protocol = self.protocol.unwrap_or_default();
+
+        let sasl_password = SecretSource {
+            file: self.sasl_password_file,
+            env: self.sasl_password_env,
+        };
+        let sasl = if protocol.uses_sasl() {
+            let mechanism = self.sasl_mechanism.ok_or_else(|| {
+                invalid(
+                    "security.sasl_mechanism",
+                    format!("is required with {}", protocol.as_str()),
+                )
+            })?;
+            let username = self.sasl_username.ok_or_else(|| {
+                invalid(
+                    "security.sasl_username",
+                    format!("is required with {}", protocol.as_str()),
+                )
+            })?;
+            let password = sasl_password
+                .resolve("security.sasl_password", env)?
+                .ok_or_else(|| {
+                    invalid(
+                        "security.sasl_password",
+                        format!("is required with {}", protocol.as_str()),
+                    )
+                })?;
+            Some(Sasl {
+                mechanism,
+                username,
+                password,
+            })
+        } else {
+            if self.sasl_mechanism.is_some()
+                || self.sasl_username.is_some()
+                || sasl_password.file.is_some()
+                || sasl_password.env.is_some()
+            {
+                return Err(invalid(
+                    "security.protocol",
+                    format!(
+                        "SASL settings given, but {} does not use SASL",
+                        protocol.as_str()
+                    ),
+                ));
+            }
+            None
+        };
+
+        let ssl_key_password = SecretSource {
+            file: self.ssl_key_password_file,
+            env: self.ssl_key_password_env,
+        }
+        .resolve("security.ssl_key_password", env)?;
+        let ssl_keystore_password = SecretSource {
+            file: self.ssl_keystore_password_file,
+            env: self.ssl_keystore_password_env,
+        }
+        .resolve("security.ssl_keystore_password", env)?;
+
+        let security = Security {
+            protocol,
+            sasl,
+            ssl_ca_location: self.ssl_ca_location,
+            ssl_certificate_location: self.ssl_certificate_location,
+            ssl_key_location: self.ssl_key_location,
+            ssl_key_password,
+            ssl_keystore_location: self.ssl_keystore_location,
+            ssl_keystore_password,
+        };
+        security.validate_ssl()?;
+        Ok(security)
+    }
+}
+
+impl Security {
+    fn validate_ssl(&self) -> Result<(), ConfigError> {
+        let locations = [
+            ("security.ssl_ca_location", &self.ssl_ca_location),
+            (
+                "security.ssl_certificate_location",
+                &self.ssl_certificate_location,
+            ),
+            ("security.ssl_key_location", &self.ssl_key_location),
+            (
+                "security.ssl_keystore_location",
+                &self.ssl_keystore_location,
+            ),
+        ];
+        let ssl_given = locations.iter().any(|(_, location)| location.is_some())
+            || self.ssl_key_password.is_some()
+            || self.ssl_keystore_password.is_some();
+        if ssl_given && !self.protocol.uses_ssl() {
+            return Err(invalid(
+                "security.protocol",
+                format!(
+                    "SSL settings given, but {} does not use SSL",
+                    self.protocol.as_str()
+                ),
+            ));
+        }
+
+        for (key, location) in locations {
+            if let Some(path) = location {
+                if !path.is_file() {
+                    return Err(invalid(key, format!("{} is not a file", path.display())));
+                }
+            }
+        }
+
+        if self.ssl_certificate_location.is_some() != self.ssl_key_location.is_some() {
+            return Err(invalid(
+                "security.ssl_key_location",
+                "a client certificate and key must be given together",
+            ));
+        }
+        if self.ssl_keystore_location.is_some() && self.ssl_certificate_location.is_some() {
+            return Err(invalid(
+                "security.ssl_keystore_location",
+                "give either a keystore or a certificate and key, not both",
+            ));
+        }
+        if self.ssl_key_password.is_some() && self.ssl_key_location.is_none() {
+            return Err(invalid(
+                "security.ssl_key_password",
+                "given without ssl_key_location",
+            ));
+        }
+        if self.ssl_keystore_password.is_some() && self.ssl_keystore_location.is_none() {
+            return Err(invalid(
+                "security.ssl_keystore_password",
+                "given without ssl_keystore_location",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{ConfigError, Settings};
+    use rdkafka::consumer::StreamConsumer;
+    use rdkafka::producer::FutureProducer;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use tempfile::NamedTempFile;
+
+    fn load(vars: &[(&str, &str)], flags: &[&str]) -> Result<Settings, ConfigError> {
+        Settings::builder("127.0.0.1:8081").load_from(
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+            flags.iter().map(|flag| flag.to_string()),
+        )
+    }
+
+    fn secret_file(contents: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    /// A CA, and a client certificate it signed whose key is encrypted with
+    /// the password `k3y`.
+    fn testdata(name: &str) -> PathBuf {
+        [env!("CARGO_MANIFEST_DIR"), "testdata", name]
+            .iter()
+            .collect()
+    }
+
+    /// Creating a client fails if librdkafka was built without what the
+    /// settings ask for, such as OpenSSL for TLS and SCRAM.
+    fn create_clients(settings: &Settings) {
+        if let Err(e) = settings.producer_config().create::<FutureProducer>() {
+            panic!("producer creation failed: {}", e);
+        }
+        if let Err(e) = settings.consumer_config().create::<StreamConsumer>() {
+            panic!("consumer creation failed: {}", e);
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_scram_credentials_from_a_file_and_redacts_them() {
+        let password = secret_file("s3cr3t\n");
+        let settings = load(
+            &[
+                ("KAFKA_SECURITY_PROTOCOL", "sasl_ssl"),
+                ("KAFKA_SASL_MECHANISM", "SCRAM-SHA-512"),
+                ("KAFKA_SASL_USERNAME", "product-catalogue"),
+            ],
+            &["--sasl-password-file", password.path().to_str().unwrap()],
+        )
+        .unwrap();
+
+        let config = settings.client_config();
+        assert_eq!(config.get("security.protocol"), Some("sasl_ssl"));
+        assert_eq!(config.get("sasl.mechanism"), Some("SCRAM-SHA-512"));
+        assert_eq!(config.get("sasl.username"), Some("product-catalogue"));
+        assert_eq!(config.get("sasl.password"), Some("s3cr3t"));
+
+        let logged = format!("{} {:?}", settings, settings);
+        assert!(logged.contains("sasl.password=[redacted]"));
+        assert!(!logged.contains("s3cr3t"));
+
+        create_clients(&settings);
+    }
+
+    #[tokio::test]
+    async fn reads_plain_credentials_from_the_environment() {
+        let settings = load(
+            &[
+                ("KAFKA_SECURITY_PROTOCOL", "sasl_plaintext"),
+                ("KAFKA_SASL_MECHANISM", "PLAIN"),
+                ("KAFKA_SASL_USERNAME", "product-catalogue"),
+                ("KAFKA_SASL_PASSWORD", "s3cr3t"),
+            ],
+            &[],
+        )
+        .unwrap();
+
+        let config = settings.consumer_config();
+        assert_eq!(config.get("sasl.mechanism"), Some("PLAIN"));
+        assert_eq!(config.get("sasl.password"), Some("s3cr3t"));
+        assert!(!settings.to_string().contains("s3cr3t"));
+
+        create_clients(&settings);
+    }
+
+    #[tokio::test]
+    async fn applies_certificate_paths() {
+        let ca = testdata("ca.pem");
+        let certificate = testdata("client.pem");
+        let key = testdata("client.key");
+        let settings = load(
+            &[
+                ("KAFKA_SECURITY_PROTOCOL", "ssl"),
+                ("KAFKA_SSL_CA_LOCATION", ca.to_str().unwrap()),
+                (
+                    "KAFKA_SSL_CERTIFICATE_LOCATION",
+                    certificate.to_str().unwrap(),
+                ),
+                ("KAFKA_SSL_KEY_LOCATION", key.to_str().unwrap()),
+                ("KAFKA_SSL_KEY_PASSWORD", "k3y"),
+            ],
+            &[],
+        )
+        .unwrap();
+
+        let config = settings.client_config();
+        assert_eq!(config.get("ssl.ca.location"), ca.to_str());
+        assert_eq!(config.get("ssl.certificate.location"), certificate.to_str());
+        assert_eq!(config.get("ssl.key.location"), key.to_str());
+        assert_eq!(config.get("ssl.key.password"), Some("k3y"));
+        assert!(!settings.to_string().contains("k3y"));
+
+        create_clients(&settings);
+    }
+
+    #[test]
+    fn rejects_incomplete_or_conflicting_settings() {
+        let error =
+            |vars: &[(&str, &str)], flags: &[&str]| load(vars, flags).unwrap_err().to_string();
+
+        assert_eq!(
+            error(
+                &[
+                    ("KAFKA_SECURITY_PROTOCOL", "sasl_ssl"),
+                    ("KAFKA_SASL_MECHANISM", "PLAIN"),
+                    ("KAFKA_SASL_USERNAME", "product-catalogue"),
+                ],
+                &[]
+            ),
+            "invalid security.sasl_password: is required with sasl_ssl"
+        );
+        assert_eq!(
+            error(&[("KAFKA_SASL_USERNAME", "product-catalogue")], &[]),
+            "invalid security.protocol: SASL settings given, but plaintext does not use SASL"
+        );
+        assert_eq!(
+            error(&[("KAFKA_SASL_MECHANISM", "GSSAPI")], &[]),
+            r#"invalid security.sasl_mechanism: "GSSAPI" is not one of PLAIN, SCRAM-SHA-512"#
+        );
+        assert_eq!(
+            error(
+                &[
+                    ("KAFKA_SECURITY_PROTOCOL", "ssl"),
+                    ("KAFKA_SSL_CA_LOCATION", "/does/not/exist.pem"),
+                ],
+                &[]
+            ),
+            "invalid security.ssl_ca_location: /does/not/exist.pem is not a file"
+        );
+        assert_eq!(
+            error(&[], &["-X", "sasl.password=s3cr3t"]),
+            "invalid kafka.sasl.password: is set by the typed settings and cannot be passed through"
+        );
+    }
+}
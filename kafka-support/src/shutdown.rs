@@ -0,0 +1,163 @@
+//! Coordinated shutdown on SIGTERM and SIGINT.
+//!
+//! A service runs [`stop_on_signal`] to stop its HTTP server and call
+//! [`Shutdown::trigger`]. Kafka loops watch [`Shutdown::triggered`] between
+//! messages, so the message in hand is finished before they [`drain`]:
+//! commit offsets and flush producers within [`Settings::shutdown_timeout`].
+//! The process then exits with [`exit_code`].
+//!
+//! [`Settings::shutdown_timeout`]: crate::config::Settings::shutdown_timeout
+//!
+//! | Exit status | Meaning                                                     |
+//! |-------------|-------------------------------------------------------------|
+//! | 0           | drained cleanly                                             |
+//! | 1           | the HTTP server failed                                      |
+//! | 2           | invalid configuration                                       |
+//! | 3           | offsets could not be committed or the producer not flushed  |
+
+use crate::dead_letter::DeadLetterQueue;
+use rdkafka::consumer::{CommitMode, Consumer, ConsumerContext};
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use std::future::Future;
+use std::process::ExitCode;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// The status a service exits with when it could not drain cleanly.
+pub const EXIT_UNCLEAN_SHUTDOWN: u8 = 3;
+
+/// A shutdown flag shared between the tasks of a service.
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: std::sync::Arc<watch::Sender<bool>>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Shutdown {
+            sender: std::sync::Arc::new(sender),
+            receiver,
+        }
+    }
+
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.receiver.borrow()
+    }
+
+    /// Resolves once [`Shutdown::trigger`] has been called.
+    pub async fn triggered(&self) {
+        let mut receiver = self.receiver.clone();
+        // The sender lives as long as any clone of self, so this cannot fail.
+        let _ = receiver.wait_for(|triggered| *triggered).await;
+    }
+}
+
+/// Waits for SIGTERM or SIGINT and returns its name.
+#[cfg(unix)]
+pub async fn signal() -> std::io::Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => Ok("SIGTERM"),
+        _ = interrupt.recv() => Ok("SIGINT"),
+    }
+}
+
+/// Waits for Ctrl-C and returns its name.
+#[cfg(not(unix))]
+pub async fn signal() -> std::io::Result<&'static str> {
+    tokio::signal::ctrl_c().await?;
+    Ok("Ctrl-C")
+}
+
+/// Waits for [`signal`], then runs `stop`, which typically stops the HTTP
+/// server and triggers a [`Shutdown`]. Never runs it if the signals cannot
+/// be listened for.
+pub async fn stop_on_signal<F: Future<Output = ()>>(stop: F) {
+    match signal().await {
+        Ok(signal) => println!("Received {}, shutting down", signal),
+        Err(e) => {
+            eprintln!("Cannot listen for shutdown signals: {}", e);
+            return;
+        }
+    }
+    stop.await;
+}
+
+/// Synchronously commits the offsets the consumer has stored.
+///
+/// Having nothing to commit, because no message arrived since the last
+/// commit or the consumer was never assigned partitions, is not an error.
+pub fn commit<X: ConsumerContext, C: Consumer<X>>(consumer: &C) -> Result<(), KafkaError> {
+    match consumer.commit_consumer_state(CommitMode::Sync) {
+        Err(KafkaError::ConsumerCommit(RDKafkaErrorCode::NoOffset)) => Ok(()),
+        result => result,
+    }
+}
+
+/// Commits the offsets the consumer has stored and flushes the dead-letter
+/// producer within `deadline`. Returns whether both succeeded.
+pub fn drain<X: ConsumerContext, C: Consumer<X>>(
+    consumer: &C,
+    dead_letter_queue: &DeadLetterQueue,
+    deadline: Duration,
+) -> bool {
+    let mut clean = true;
+    if let Err(e) = commit(consumer) {
+        eprintln!("Error committing offsets on shutdown: {}", e);
+        clean = false;
+    }
+    if let Err(e) = dead_letter_queue.flush(deadline) {
+        eprintln!(
+            "Error flushing {} topic on shutdown: {}",
+            dead_letter_queue.topic(),
+            e
+        );
+        clean = false;
+    }
+    clean
+}
+
+/// The exit status for a shutdown that did or did not drain cleanly.
+pub fn exit_code(clean: bool) -> ExitCode {
+    if clean {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_UNCLEAN_SHUTDOWN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn clones_see_the_trigger() {
+        let shutdown = Shutdown::new();
+        let watcher = shutdown.clone();
+        let waiting = tokio::spawn(async move { watcher.triggered().await });
+
+        assert!(!shutdown.is_triggered());
+        shutdown.trigger();
+
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .expect("shutdown was not seen")
+            .unwrap();
+        assert!(shutdown.is_triggered());
+    }
+}
@@ -0,0 +1,367 @@
+//! Kafka transactions, so a group of records, and the offsets of the messages
+//! they answer, are published all or nothing.
+//!
+//! [`Transactions`] wraps a producer created from
+//! [`Settings::producer_config`] in [`ProducerMode::Transactional`]. Such a
+//! producer may only send inside a transaction, and only one transaction is
+//! open at a time, so [`Transactions::begin`] waits for the one before it to
+//! finish. Consumers see the records of a transaction once it commits, and
+//! never if it aborts, as long as they read with
+//! `isolation.level=read_committed`, librdkafka's default.
+//!
+//! Initialising, committing and aborting wait for the brokers, so they run on
+//! tokio's blocking thread pool. Callers abort explicitly when a step of a
+//! transaction fails, with [`Transaction::abort_with`]; a transaction dropped
+//! while open, say by a panic, is aborted in the background and holds up the
+//! next one until the brokers answer.
+//!
+//! [`Settings::producer_config`]: crate::config::Settings::producer_config
+//! [`ProducerMode::Transactional`]: crate::config::ProducerMode::Transactional
+
+use rdkafka::consumer::{Consumer, ConsumerContext};
+use rdkafka::error::{KafkaError, KafkaResult, RDKafkaErrorCode};
+use rdkafka::producer::{FutureProducer, Producer};
+use rdkafka::TopicPartitionList;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// How many times a commit that failed with a retriable error, typically a
+/// timeout while the brokers are busy, is tried before it is aborted.
+const COMMIT_ATTEMPTS: usize = 5;
+
+/// The transactions of one transactional producer.
+pub struct Transactions {
+    producer: FutureProducer,
+    turn: Arc<Mutex<()>>,
+    timeout: Duration,
+}
+
+impl Transactions {
+    /// Registers the producer's transactional id with the brokers, fencing off
+    /// older producers with the same id and aborting their open transaction.
+    /// Each call to the brokers waits up to `timeout`.
+    pub async fn init(producer: FutureProducer, timeout: Duration) -> KafkaResult<Self> {
+        let init = producer.clone();
+        blocking(move || init.init_transactions(timeout)).await?;
+        Ok(Transactions {
+            producer,
+            turn: Arc::new(Mutex::new(())),
+            timeout,
+        })
+    }
+
+    /// Begins a transaction once the open one, if any, has finished.
+    pub async fn begin(&self) -> KafkaResult<Transaction<'_>> {
+        let turn = self.turn.clone().lock_owned().await;
+        self.producer.begin_transaction()?;
+        Ok(Transaction {
+            producer: &self.producer,
+            timeout: self.timeout,
+            open: true,
+            turn: Some(turn),
+        })
+    }
+}
+
+/// An open transaction. It must be committed or aborted; dropping it aborts
+/// it on the blocking thread pool, and the next transaction waits for that.
+pub struct Transaction<'a> {
+    producer: &'a FutureProducer,
+    timeout: Duration,
+    open: bool,
+    turn: Option<OwnedMutexGuard<()>>,
+}
+
+impl Transaction<'_> {
+    /// The producer to send the transaction's records with. Their delivery
+    /// is confirmed by [`Transaction::commit`], so they need not be awaited.
+    pub fn producer(&self) -> &FutureProducer {
+        self.producer
+    }
+
+    /// Commits `offsets` for `consumer`'s group if, and only if, the
+    /// transaction commits.
+    pub async fn send_offsets<X: ConsumerContext, C: Consumer<X>>(
+        &self,
+        offsets: &TopicPartitionList,
+        consumer: &C,
+    ) -> KafkaResult<()> {
+        let group = consumer
+            .group_metadata()
+            .ok_or(KafkaError::ConsumerCommit(RDKafkaErrorCode::InvalidGroupId))?;
+        let producer = self.producer.clone();
+        let offsets = offsets.clone();
+        let timeout = self.timeout;
+        blocking(move || producer.send_offsets_to_transaction(&offsets, &group, timeout)).await
+    }
+
+    /// Delivers the transaction's records and commits them with its offsets.
+    /// A commit that fails with a retriable error is resumed, up to
+    /// [`COMMIT_ATTEMPTS`] tries. Otherwise, or once those run out, the
+    /// transaction is aborted, unless the error is fatal and the producer can
+    /// no longer be used. A caller that stops waiting does not stop the
+    /// commit, and the next transaction waits for it.
+    pub async fn commit(mut self) -> KafkaResult<()> {
+        self.open = false;
+        let producer = self.producer.clone();
+        let timeout = self.timeout;
+        let turn = self.turn.take();
+        blocking(move || {
+            let mut attempts = 1;
+            let result = loop {
+                match producer.commit_transaction(timeout) {
+                    Err(e) if is_retriable(&e) && attempts < COMMIT_ATTEMPTS => {
+                        eprintln!("Retrying transaction commit: {}", e);
+                        attempts += 1;
+                    }
+                    result => break result,
+                }
+            };
+            if let Err(e) = &result {
+                if !is_fatal(e) {
+                    if let Err(abort) = producer.abort_transaction(timeout) {
+                        eprintln!("Error aborting failed transaction: {}", abort);
+                    }
+                }
+            }
+            drop(turn);
+            result
+        })
+        .await
+    }
+
+    /// Discards the transaction's records and offsets. Like a commit, the
+    /// abort carries on if the caller stops waiting.
+    pub async fn abort(mut self) -> KafkaResult<()> {
+        self.open = false;
+        let producer = self.producer.clone();
+        let timeout = self.timeout;
+        let turn = self.turn.take();
+        blocking(move || {
+            let result = producer.abort_transaction(timeout);
+            drop(turn);
+            result
+        })
+        .await
+    }
+
+    /// Aborts the transaction because a step of it failed with `error`, and
+    /// returns that error. Failing to abort is only logged.
+    pub async fn abort_with(self, error: KafkaError) -> KafkaError {
+        if let Err(abort) = self.abort().await {
+            eprintln!("Error aborting transaction after {}: {}", error, abort);
+        }
+        error
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.open {
+            return;
+        }
+        let producer = self.producer.clone();
+        let timeout = self.timeout;
+        // Released once the abort is answered, so the next transaction waits
+        let turn = self.turn.take();
+        let abort = move || {
+            if let Err(e) = producer.abort_transaction(timeout) {
+                eprintln!("Error aborting abandoned transaction: {}", e);
+            }
+            drop(turn);
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(abort)),
+            Err(_) => abort(),
+        }
+    }
+}
+
+/// Whether `error` leaves the producer unusable, so the service has to
+/// create a new one, typically because a newer instance with the same
+/// transactional id fenced it off.
+pub fn is_fatal(error: &KafkaError) -> bool {
+    match error {
+        KafkaError::Transaction(e) => e.is_fatal(),
+        _ => false,
+    }
+}
+
+/// Whether the call that failed with `error` may be made again to resume
+/// what it started, rather than aborting the transaction.
+fn is_retriable(error: &KafkaError) -> bool {
+    match error {
+        KafkaError::Transaction(e) => e.is_retriable() && !e.txn_requires_abort() && !e.is_fatal(),
+        _ => false,
+    }
+}
+
+async fn blocking<F>(call: F) -> KafkaResult<()>
+where
+    F: FnOnce() -> KafkaResult<()> + Send + 'static,
+{
+    tokio::task::spawn_blocking(call)
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdkafka::consumer::StreamConsumer;
+    use rdkafka::mocking::MockCluster;
+    use rdkafka::producer::FutureRecord;
+    use rdkafka::types::{RDKafkaApiKey, RDKafkaRespErr};
+    use rdkafka::{ClientConfig, Message, Offset};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn transactions(bootstrap_servers: &str) -> Transactions {
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", bootstrap_servers)
+            .set("transactional.id", "products-1")
+            .create()
+            .unwrap();
+        Transactions::init(producer, TIMEOUT).await.unwrap()
+    }
+
+    fn consumer(bootstrap_servers: &str, group_id: &str) -> StreamConsumer {
+        ClientConfig::new()
+            .set("bootstrap.servers", bootstrap_servers)
+            .set("group.id", group_id)
+            .set("auto.offset.reset", "earliest")
+            .set("enable.auto.commit", "false")
+            .create()
+            .unwrap()
+    }
+
+    async fn send(transaction: &Transaction<'_>, payload: &str) {
+        transaction
+            .producer()
+            .send(
+                FutureRecord::<(), str>::to("products").payload(payload),
+                TIMEOUT,
+            )
+            .await
+            .unwrap();
+    }
+
+    // The mock cluster neither hides aborted records from consumers nor applies
+    // offsets sent to a transaction, so these tests cover the calls and their
+    // order, not the brokers' guarantees. tests/read_committed.rs checks those
+    // against a real broker.
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn commits_records_with_the_offsets_they_answer() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("requests", 1, 1).unwrap();
+        cluster.create_topic("products", 1, 1).unwrap();
+        let transactions = transactions(&cluster.bootstrap_servers()).await;
+        let requests = consumer(&cluster.bootstrap_servers(), "requests-group");
+        requests.subscribe(&["requests"]).unwrap();
+        let mut offsets = TopicPartitionList::new();
+        offsets
+            .add_partition_offset("requests", 0, Offset::Offset(1))
+            .unwrap();
+
+        let aborted = transactions.begin().await.unwrap();
+        send(&aborted, "aborted").await;
+        aborted.abort().await.unwrap();
+
+        let transaction = transactions.begin().await.unwrap();
+        send(&transaction, "reply").await;
+        transaction.send_offsets(&offsets, &requests).await.unwrap();
+        transaction.commit().await.unwrap();
+
+        let products = consumer(&cluster.bootstrap_servers(), "products-group");
+        products.subscribe(&["products"]).unwrap();
+        let mut payloads = Vec::new();
+        for _ in 0..2 {
+            let message = tokio::time::timeout(TIMEOUT, products.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            payloads.push(message.payload_view::<str>().unwrap().unwrap().to_string());
+        }
+        assert_eq!(payloads.last().map(String::as_str), Some("reply"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn transactions_take_turns() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("products", 1, 1).unwrap();
+        let transactions = std::sync::Arc::new(transactions(&cluster.bootstrap_servers()).await);
+
+        let first = transactions.begin().await.unwrap();
+        let second = tokio::spawn({
+            let transactions = transactions.clone();
+            async move {
+                let transaction = transactions.begin().await.unwrap();
+                send(&transaction, "second").await;
+                transaction.commit().await.unwrap();
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!second.is_finished());
+
+        send(&first, "first").await;
+        first.commit().await.unwrap();
+        tokio::time::timeout(TIMEOUT, second)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dropped_transactions_are_aborted_before_the_next_begins() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("products", 1, 1).unwrap();
+        let transactions = transactions(&cluster.bootstrap_servers()).await;
+
+        let abandoned = transactions.begin().await.unwrap();
+        send(&abandoned, "abandoned").await;
+        drop(abandoned);
+
+        let transaction = transactions.begin().await.unwrap();
+        send(&transaction, "reply").await;
+        transaction.commit().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn commits_are_resumed_after_retriable_errors_and_aborted_after_others() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("products", 1, 1).unwrap();
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .set("transactional.id", "products-1")
+            .create()
+            .unwrap();
+        let transactions = Transactions::init(producer, Duration::from_millis(500))
+            .await
+            .unwrap();
+
+        // The coordinator is still loading for longer than one attempt waits
+        let resumed = transactions.begin().await.unwrap();
+        send(&resumed, "resumed").await;
+        cluster.request_errors(
+            RDKafkaApiKey::EndTxn,
+            &[RDKafkaRespErr::RD_KAFKA_RESP_ERR_COORDINATOR_LOAD_IN_PROGRESS; 4],
+        );
+        resumed.commit().await.unwrap();
+
+        // A request that timed out on the broker leaves the transaction to abort
+        let aborted = transactions.begin().await.unwrap();
+        send(&aborted, "aborted").await;
+        cluster.request_errors(
+            RDKafkaApiKey::EndTxn,
+            &[RDKafkaRespErr::RD_KAFKA_RESP_ERR_REQUEST_TIMED_OUT],
+        );
+        let error = aborted.commit().await.unwrap_err();
+        assert!(!is_retriable(&error) && !is_fatal(&error), "{}", error);
+
+        let transaction = transactions.begin().await.unwrap();
+        send(&transaction, "reply").await;
+        transaction.commit().await.unwrap();
+    }
+}
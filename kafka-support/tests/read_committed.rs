@@ -0,0 +1,76 @@
+//! Checks against a real broker what the mock cluster cannot: consumers reading
+//! with `isolation.level=read_committed` never see an aborted transaction's
+//! records. Start the broker with `make docker` and run
+//! `cargo test -p kafka-support --test read_committed -- --ignored`, or set
+//! `KAFKA_BOOTSTRAP_SERVERS` to test another one.
+
+use kafka_support::transaction::Transactions;
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::{ClientConfig, Message};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn bootstrap_servers() -> String {
+    std::env::var("KAFKA_BOOTSTRAP_SERVERS").unwrap_or_else(|_| "localhost:9092".to_string())
+}
+
+fn consumer(isolation_level: &str) -> StreamConsumer {
+    ClientConfig::new()
+        .set("bootstrap.servers", bootstrap_servers())
+        .set("group.id", uuid::Uuid::new_v4().to_string())
+        .set("auto.offset.reset", "earliest")
+        .set("enable.auto.commit", "false")
+        .set("isolation.level", isolation_level)
+        .create()
+        .unwrap()
+}
+
+async fn first_payload(consumer: &StreamConsumer, topic: &str) -> String {
+    consumer.subscribe(&[topic]).unwrap();
+    let message = tokio::time::timeout(TIMEOUT, consumer.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    message.payload_view::<str>().unwrap().unwrap().to_string()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "needs a Kafka broker, see `make docker`"]
+async fn aborted_records_are_not_read_committed() {
+    let topic = format!("transactions-{}", uuid::Uuid::new_v4());
+    let producer: FutureProducer = ClientConfig::new()
+        .set("bootstrap.servers", bootstrap_servers())
+        .set("transactional.id", &topic)
+        .create()
+        .unwrap();
+    let transactions = Transactions::init(producer, TIMEOUT).await.unwrap();
+
+    for payload in ["aborted", "committed"] {
+        let transaction = transactions.begin().await.unwrap();
+        transaction
+            .producer()
+            .send(
+                FutureRecord::<(), str>::to(&topic).payload(payload),
+                TIMEOUT,
+            )
+            .await
+            .unwrap();
+        if payload == "aborted" {
+            transaction.abort().await.unwrap();
+        } else {
+            transaction.commit().await.unwrap();
+        }
+    }
+
+    // The aborted record is in the log, only hidden from read_committed
+    assert_eq!(
+        first_payload(&consumer("read_uncommitted"), &topic).await,
+        "aborted"
+    );
+    assert_eq!(
+        first_payload(&consumer("read_committed"), &topic).await,
+        "committed"
+    );
+}
@@ -0,0 +1,112 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// The kind of change a [`ProductEvent`](crate::ProductEvent) describes.
+///
+/// Parsing is strict: only the exact upper case names used on the wire are
+/// accepted.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum EventType {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl EventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventType::Created => "CREATED",
+            EventType::Updated => "UPDATED",
+            EventType::Deleted => "DELETED",
+        }
+    }
+}
+
+impl fmt::Display for EventType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for EventType {
+    type Err = UnknownEventType;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "CREATED" => Ok(EventType::Created),
+            "UPDATED" => Ok(EventType::Updated),
+            "DELETED" => Ok(EventType::Deleted),
+            other => Err(UnknownEventType(other.to_string())),
+        }
+    }
+}
+
+/// An `event` value that does not name any [`EventType`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownEventType(pub String);
+
+impl fmt::Display for UnknownEventType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown event type {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownEventType {}
+
+/// What a consumer does with an event whose type it does not recognise.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnknownEventPolicy {
+    /// Treat the event as an error.
+    #[default]
+    Reject,
+    /// Forward the event untouched to the dead-letter topic.
+    DeadLetter,
+    /// Skip the event.
+    Ignore,
+}
+
+impl FromStr for UnknownEventPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(UnknownEventPolicy::Reject),
+            "dead-letter" => Ok(UnknownEventPolicy::DeadLetter),
+            "ignore" => Ok(UnknownEventPolicy::Ignore),
+            other => Err(format!(
+                "unknown event policy {:?}, expected one of reject, dead-letter or ignore",
+                other
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_only_the_exact_wire_names() {
+        for event_type in [EventType::Created, EventType::Updated, EventType::Deleted] {
+            assert_eq!(event_type.to_string().parse(), Ok(event_type));
+        }
+        assert_eq!(
+            "updated".parse::<EventType>(),
+            Err(UnknownEventType("updated".to_string()))
+        );
+        assert_eq!(
+            "UPDATE".parse::<EventType>(),
+            Err(UnknownEventType("UPDATE".to_string()))
+        );
+    }
+
+    #[test]
+    fn parses_unknown_event_policies() {
+        assert_eq!("reject".parse(), Ok(UnknownEventPolicy::Reject));
+        assert_eq!("dead-letter".parse(), Ok(UnknownEventPolicy::DeadLetter));
+        assert_eq!("ignore".parse(), Ok(UnknownEventPolicy::Ignore));
+        assert!("drop".parse::<UnknownEventPolicy>().is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! Product domain types shared by the Rust Kafka consumers and providers.
+//!
+//! Both sides of the contract serialize these types, so a change to the message
+//! shape only has to be made here.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+mod event_type;
+mod metadata;
+mod process;
+mod reply;
+mod store;
+mod version;
+
+pub use event_type::{EventType, UnknownEventPolicy, UnknownEventType};
+pub use metadata::EventMetadata;
+pub use process::{ProcessError, ProcessOutcome};
+pub use reply::{ErrorCode, ErrorEnvelope, ProductReply};
+pub use store::ProductStore;
+pub use version::{InvalidVersion, ProductVersion, VersionOverflow};
+
+/// A product as accepted by the provider APIs and served by the consumer APIs.
+///
+/// `id` and `version` are only absent on requests for products that have not
+/// been created yet.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Product {
+    pub id: Option<String>,
+    pub name: String,
+    pub r#type: String,
+    pub version: Option<ProductVersion>,
+}
+
+/// The message published to Kafka whenever a product changes.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ProductEvent {
+    pub id: String,
+    pub name: String,
+    pub r#type: String,
+    pub version: ProductVersion,
+    pub event: EventType,
+}
+
+/// Why a payload could not be decoded into a [`ProductEvent`].
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The payload is not a well formed product event.
+    Json(serde_json::Error),
+    /// The payload is well formed, but its `event` names no known [`EventType`].
+    UnknownEventType(UnknownEventType),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Json(e) => write!(f, "malformed product event: {}", e),
+            DecodeError::UnknownEventType(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::Json(e) => Some(e),
+            DecodeError::UnknownEventType(e) => Some(e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for DecodeError {
+    fn from(e: serde_json::Error) -> Self {
+        DecodeError::Json(e)
+    }
+}
+
+impl ProductEvent {
+    /// Decodes a Kafka payload, telling a malformed message apart from one
+    /// that only carries an event type this version does not know about.
+    pub fn from_slice(payload: &[u8]) -> Result<ProductEvent, DecodeError> {
+        #[derive(Deserialize)]
+        struct RawProductEvent {
+            id: String,
+            name: String,
+            r#type: String,
+            version: ProductVersion,
+            event: String,
+        }
+
+        let raw: RawProductEvent = serde_json::from_slice(payload)?;
+        let event = raw.event.parse().map_err(DecodeError::UnknownEventType)?;
+        Ok(ProductEvent {
+            id: raw.id,
+            name: raw.name,
+            r#type: raw.r#type,
+            version: raw.version,
+            event,
+        })
+    }
+}
+
+/// The id in a request body disagrees with the id in its path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IdMismatch {
+    pub path: String,
+    pub body: String,
+}
+
+impl fmt::Display for IdMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the body is for product {} but the path is for product {}",
+            self.body, self.path
+        )
+    }
+}
+
+impl std::error::Error for IdMismatch {}
+
+impl Product {
+    /// Builds the event for `event_type`, assigning an id to new products and
+    /// bumping the version. A delete that names no version deletes whatever
+    /// version is stored, with [`ProductVersion::LAST`].
+    pub fn into_event(self, event_type: EventType) -> Result<ProductEvent, VersionOverflow> {
+        let version = match (self.version, event_type) {
+            (Some(version), _) => version.next()?,
+            (None, EventType::Deleted) => ProductVersion::LAST,
+            (None, _) => ProductVersion::FIRST,
+        };
+        Ok(ProductEvent {
+            id: self.id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            name: self.name,
+            r#type: self.r#type,
+            version,
+            event: event_type,
+        })
+    }
+
+    /// Gives the product the id from a request path, which is authoritative.
+    /// A body may leave its id out, but must not name a different product.
+    pub fn with_id(mut self, id: &str) -> Result<Product, IdMismatch> {
+        match self.id {
+            Some(body) if body != id => Err(IdMismatch {
+                path: id.to_string(),
+                body,
+            }),
+            _ => {
+                self.id = Some(id.to_string());
+                Ok(self)
+            }
+        }
+    }
+
+    /// A product known only by its id and, if the request named one, its
+    /// version, as named by a request without a body. Its name and type are
+    /// empty, and so are those of the event built from it: consumers find
+    /// the product to delete by id and version alone.
+    pub fn reference(id: &str, version: Option<ProductVersion>) -> Product {
+        Product {
+            id: Some(id.to_string()),
+            name: String::new(),
+            r#type: String::new(),
+            version,
+        }
+    }
+}
+
+impl From<ProductEvent> for Product {
+    fn from(event: ProductEvent) -> Self {
+        Product {
+            id: Some(event.id),
+            name: event.name,
+            r#type: event.r#type,
+            version: Some(event.version),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn product(id: Option<&str>, version: Option<&str>) -> Product {
+        Product {
+            id: id.map(str::to_string),
+            name: "Some Product".to_string(),
+            r#type: "Product Range".to_string(),
+            version: version.map(|v| v.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn event_type_uses_upper_case_names_on_the_wire() {
+        assert_eq!(json!(EventType::Created), json!("CREATED"));
+        assert_eq!(json!(EventType::Updated), json!("UPDATED"));
+        assert_eq!(json!(EventType::Deleted), json!("DELETED"));
+    }
+
+    #[test]
+    fn from_slice_separates_unknown_event_types_from_malformed_payloads() {
+        let event = |event: &str| {
+            json!({
+                "id": "some-uuid-1234-5678",
+                "name": "Some Product",
+                "type": "Product Range",
+                "version": "v1",
+                "event": event
+            })
+            .to_string()
+        };
+
+        let decoded = ProductEvent::from_slice(event("UPDATED").as_bytes()).unwrap();
+        assert_eq!(decoded.event, EventType::Updated);
+
+        match ProductEvent::from_slice(event("UPDATD").as_bytes()) {
+            Err(DecodeError::UnknownEventType(e)) => assert_eq!(e.0, "UPDATD"),
+            other => panic!("expected an unknown event type, got {:?}", other),
+        }
+        assert!(matches!(
+            ProductEvent::from_slice(b"{\"id\": 1}"),
+            Err(DecodeError::Json(_))
+        ));
+
+        let mut invalid_version: serde_json::Value =
+            serde_json::from_str(&event("UPDATED")).unwrap();
+        invalid_version["version"] = json!("v1.2");
+        assert!(matches!(
+            ProductEvent::from_slice(invalid_version.to_string().as_bytes()),
+            Err(DecodeError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn path_ids_are_authoritative() {
+        let product = product(None, Some("v1"))
+            .with_id("some-uuid-1234-5678")
+            .unwrap();
+        assert_eq!(product.id.as_deref(), Some("some-uuid-1234-5678"));
+        assert!(product.clone().with_id("some-uuid-1234-5678").is_ok());
+
+        let mismatch = product.with_id("other-uuid").unwrap_err();
+        assert_eq!(mismatch.body, "some-uuid-1234-5678");
+        assert_eq!(mismatch.path, "other-uuid");
+    }
+
+    #[test]
+    fn into_event_bumps_the_version_of_existing_products() {
+        let event = product(Some("some-uuid-1234-5678"), Some("v1"))
+            .into_event(EventType::Updated)
+            .unwrap();
+        assert_eq!(
+            json!(event),
+            json!({
+                "id": "some-uuid-1234-5678",
+                "name": "Some Product",
+                "type": "Product Range",
+                "version": "v2",
+                "event": "UPDATED"
+            })
+        );
+    }
+
+    #[test]
+    fn into_event_assigns_an_id_and_first_version_to_new_products() {
+        let event = product(None, None).into_event(EventType::Created).unwrap();
+        assert!(uuid::Uuid::parse_str(&event.id).is_ok());
+        assert_eq!(event.version, ProductVersion::FIRST);
+    }
+
+    #[test]
+    fn deletes_without_a_version_supersede_every_version() {
+        let event = Product::reference("some-uuid-1234-5678", None)
+            .into_event(EventType::Deleted)
+            .unwrap();
+        assert_eq!(event.version, ProductVersion::LAST);
+        assert_eq!(event.name, "");
+        assert_eq!(event.r#type, "");
+    }
+
+    #[test]
+    fn into_event_refuses_to_wrap_the_version_around() {
+        let mut product = product(Some("some-uuid-1234-5678"), None);
+        product.version = Some(ProductVersion::new(u32::MAX));
+        assert!(product.into_event(EventType::Updated).is_err());
+    }
+
+    #[test]
+    fn product_from_event_keeps_id_and_version() {
+        let event = product(Some("some-uuid-1234-5678"), Some("v1"))
+            .into_event(EventType::Created)
+            .unwrap();
+        let product = Product::from(event);
+        assert_eq!(product.id.as_deref(), Some("some-uuid-1234-5678"));
+        assert_eq!(product.version, Some(ProductVersion::new(2)));
+    }
+}
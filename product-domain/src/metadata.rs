@@ -0,0 +1,130 @@
+use crate::{ProcessError, ProductEvent};
+use chrono::{SecondsFormat, Utc};
+
+/// The Kafka headers describing a product event, which Pact contracts capture
+/// as message metadata.
+///
+/// Producers set every field. Consumers read whatever headers arrived, so a
+/// field is `None` for events from producers that do not set it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EventMetadata {
+    pub content_type: Option<String>,
+    pub event_type: Option<String>,
+    pub schema_version: Option<String>,
+    pub producer_id: Option<String>,
+    pub event_id: Option<String>,
+    /// When the event was produced, as an ISO-8601 UTC timestamp.
+    pub produced_at: Option<String>,
+}
+
+impl EventMetadata {
+    pub const CONTENT_TYPE: &'static str = "content-type";
+    pub const EVENT_TYPE: &'static str = "event-type";
+    pub const SCHEMA_VERSION: &'static str = "schema-version";
+    pub const PRODUCER_ID: &'static str = "producer-id";
+    pub const EVENT_ID: &'static str = "event-id";
+    pub const PRODUCED_AT: &'static str = "produced-at";
+
+    /// The version of the [`ProductEvent`] payload schema this crate reads
+    /// and writes.
+    pub const CURRENT_SCHEMA_VERSION: &'static str = "1";
+
+    /// The metadata of `event`, produced now by `producer_id`.
+    pub fn new(event: &ProductEvent, producer_id: &str) -> Self {
+        EventMetadata {
+            content_type: Some("application/json".to_string()),
+            event_type: Some(event.event.to_string()),
+            schema_version: Some(Self::CURRENT_SCHEMA_VERSION.to_string()),
+            producer_id: Some(producer_id.to_string()),
+            event_id: Some(uuid::Uuid::new_v4().to_string()),
+            produced_at: Some(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+        }
+    }
+
+    /// Fails for events in a payload schema this crate cannot read. Events
+    /// without a `schema-version` header predate it and use the current one.
+    pub fn check_schema(&self) -> Result<(), ProcessError> {
+        match self.schema_version.as_deref() {
+            None | Some(Self::CURRENT_SCHEMA_VERSION) => Ok(()),
+            Some(version) => Err(ProcessError::UnsupportedSchema {
+                version: version.to_string(),
+            }),
+        }
+    }
+
+    /// Reads the metadata from message headers, ignoring headers it does not
+    /// know and values that are not UTF-8.
+    pub fn from_headers<'a, I>(headers: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a str, &'a [u8])>,
+    {
+        let mut metadata = EventMetadata::default();
+        for (name, value) in headers {
+            let field = match name {
+                Self::CONTENT_TYPE => &mut metadata.content_type,
+                Self::EVENT_TYPE => &mut metadata.event_type,
+                Self::SCHEMA_VERSION => &mut metadata.schema_version,
+                Self::PRODUCER_ID => &mut metadata.producer_id,
+                Self::EVENT_ID => &mut metadata.event_id,
+                Self::PRODUCED_AT => &mut metadata.produced_at,
+                _ => continue,
+            };
+            if let Ok(value) = std::str::from_utf8(value) {
+                *field = Some(value.to_string());
+            }
+        }
+        metadata
+    }
+
+    /// The headers to send, by name.
+    pub fn headers(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            (Self::CONTENT_TYPE, &self.content_type),
+            (Self::EVENT_TYPE, &self.event_type),
+            (Self::SCHEMA_VERSION, &self.schema_version),
+            (Self::PRODUCER_ID, &self.producer_id),
+            (Self::EVENT_ID, &self.event_id),
+            (Self::PRODUCED_AT, &self.produced_at),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_deref().map(|value| (name, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EventType, Product};
+
+    #[test]
+    fn round_trips_through_headers() {
+        let event = Product::reference("some-uuid-1234-5678", Some("v1".parse().unwrap()))
+            .into_event(EventType::Updated)
+            .unwrap();
+        let metadata = EventMetadata::new(&event, "provider-rust-kafka-async");
+        assert_eq!(metadata.event_type.as_deref(), Some("UPDATED"));
+        assert!(metadata.produced_at.as_deref().unwrap().ends_with('Z'));
+
+        let headers: Vec<(&str, &[u8])> = metadata
+            .headers()
+            .map(|(name, value)| (name, value.as_bytes()))
+            .chain([("correlation_id", &b"1234"[..])])
+            .collect();
+        assert_eq!(headers.len(), 7);
+        assert_eq!(EventMetadata::from_headers(headers), metadata);
+    }
+
+    #[test]
+    fn only_the_current_schema_is_supported() {
+        let schema = |version: Option<&str>| EventMetadata {
+            schema_version: version.map(str::to_string),
+            ..EventMetadata::default()
+        };
+        assert!(schema(None).check_schema().is_ok());
+        assert!(schema(Some("1")).check_schema().is_ok());
+        assert!(matches!(
+            schema(Some("2")).check_schema(),
+            Err(ProcessError::UnsupportedSchema { version }) if version == "2"
+        ));
+    }
+}
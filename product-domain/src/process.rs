@@ -0,0 +1,112 @@
+use crate::{DecodeError, ProductVersion, UnknownEventPolicy, UnknownEventType};
+use std::fmt;
+
+/// What a consumer did with an event it was able to handle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessOutcome {
+    /// The event was applied to the product store.
+    Applied,
+    /// The event had an unknown type and the consumer is configured to skip those.
+    Ignored,
+    /// The event carries the version already stored, so it was redelivered.
+    Duplicate,
+}
+
+/// Why a consumer could not handle an event.
+#[derive(Debug)]
+pub enum ProcessError {
+    /// The payload is not a well formed product event.
+    Decode(serde_json::Error),
+    /// The event type is not one this consumer understands.
+    UnknownEventType(UnknownEventType),
+    /// The event is older than the version of the product already stored.
+    StaleVersion {
+        id: String,
+        stored: ProductVersion,
+        received: ProductVersion,
+    },
+    /// The event updates or deletes a product that has already been deleted.
+    NotFound { id: String },
+    /// The event's `schema-version` header names a payload schema this
+    /// consumer cannot read.
+    UnsupportedSchema { version: String },
+}
+
+impl ProcessError {
+    /// Whether the event can never be handled and belongs on the dead-letter
+    /// topic. Stale events are expected under redelivery and are not poison,
+    /// and neither are changes to deleted products.
+    pub fn is_poison(&self, unknown_event_policy: UnknownEventPolicy) -> bool {
+        match self {
+            ProcessError::Decode(_) | ProcessError::UnsupportedSchema { .. } => true,
+            ProcessError::UnknownEventType(_) => {
+                unknown_event_policy == UnknownEventPolicy::DeadLetter
+            }
+            ProcessError::StaleVersion { .. } | ProcessError::NotFound { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessError::Decode(e) => write!(f, "malformed product event: {}", e),
+            ProcessError::UnknownEventType(e) => e.fmt(f),
+            ProcessError::StaleVersion {
+                id,
+                stored,
+                received,
+            } => write!(
+                f,
+                "stale event for product {}: received {} but {} is already stored",
+                id, received, stored
+            ),
+            ProcessError::NotFound { id } => write!(f, "product {} has been deleted", id),
+            ProcessError::UnsupportedSchema { version } => {
+                write!(f, "unsupported product event schema version {}", version)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProcessError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProcessError::Decode(e) => Some(e),
+            ProcessError::UnknownEventType(e) => Some(e),
+            ProcessError::StaleVersion { .. }
+            | ProcessError::NotFound { .. }
+            | ProcessError::UnsupportedSchema { .. } => None,
+        }
+    }
+}
+
+impl From<DecodeError> for ProcessError {
+    fn from(e: DecodeError) -> Self {
+        match e {
+            DecodeError::Json(e) => ProcessError::Decode(e),
+            DecodeError::UnknownEventType(e) => ProcessError::UnknownEventType(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_undecodable_and_dead_lettered_unknown_events_are_poison() {
+        let decode = ProcessError::Decode(serde_json::from_slice::<()>(b"{").unwrap_err());
+        let unknown = ProcessError::UnknownEventType(UnknownEventType("ARCHIVED".to_string()));
+        let stale = ProcessError::StaleVersion {
+            id: "some-uuid-1234-5678".to_string(),
+            stored: ProductVersion::new(2),
+            received: ProductVersion::new(1),
+        };
+
+        assert!(decode.is_poison(UnknownEventPolicy::Reject));
+        assert!(!unknown.is_poison(UnknownEventPolicy::Reject));
+        assert!(unknown.is_poison(UnknownEventPolicy::DeadLetter));
+        assert!(!stale.is_poison(UnknownEventPolicy::DeadLetter));
+    }
+}
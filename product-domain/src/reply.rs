@@ -0,0 +1,121 @@
+use crate::{ProcessError, Product};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// What a sync consumer replies to a product request with.
+///
+/// A successful reply is the product itself, so it keeps the shape replies
+/// had before errors were reported. A failed one is an envelope:
+///
+/// ```json
+/// {"error": {"code": "STALE_VERSION", "message": "..."}}
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum ProductReply {
+    Product(Product),
+    Error { error: ErrorEnvelope },
+}
+
+impl From<Result<Product, &ProcessError>> for ProductReply {
+    fn from(result: Result<Product, &ProcessError>) -> Self {
+        match result {
+            Ok(product) => ProductReply::Product(product),
+            Err(e) => ProductReply::Error {
+                error: ErrorEnvelope::from(e),
+            },
+        }
+    }
+}
+
+/// Why a request could not be applied.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ErrorEnvelope {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl From<&ProcessError> for ErrorEnvelope {
+    fn from(e: &ProcessError) -> Self {
+        let code = match e {
+            ProcessError::Decode(_) | ProcessError::UnsupportedSchema { .. } => {
+                ErrorCode::MalformedEvent
+            }
+            ProcessError::UnknownEventType(_) => ErrorCode::UnknownEventType,
+            ProcessError::StaleVersion { .. } => ErrorCode::StaleVersion,
+            ProcessError::NotFound { .. } => ErrorCode::NotFound,
+        };
+        ErrorEnvelope {
+            code,
+            message: e.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ErrorEnvelope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code.as_str(), self.message)
+    }
+}
+
+/// The machine readable part of an [`ErrorEnvelope`].
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    /// The product has been deleted.
+    NotFound,
+    /// The request is older than the version of the product already stored.
+    StaleVersion,
+    /// The request's event type is not one the consumer understands.
+    UnknownEventType,
+    /// The request is not a well formed product event.
+    MalformedEvent,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::NotFound => "NOT_FOUND",
+            ErrorCode::StaleVersion => "STALE_VERSION",
+            ErrorCode::UnknownEventType => "UNKNOWN_EVENT_TYPE",
+            ErrorCode::MalformedEvent => "MALFORMED_EVENT",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProductVersion;
+
+    #[test]
+    fn products_keep_their_shape_and_errors_are_enveloped() {
+        let product = Product {
+            id: Some("some-uuid-1234-5678".to_string()),
+            name: "Some Product".to_string(),
+            r#type: "Product Range".to_string(),
+            version: Some(ProductVersion::FIRST),
+        };
+        let reply = ProductReply::from(Ok(product.clone()));
+        assert_eq!(
+            serde_json::to_value(&reply).unwrap(),
+            serde_json::to_value(&product).unwrap()
+        );
+
+        let stale = ProcessError::StaleVersion {
+            id: "some-uuid-1234-5678".to_string(),
+            stored: ProductVersion::new(2),
+            received: ProductVersion::new(1),
+        };
+        let reply = ProductReply::from(Err(&stale));
+        let json = serde_json::to_value(&reply).unwrap();
+        assert_eq!(json["error"]["code"], "STALE_VERSION");
+        assert_eq!(json["error"]["message"], stale.to_string());
+
+        for reply in [ProductReply::Product(product), reply] {
+            let decoded: ProductReply =
+                serde_json::from_slice(&serde_json::to_vec(&reply).unwrap()).unwrap();
+            assert_eq!(decoded, reply);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use crate::{EventType, ProcessError, ProcessOutcome, Product, ProductEvent, ProductVersion};
+use std::collections::HashMap;
+
+enum Entry {
+    Live {
+        product: Product,
+        version: ProductVersion,
+    },
+    Deleted {
+        version: ProductVersion,
+    },
+}
+
+impl Entry {
+    fn version(&self) -> ProductVersion {
+        match self {
+            Entry::Live { version, .. } | Entry::Deleted { version } => *version,
+        }
+    }
+}
+
+/// The consumer's view of the product catalogue.
+///
+/// Events are applied in version order per product: redelivered events are
+/// reported as duplicates and older ones as stale, so neither can regress
+/// state. Deleted products leave a tombstone behind, which stops a late
+/// `CREATED` from bringing them back. Only a newer `CREATED` can; updating or
+/// deleting them again is an error.
+#[derive(Default)]
+pub struct ProductStore {
+    entries: HashMap<String, Entry>,
+}
+
+impl ProductStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Product> {
+        match self.entries.get(id) {
+            Some(Entry::Live { product, .. }) => Some(product),
+            _ => None,
+        }
+    }
+
+    pub fn products(&self) -> impl Iterator<Item = &Product> {
+        self.entries.values().filter_map(|entry| match entry {
+            Entry::Live { product, .. } => Some(product),
+            Entry::Deleted { .. } => None,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.products().next().is_none()
+    }
+
+    pub fn apply(&mut self, event: ProductEvent) -> Result<ProcessOutcome, ProcessError> {
+        let version = event.version;
+
+        if let Some(entry) = self.entries.get(&event.id) {
+            let stored = entry.version();
+            if version == stored {
+                return Ok(ProcessOutcome::Duplicate);
+            }
+            if version < stored {
+                return Err(ProcessError::StaleVersion {
+                    id: event.id,
+                    stored,
+                    received: version,
+                });
+            }
+            if matches!(entry, Entry::Deleted { .. }) && event.event != EventType::Created {
+                return Err(ProcessError::NotFound { id: event.id });
+            }
+        }
+
+        let id = event.id.clone();
+        let entry = match event.event {
+            EventType::Created | EventType::Updated => Entry::Live {
+                product: Product::from(event),
+                version,
+            },
+            EventType::Deleted => Entry::Deleted { version },
+        };
+        self.entries.insert(id, entry);
+        Ok(ProcessOutcome::Applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event: EventType, version: &str) -> ProductEvent {
+        ProductEvent {
+            id: "some-uuid-1234-5678".to_string(),
+            name: format!("Some Product {}", version),
+            r#type: "Product Range".to_string(),
+            version: version.parse().unwrap(),
+            event,
+        }
+    }
+
+    #[test]
+    fn applies_newer_versions() {
+        let mut store = ProductStore::new();
+        assert_eq!(
+            store.apply(event(EventType::Created, "v1")).unwrap(),
+            ProcessOutcome::Applied
+        );
+        assert_eq!(
+            store.apply(event(EventType::Updated, "v2")).unwrap(),
+            ProcessOutcome::Applied
+        );
+
+        let product = store.get("some-uuid-1234-5678").unwrap();
+        assert_eq!(product.version, Some(ProductVersion::new(2)));
+        assert_eq!(product.name, "Some Product v2");
+    }
+
+    #[test]
+    fn skips_redelivered_and_stale_events() {
+        let mut store = ProductStore::new();
+        store.apply(event(EventType::Updated, "v3")).unwrap();
+
+        assert_eq!(
+            store.apply(event(EventType::Updated, "v3")).unwrap(),
+            ProcessOutcome::Duplicate
+        );
+        assert!(matches!(
+            store.apply(event(EventType::Updated, "v2")),
+            Err(ProcessError::StaleVersion { .. })
+        ));
+        assert_eq!(
+            store.get("some-uuid-1234-5678").unwrap().version,
+            Some(ProductVersion::new(3))
+        );
+    }
+
+    #[test]
+    fn tombstones_stop_late_events_resurrecting_a_product() {
+        let mut store = ProductStore::new();
+        store.apply(event(EventType::Created, "v1")).unwrap();
+        store.apply(event(EventType::Deleted, "v2")).unwrap();
+
+        assert!(matches!(
+            store.apply(event(EventType::Created, "v1")),
+            Err(ProcessError::StaleVersion { .. })
+        ));
+        assert!(store.get("some-uuid-1234-5678").is_none());
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn deleted_products_can_only_be_created_again() {
+        let mut store = ProductStore::new();
+        store.apply(event(EventType::Created, "v1")).unwrap();
+        store.apply(event(EventType::Deleted, "v2")).unwrap();
+
+        for later in [EventType::Updated, EventType::Deleted] {
+            assert!(matches!(
+                store.apply(event(later, "v3")),
+                Err(ProcessError::NotFound { .. })
+            ));
+        }
+        assert_eq!(
+            store.apply(event(EventType::Created, "v3")).unwrap(),
+            ProcessOutcome::Applied
+        );
+        assert!(store.get("some-uuid-1234-5678").is_some());
+    }
+}
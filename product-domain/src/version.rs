@@ -0,0 +1,160 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// A product version as it appears on the wire: `v` followed by a number,
+/// such as `v3`.
+///
+/// Versions order numerically, so `v10` is newer than `v9`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProductVersion(u32);
+
+impl ProductVersion {
+    /// The version a product is given when it is created.
+    pub const FIRST: ProductVersion = ProductVersion(1);
+
+    /// The version of a delete that names no version. It supersedes every
+    /// version a consumer may hold, so the product's id cannot be reused.
+    pub const LAST: ProductVersion = ProductVersion(u32::MAX);
+
+    pub fn new(number: u32) -> Self {
+        ProductVersion(number)
+    }
+
+    pub fn number(&self) -> u32 {
+        self.0
+    }
+
+    /// The version after this one, or an error once the counter is exhausted.
+    pub fn next(self) -> Result<ProductVersion, VersionOverflow> {
+        self.0
+            .checked_add(1)
+            .map(ProductVersion)
+            .ok_or(VersionOverflow(self))
+    }
+}
+
+impl fmt::Display for ProductVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+impl FromStr for ProductVersion {
+    type Err = InvalidVersion;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidVersion(s.to_string());
+        let digits = s.strip_prefix('v').ok_or_else(invalid)?;
+        let canonical = !digits.is_empty()
+            && digits.bytes().all(|b| b.is_ascii_digit())
+            && (digits == "0" || !digits.starts_with('0'));
+        if !canonical {
+            return Err(invalid());
+        }
+        digits.parse().map(ProductVersion).map_err(|_| invalid())
+    }
+}
+
+impl Serialize for ProductVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ProductVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let version = String::deserialize(deserializer)?;
+        version.parse().map_err(de::Error::custom)
+    }
+}
+
+/// A string that is not a valid [`ProductVersion`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidVersion(pub String);
+
+impl fmt::Display for InvalidVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid version {:?}, expected v followed by a number",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidVersion {}
+
+/// Returned when a version is already the highest one that can be represented.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VersionOverflow(pub ProductVersion);
+
+impl fmt::Display for VersionOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "version {} cannot be incremented", self.0)
+    }
+}
+
+impl std::error::Error for VersionOverflow {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_v_followed_by_a_number() {
+        assert_eq!("v1".parse(), Ok(ProductVersion::new(1)));
+        assert_eq!("v42".parse(), Ok(ProductVersion::new(42)));
+        assert_eq!(ProductVersion::new(42).to_string(), "v42");
+    }
+
+    #[test]
+    fn rejects_anything_else() {
+        for version in [
+            "",
+            "v",
+            "1",
+            "vX",
+            "v1.2",
+            "v+1",
+            "v-1",
+            "v01",
+            "V1",
+            "v99999999999",
+        ] {
+            assert_eq!(
+                version.parse::<ProductVersion>(),
+                Err(InvalidVersion(version.to_string())),
+                "{:?} should be rejected",
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn orders_numerically() {
+        let v9: ProductVersion = "v9".parse().unwrap();
+        let v10: ProductVersion = "v10".parse().unwrap();
+        assert!(v9 < v10);
+    }
+
+    #[test]
+    fn increments_without_overflowing() {
+        assert_eq!(ProductVersion::FIRST.next(), Ok(ProductVersion::new(2)));
+        let last = ProductVersion::new(u32::MAX);
+        assert_eq!(last.next(), Err(VersionOverflow(last)));
+    }
+
+    #[test]
+    fn round_trips_through_serde_as_a_string() {
+        let version = ProductVersion::new(3);
+        assert_eq!(json!(version), json!("v3"));
+        assert_eq!(
+            serde_json::from_value::<ProductVersion>(json!("v3")).unwrap(),
+            version
+        );
+        assert!(serde_json::from_value::<ProductVersion>(json!("vX")).is_err());
+        assert!(serde_json::from_value::<ProductVersion>(json!(3)).is_err());
+    }
+}
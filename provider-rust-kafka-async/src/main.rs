@@ -1,57 +1,179 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
-use rdkafka::config::ClientConfig;
-use rdkafka::producer::{FutureProducer, FutureRecord};
-use serde::{Deserialize, Serialize};
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder, ResponseError};
+use futures::future::join_all;
+use kafka_support::config::{ProducerMode, Settings};
+use kafka_support::health::{self, ProducerProbe};
+use kafka_support::http::{self, problem};
+use kafka_support::shutdown::{self, Shutdown};
+use kafka_support::transaction::Transactions;
+use product_domain::{EventMetadata, EventType, IdMismatch, Product, ProductEvent, VersionOverflow};
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::producer::{FutureProducer, Producer};
+use serde::Serialize;
+use std::fmt;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Product {
-    id: Option<String>,
-    name: String,
-    r#type: String,
-    version: Option<String>,
+use std::time::Duration;
+
+mod outbox;
+mod partitioning;
+
+use outbox::{EventRecord, Outbox, Relay};
+use partitioning::Partitioner;
+
+/// Identifies this service in the `producer-id` header of its events.
+const PRODUCER_ID: &str = env!("CARGO_PKG_NAME");
+
+/// How long a publish waits for room in the producer's queue.
+const ENQUEUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long the brokers have to acknowledge an event, unless
+/// `message.timeout.ms` is passed through to the producer.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The `Retry-After` given to clients when an event cannot be published.
+const RETRY_AFTER_SECS: u64 = 5;
+
+/// How long each call to the brokers in a transaction may take.
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why an event could not be published.
+#[derive(Debug)]
+pub enum PublishError {
+    /// The producer's queue stayed full for the whole enqueue timeout.
+    QueueFull,
+    /// The brokers did not acknowledge the event.
+    Delivery(KafkaError),
+    /// The event could not be stored in the outbox.
+    Outbox(rusqlite::Error),
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct ProductEvent {
-    id: String,
-    name: String,
-    r#type: String,
-    version: String,
-    event: String,
+impl From<KafkaError> for PublishError {
+    fn from(e: KafkaError) -> Self {
+        match e {
+            KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull) => PublishError::QueueFull,
+            e => PublishError::Delivery(e),
+        }
+    }
 }
 
-pub struct ProductEventService {
-    producer: Arc<Mutex<FutureProducer>>,
-    topic: String,
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PublishError::QueueFull => write!(
+                f,
+                "the producer queue stayed full for {}ms",
+                ENQUEUE_TIMEOUT.as_millis()
+            ),
+            PublishError::Delivery(e) => write!(f, "the event was not delivered: {}", e),
+            PublishError::Outbox(e) => write!(f, "the event was not stored in the outbox: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PublishError {}
+
+/// Why a write endpoint could not publish its event.
+#[derive(Debug)]
+pub enum WriteError {
+    InvalidVersion(VersionOverflow),
+    Publish(PublishError),
 }
 
-pub fn create_event(product: Product, event_type: &str) -> ProductEvent {
-    let version = increment_version(product.version);
-    ProductEvent {
-        id: product
-            .id
-            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
-        name: product.name,
-        r#type: product.r#type,
-        event: event_type.to_string(),
-        version,
+impl From<VersionOverflow> for WriteError {
+    fn from(e: VersionOverflow) -> Self {
+        WriteError::InvalidVersion(e)
     }
 }
 
+impl From<PublishError> for WriteError {
+    fn from(e: PublishError) -> Self {
+        WriteError::Publish(e)
+    }
+}
+
+/// A published event and where it landed, as returned by the write
+/// endpoints so clients can correlate with what consumers see.
+#[derive(Debug, Serialize)]
+pub struct Published {
+    pub event: ProductEvent,
+    #[serde(flatten)]
+    pub delivery: Delivery,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Delivery {
+    /// The brokers acknowledged the event.
+    Sent { partition: i32, offset: i64 },
+    /// The event is stored in the outbox for the relay to publish.
+    Queued { outbox_id: i64 },
+}
+
+pub struct ProductEventService {
+    // FutureProducer is a cheap handle that can send from many tasks at once,
+    // so publishes only wait for their own delivery reports.
+    producer: FutureProducer,
+    // Set in transactional mode, where every publish is a transaction
+    transactions: Option<Transactions>,
+    topic: String,
+    partitioner: Arc<dyn Partitioner>,
+    outbox: Option<Arc<Outbox>>,
+    failed_deliveries: AtomicU64,
+}
+
 impl ProductEventService {
-    async fn new(broker: &str, topic: &str) -> Self {
-        let producer: FutureProducer = ClientConfig::new()
-            .set("bootstrap.servers", broker)
-            .create()
-            .expect("Producer creation error");
+    async fn new(settings: &Settings) -> Self {
+        let mut config = settings.producer_config();
+        if config.get("message.timeout.ms").is_none() {
+            config.set(
+                "message.timeout.ms",
+                DELIVERY_TIMEOUT.as_millis().to_string(),
+            );
+        }
+        let producer: FutureProducer = config.create().expect("Producer creation error");
+        let transactions = match settings.producer_mode() {
+            ProducerMode::Transactional => Some(
+                Transactions::init(producer.clone(), TRANSACTION_TIMEOUT)
+                    .await
+                    .expect("Transactional producer initialisation error"),
+            ),
+            _ => None,
+        };
 
         ProductEventService {
-            producer: Arc::new(Mutex::new(producer)),
-            topic: topic.to_string(),
+            producer,
+            transactions,
+            topic: settings.topic("products").to_string(),
+            partitioner: Arc::new(settings.partitioning()),
+            outbox: None,
+            failed_deliveries: AtomicU64::new(0),
         }
     }
 
+    /// Stores events in `outbox` for a [`Relay`] to publish, instead of
+    /// publishing them before answering.
+    fn with_outbox(mut self, outbox: Arc<Outbox>) -> Self {
+        self.outbox = Some(outbox);
+        self
+    }
+
+    /// A relay publishing the outbox with this service's producer.
+    fn relay(&self) -> Option<Relay> {
+        let outbox = self.outbox.clone()?;
+        Some(Relay::new(outbox, self.producer.clone()))
+    }
+
+    /// Publishes to the partitions `partitioner` chooses instead of those the
+    /// `partitioning` setting does.
+    // Only other partitioners need it; main publishes as configured
+    #[allow(dead_code)]
+    fn with_partitioner(mut self, partitioner: Arc<dyn Partitioner>) -> Self {
+        self.partitioner = partitioner;
+        self
+    }
+
     // pub fn create_event(&self, product: Product, event_type: &str) -> ProductEvent {
     //     let version = increment_version(product.version);
     //     ProductEvent {
@@ -65,92 +187,334 @@ impl ProductEventService {
     //     }
     // }
 
-    async fn publish(&self, event: ProductEvent) {
-        let payload = serde_json::to_string(&event).unwrap();
-        let record = FutureRecord::<String, String>::to(&self.topic).payload(&payload);
-        let producer = self.producer.lock().await;
-        producer
-            .send(record, rdkafka::util::Timeout::Never)
-            .await
-            .unwrap();
+    fn record(&self, event: &ProductEvent) -> EventRecord {
+        let metadata = EventMetadata::new(event, PRODUCER_ID);
+        EventRecord {
+            topic: self.topic.clone(),
+            key: event.id.clone(),
+            partition: self.partitioner.partition(event),
+            payload: serde_json::to_string(event).unwrap(),
+            headers: metadata
+                .headers()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
     }
 
-    async fn create(&self, product: Product) {
-        let event = create_event(product, "CREATED");
-        self.publish(event).await;
+    /// Publishes `event`, failing if the producer's queue stays full for
+    /// [`ENQUEUE_TIMEOUT`] or the brokers do not acknowledge it. With an
+    /// outbox, only stores it.
+    async fn publish(&self, event: ProductEvent) -> Result<Published, PublishError> {
+        let mut published = self.publish_all(vec![event]).await?;
+        Ok(published.remove(0))
     }
 
-    async fn update(&self, product: Product) {
-        let event = create_event(product, "UPDATED");
-        self.publish(event).await;
+    /// Publishes `events` in order. In transactional mode, and when storing
+    /// them in the outbox, they are published all or nothing. Otherwise the
+    /// events that were acknowledged stay published when another fails.
+    async fn publish_all(&self, events: Vec<ProductEvent>) -> Result<Vec<Published>, PublishError> {
+        let records: Vec<EventRecord> = events.iter().map(|event| self.record(event)).collect();
+        if let Some(outbox) = &self.outbox {
+            let ids = outbox.add_all(&records).await.map_err(PublishError::Outbox)?;
+            return Ok(events
+                .into_iter()
+                .zip(ids)
+                .map(|(event, outbox_id)| Published {
+                    event,
+                    delivery: Delivery::Queued { outbox_id },
+                })
+                .collect());
+        }
+
+        let transaction = match &self.transactions {
+            Some(transactions) => match transactions.begin().await {
+                Ok(transaction) => Some(transaction),
+                Err(e) => {
+                    for event in &events {
+                        self.count_failure(event, &e);
+                    }
+                    return Err(e.into());
+                }
+            },
+            None => None,
+        };
+        // Records are queued in order, so events for one product keep theirs
+        let results = join_all(
+            records
+                .iter()
+                .map(|record| self.producer.send(record.future_record(), ENQUEUE_TIMEOUT)),
+        )
+        .await;
+        let mut published = Vec::with_capacity(events.len());
+        let mut error = None;
+        for (event, result) in events.into_iter().zip(results) {
+            match result {
+                Ok((partition, offset)) => published.push(Published {
+                    event,
+                    delivery: Delivery::Sent { partition, offset },
+                }),
+                Err((e, _)) => {
+                    self.count_failure(&event, &e);
+                    error.get_or_insert(e);
+                }
+            }
+        }
+
+        match (transaction, error) {
+            (None, None) => Ok(published),
+            (None, Some(e)) => Err(e.into()),
+            (Some(transaction), None) => match transaction.commit().await {
+                Ok(()) => Ok(published),
+                Err(e) => {
+                    for published in &published {
+                        self.count_failure(&published.event, &e);
+                    }
+                    Err(e.into())
+                }
+            },
+            // The acknowledged events are aborted with the rest
+            (Some(transaction), Some(e)) => {
+                for published in &published {
+                    self.count_failure(&published.event, &e);
+                }
+                Err(transaction.abort_with(e).await.into())
+            }
+        }
     }
 
-    async fn delete(&self, product: Product) {
-        let event = create_event(product, "DELETED");
-        self.publish(event).await;
+    fn count_failure(&self, event: &ProductEvent, error: &KafkaError) {
+        let failed = self.failed_deliveries.fetch_add(1, Ordering::Relaxed) + 1;
+        eprintln!(
+            "Error publishing event for product {} ({} failed so far): {}",
+            event.id, failed, error
+        );
     }
+
+    /// Blocks for up to `timeout` while records still queued are delivered.
+    fn flush(&self, timeout: Duration) -> Result<(), KafkaError> {
+        self.producer.flush(timeout)
+    }
+
+    async fn create(&self, product: Product) -> Result<Published, WriteError> {
+        let event = product.into_event(EventType::Created)?;
+        Ok(self.publish(event).await?)
+    }
+
+    async fn update(&self, product: Product) -> Result<Published, WriteError> {
+        let event = product.into_event(EventType::Updated)?;
+        Ok(self.publish(event).await?)
+    }
+
+    async fn delete(&self, product: Product) -> Result<Published, WriteError> {
+        let event = product.into_event(EventType::Deleted)?;
+        Ok(self.publish(event).await?)
+    }
+
+    async fn create_all(&self, products: Vec<Product>) -> Result<Vec<Published>, WriteError> {
+        let events = products
+            .into_iter()
+            .map(|product| product.into_event(EventType::Created))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(self.publish_all(events).await?)
+    }
+}
+
+fn invalid_version(e: VersionOverflow) -> HttpResponse {
+    problem(
+        StatusCode::BAD_REQUEST,
+        "Invalid product version",
+        &e.to_string(),
+    )
 }
 
-fn increment_version(version: Option<String>) -> String {
-    match version {
-        Some(v) => {
-            let num: u32 = v[1..].parse().unwrap();
-            format!("v{}", num + 1)
+fn write_error(e: WriteError) -> HttpResponse {
+    match e {
+        WriteError::InvalidVersion(e) => invalid_version(e),
+        WriteError::Publish(e) => {
+            let mut response = problem(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Product event not published",
+                &e.to_string(),
+            );
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, header::HeaderValue::from(RETRY_AFTER_SECS));
+            response
         }
-        None => "v1".to_string(),
     }
 }
 
+/// `sent` once the brokers have the event, or 202 while it waits in the
+/// outbox.
+fn write_status(published: &Published, sent: StatusCode) -> StatusCode {
+    match published.delivery {
+        Delivery::Sent { .. } => sent,
+        Delivery::Queued { .. } => StatusCode::ACCEPTED,
+    }
+}
+
+fn id_mismatch(e: IdMismatch) -> HttpResponse {
+    problem(StatusCode::BAD_REQUEST, "Product id mismatch", &e.to_string())
+}
+
+fn app_config(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(web::JsonConfig::default().error_handler(http::json_error_handler))
+        .route("/healthz", web::get().to(health::healthz))
+        .route("/readyz", web::get().to(health::readyz::<ProducerProbe>))
+        .route("/products", web::post().to(create_product))
+        .route("/products/bulk", web::post().to(create_products))
+        .route("/products/{id}", web::put().to(update_product))
+        .route("/products/{id}", web::delete().to(delete_product));
+}
+
 async fn create_product(
     service: web::Data<Arc<ProductEventService>>,
     product: web::Json<Product>,
 ) -> impl Responder {
-    service.create(product.into_inner()).await;
-    HttpResponse::Created().finish()
+    match service.create(product.into_inner()).await {
+        Ok(published) => HttpResponse::build(write_status(&published, StatusCode::CREATED))
+            .insert_header((header::LOCATION, format!("/products/{}", published.event.id)))
+            .json(published),
+        Err(e) => write_error(e),
+    }
+}
+
+/// Creates every product in the body, publishing their events together.
+async fn create_products(
+    service: web::Data<Arc<ProductEventService>>,
+    products: web::Json<Vec<Product>>,
+) -> impl Responder {
+    match service.create_all(products.into_inner()).await {
+        Ok(published) => {
+            let status = published
+                .first()
+                .map_or(StatusCode::CREATED, |first| {
+                    write_status(first, StatusCode::CREATED)
+                });
+            HttpResponse::build(status).json(published)
+        }
+        Err(e) => write_error(e),
+    }
 }
 
 async fn update_product(
     service: web::Data<Arc<ProductEventService>>,
+    id: web::Path<String>,
     product: web::Json<Product>,
 ) -> impl Responder {
-    service.update(product.into_inner()).await;
-    HttpResponse::Ok().finish()
+    let product = match product.into_inner().with_id(&id) {
+        Ok(product) => product,
+        Err(e) => return id_mismatch(e),
+    };
+    match service.update(product).await {
+        Ok(published) => {
+            HttpResponse::build(write_status(&published, StatusCode::OK)).json(published)
+        }
+        Err(e) => write_error(e),
+    }
 }
 
 async fn delete_product(
+    req: HttpRequest,
     service: web::Data<Arc<ProductEventService>>,
-    product: web::Json<Product>,
+    id: web::Path<String>,
+    body: web::Bytes,
 ) -> impl Responder {
-    service.delete(product.into_inner()).await;
-    HttpResponse::Ok().finish()
+    let product = match http::product_to_delete(&req, &id, &body) {
+        Ok(product) => product,
+        Err(e) => return e.error_response(),
+    };
+    match service.delete(product).await {
+        Ok(published) => {
+            HttpResponse::build(write_status(&published, StatusCode::OK)).json(published)
+        }
+        Err(e) => write_error(e),
+    }
 }
 
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let broker = "localhost:9092";
-    let topic = "products";
-    let service = Arc::new(ProductEventService::new(broker, topic).await);
+async fn main() -> std::io::Result<ExitCode> {
+    let settings = Settings::builder("127.0.0.1:8081")
+        .topic("products", "products")
+        .load()
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(2)
+        });
+    println!("Kafka client settings: {}", settings);
+    let mut service = ProductEventService::new(&settings).await;
+    if let Some(path) = settings.outbox_path() {
+        let outbox = Outbox::open(path).unwrap_or_else(|e| {
+            eprintln!("Cannot open outbox {}: {}", path.display(), e);
+            std::process::exit(2)
+        });
+        service = service.with_outbox(Arc::new(outbox));
+    }
+    let service = Arc::new(service);
+    let shutdown = Shutdown::new();
+
+    // The relay publishes what handlers stored until the server has stopped,
+    // so it sees every write the server accepted
+    let server_stopped = Shutdown::new();
+    let relay = service.relay().map(|relay| {
+        let server_stopped = server_stopped.clone();
+        actix_web::rt::spawn(async move { relay.run(&server_stopped).await })
+    });
+    let probe = web::Data::new(ProducerProbe::new(
+        service.producer.clone(),
+        settings.topics().map(str::to_string).collect(),
+        shutdown.clone(),
+        Duration::from_secs(5),
+    ));
 
-    HttpServer::new(move || {
+    let app_service = service.clone();
+    let server = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(service.clone()))
-            .route("/products", web::post().to(create_product))
-            .route("/products/{id}", web::put().to(update_product))
-            .route("/products/{id}", web::delete().to(delete_product))
+            .app_data(web::Data::new(app_service.clone()))
+            .app_data(probe.clone())
+            .configure(app_config)
     })
-    .bind("127.0.0.1:8081")?
-    .run()
-    .await
+    .bind(settings.bind())?
+    .disable_signals()
+    .shutdown_timeout(settings.shutdown_timeout().as_secs())
+    .run();
+
+    // In-flight requests finish publishing before the server stops
+    let server_handle = server.handle();
+    let on_signal = shutdown.clone();
+    actix_web::rt::spawn(shutdown::stop_on_signal(async move {
+        on_signal.trigger();
+        server_handle.stop(true).await;
+    }));
+
+    server.await?;
+    server_stopped.trigger();
+    if let Some(relay) = relay {
+        let _ = relay.await;
+    }
+
+    // The server and relay have stopped, so blocking here holds up nothing
+    let clean = match service.flush(settings.shutdown_timeout()) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Error flushing {} topic on shutdown: {}", service.topic, e);
+            false
+        }
+    };
+    Ok(shutdown::exit_code(clean))
 }
 
 #[cfg(test)]
 mod tests {
 
-    use crate::{create_event, Product};
+    use actix_http::Request;
+    use actix_web::dev::{Service, ServiceResponse};
     use actix_web::http::header::HeaderName;
     use actix_web::http::header::HeaderValue;
-    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
     use async_trait::async_trait;
+    use kafka_support::config::Settings;
     use base64::{engine::general_purpose, Engine as _};
     use expectest::prelude::*;
     use maplit::*;
@@ -160,9 +524,21 @@ mod tests {
         NullRequestFilterExecutor, PactSource, ProviderInfo, ProviderTransport,
         VerificationOptions,
     };
+    use crate::outbox::Outbox;
+    use kafka_support::headers;
+    use kafka_support::shutdown::Shutdown;
+    use product_domain::{EventMetadata, EventType, Product, ProductEvent};
+    use rdkafka::consumer::{Consumer, StreamConsumer};
+    use rdkafka::message::Message;
+    use rdkafka::mocking::MockCluster;
+    use rdkafka::producer::DefaultProducerContext;
+    use rdkafka::ClientConfig;
     use reqwest::Client;
     use serde_json::json;
     use serde_json::Value;
+    use futures::future::join_all;
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
     use std::{collections::HashMap, env, path::PathBuf, sync::Arc};
     use tokio::sync::oneshot;
     #[derive(Debug)]
@@ -180,8 +556,8 @@ mod tests {
             Ok(hashmap! {})
         }
 
-        fn teardown(self: &Self) -> bool {
-            return false;
+        fn teardown(&self) -> bool {
+            false
         }
     }
 
@@ -201,14 +577,16 @@ mod tests {
                         id: Some("some-uuid-1234-5678".to_string()),
                         name: "Some Product".to_string(),
                         r#type: "Product Range".to_string(),
-                        version: Some("v1".to_string()),
+                        version: Some("v1".parse().unwrap()),
                     };
-                    let event_type = "UPDATED";
-                    let product_event = create_event(product, event_type);
-                    let mut response = HttpResponse::Ok().json(product_event);
-                    let metadata = json!({
+                    let product_event = product.into_event(EventType::Updated).unwrap();
+                    let mut metadata = json!({
                       "kafka_topic": "products"
                     });
+                    for (name, value) in EventMetadata::new(&product_event, super::PRODUCER_ID).headers() {
+                        metadata[name] = json!(value);
+                    }
+                    let mut response = HttpResponse::Ok().json(product_event);
                     let encoded_metadata =
                         general_purpose::STANDARD.encode(metadata.to_string());
                     response.headers_mut().insert(
@@ -239,6 +617,397 @@ mod tests {
         tx
     }
 
+    /// A products topic with `partitions` partitions on a fresh mock
+    /// cluster, and the settings of a provider publishing to it with `env`
+    /// on top.
+    fn products_topic(partitions: i32, env: &[(&str, &str)]) -> (MockCluster<'static, DefaultProducerContext>, Settings) {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("products", partitions, 1).unwrap();
+        let env = [("KAFKA_BOOTSTRAP_SERVERS".to_string(), cluster.bootstrap_servers())]
+            .into_iter()
+            .chain(env.iter().map(|(name, value)| (name.to_string(), value.to_string())));
+        let settings = Settings::builder("127.0.0.1:8081")
+            .topic("products", "products")
+            .load_from(env, std::iter::empty())
+            .unwrap();
+        (cluster, settings)
+    }
+
+    /// The provider's routes around `service`.
+    async fn app(
+        service: Arc<super::ProductEventService>,
+    ) -> impl Service<Request, Response = ServiceResponse, Error = actix_web::Error> {
+        test::init_service(
+            App::new()
+                .app_data(web::Data::new(service))
+                .configure(super::app_config),
+        )
+        .await
+    }
+
+    #[actix_web::test]
+    async fn liveness_does_not_depend_on_kafka() {
+        let app = test::init_service(App::new().configure(super::app_config)).await;
+
+        let request = test::TestRequest::get().uri("/healthz").to_request();
+        let body: Value = test::call_and_read_body_json(&app, request).await;
+
+        expect!(body).to(be_equal_to(json!({ "status": "ok" })));
+    }
+
+    #[actix_web::test]
+    async fn write_endpoints_return_the_published_event() {
+        let (_cluster, settings) = products_topic(1, &[]);
+        let app = app(Arc::new(super::ProductEventService::new(&settings).await)).await;
+
+        let request = test::TestRequest::post()
+            .uri("/products")
+            .set_json(json!({ "name": "Some Product", "type": "Product Range" }))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        expect!(response.status()).to(be_equal_to(StatusCode::CREATED));
+        let location = response.headers().get("location").unwrap().to_str().unwrap().to_string();
+        let created: Value = test::read_body_json(response).await;
+        let id = created["event"]["id"].as_str().unwrap();
+        expect!(location.clone()).to(be_equal_to(format!("/products/{}", id)));
+        expect!(created["event"]["version"].as_str()).to(be_some().value("v1"));
+        expect!(created["event"]["event"].as_str()).to(be_some().value("CREATED"));
+        expect!(created["partition"].as_i64()).to(be_some().value(0));
+        expect!(created["offset"].as_i64()).to(be_some().value(0));
+
+        let request = test::TestRequest::put()
+            .uri(&location)
+            .set_json(json!({ "name": "Some Product", "type": "Product Range", "version": "v1" }))
+            .to_request();
+        let updated: Value = test::call_and_read_body_json(&app, request).await;
+        expect!(updated["event"]["version"].as_str()).to(be_some().value("v2"));
+        expect!(updated["offset"].as_i64()).to(be_some().value(1));
+    }
+
+    #[actix_web::test]
+    async fn publishes_concurrent_posts_in_parallel() {
+        // Each delivery takes at least linger.ms, so publishing these one at
+        // a time would take REQUESTS * LINGER.
+        const REQUESTS: usize = 50;
+        const LINGER: Duration = Duration::from_millis(200);
+        let linger_ms = LINGER.as_millis().to_string();
+        let (_cluster, settings) = products_topic(3, &[("KAFKA_PROPERTY_LINGER_MS", &linger_ms)]);
+        let app = app(Arc::new(super::ProductEventService::new(&settings).await)).await;
+
+        let started = Instant::now();
+        let statuses = join_all((0..REQUESTS).map(|i| {
+            let request = test::TestRequest::post()
+                .uri("/products")
+                .set_json(json!({ "name": format!("Product {}", i), "type": "Product Range" }))
+                .to_request();
+            let app = &app;
+            async move { test::call_service(app, request).await.status() }
+        }))
+        .await;
+        let elapsed = started.elapsed();
+
+        expect!(statuses.iter().all(|status| *status == StatusCode::CREATED)).to(be_true());
+        expect!(elapsed < LINGER * (REQUESTS as u32) / 5).to(be_true());
+    }
+
+    #[actix_web::test]
+    async fn events_for_one_product_are_consumed_in_order() {
+        let (cluster, settings) = products_topic(6, &[]);
+        let service = super::ProductEventService::new(&settings).await;
+        let product = |name: &str| Product {
+            id: None,
+            name: name.to_string(),
+            r#type: "Product Range".to_string(),
+            version: None,
+        };
+
+        let created = service.create(product("Some Product")).await.unwrap();
+        let id = created.event.id.clone();
+        for i in 0..10 {
+            service.create(product(&format!("Other Product {}", i))).await.unwrap();
+        }
+        let updated = service
+            .update(Product {
+                id: Some(id.clone()),
+                version: Some(created.event.version),
+                ..product("Some Product v2")
+            })
+            .await
+            .unwrap();
+        let deleted = service
+            .delete(Product::reference(&id, Some(updated.event.version)))
+            .await
+            .unwrap();
+        let partition = |published: &super::Published| match published.delivery {
+            super::Delivery::Sent { partition, .. } => partition,
+            super::Delivery::Queued { .. } => panic!("published without an outbox"),
+        };
+        expect!(partition(&updated)).to(be_equal_to(partition(&created)));
+        expect!(partition(&deleted)).to(be_equal_to(partition(&created)));
+
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .set("group.id", "products-group")
+            .set("auto.offset.reset", "earliest")
+            .create()
+            .unwrap();
+        consumer.subscribe(&["products"]).unwrap();
+        let mut events = Vec::new();
+        for _ in 0..13 {
+            let message = tokio::time::timeout(Duration::from_secs(10), consumer.recv())
+                .await
+                .expect("events were not consumed")
+                .unwrap();
+            if message.key() == Some(id.as_bytes()) {
+                let event: ProductEvent = serde_json::from_slice(message.payload().unwrap()).unwrap();
+                let metadata = EventMetadata::from_headers(headers::pairs(&message));
+                expect!(metadata.event_type).to(be_some().value(event.event.to_string()));
+                expect!(metadata.producer_id.as_deref()).to(be_some().value("provider-rust-kafka-async"));
+                events.push(event.event);
+            }
+        }
+        expect!(events).to(be_equal_to(vec![EventType::Created, EventType::Updated, EventType::Deleted]));
+    }
+
+    #[actix_web::test]
+    async fn publishes_to_the_configured_partition() {
+        let (_cluster, settings) = products_topic(3, &[("KAFKA_PARTITIONING", "explicit:2")]);
+        let service = super::ProductEventService::new(&settings).await;
+        let app = app(Arc::new(service)).await;
+
+        for name in ["Some Product", "Other Product"] {
+            let request = test::TestRequest::post()
+                .uri("/products")
+                .set_json(json!({ "name": name, "type": "Product Range" }))
+                .to_request();
+            let created: Value = test::call_and_read_body_json(&app, request).await;
+            expect!(created["partition"].as_i64()).to(be_some().value(2));
+        }
+    }
+
+    #[actix_web::test]
+    async fn publishes_to_the_partition_a_custom_partitioner_chooses() {
+        struct ByName;
+
+        impl crate::partitioning::Partitioner for ByName {
+            fn partition(&self, event: &ProductEvent) -> Option<i32> {
+                Some(if event.name == "Other Product" { 1 } else { 0 })
+            }
+        }
+
+        let (_cluster, settings) = products_topic(3, &[("KAFKA_PARTITIONING", "explicit:2")]);
+        let service = super::ProductEventService::new(&settings)
+            .await
+            .with_partitioner(Arc::new(ByName));
+        let app = app(Arc::new(service)).await;
+
+        for (name, partition) in [("Some Product", 0), ("Other Product", 1)] {
+            let request = test::TestRequest::post()
+                .uri("/products")
+                .set_json(json!({ "name": name, "type": "Product Range" }))
+                .to_request();
+            let created: Value = test::call_and_read_body_json(&app, request).await;
+            expect!(created["partition"].as_i64()).to(be_some().value(partition));
+        }
+    }
+
+    #[actix_web::test]
+    async fn bulk_creates_are_published_in_one_transaction() {
+        let (cluster, settings) = products_topic(
+            3,
+            &[
+                ("KAFKA_PRODUCER_MODE", "transactional"),
+                ("KAFKA_TRANSACTIONAL_ID", "provider-rust-kafka-async-1"),
+            ],
+        );
+        let app = app(Arc::new(super::ProductEventService::new(&settings).await)).await;
+
+        let request = test::TestRequest::post()
+            .uri("/products/bulk")
+            .set_json(json!([
+                { "name": "Some Product", "type": "Product Range" },
+                { "name": "Other Product", "type": "Product Range" },
+                { "name": "Third Product", "type": "Product Range" }
+            ]))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        expect!(response.status()).to(be_equal_to(StatusCode::CREATED));
+        let published: Value = test::read_body_json(response).await;
+        let mut ids: Vec<String> = published
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|published| published["event"]["id"].as_str().unwrap().to_string())
+            .collect();
+        expect!(ids.len()).to(be_equal_to(3));
+
+        // Single writes are transactions of their own
+        let request = test::TestRequest::post()
+            .uri("/products")
+            .set_json(json!({ "name": "Fourth Product", "type": "Product Range" }))
+            .to_request();
+        let created: Value = test::call_and_read_body_json(&app, request).await;
+        ids.push(created["event"]["id"].as_str().unwrap().to_string());
+
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .set("group.id", "products-group")
+            .set("auto.offset.reset", "earliest")
+            .create()
+            .unwrap();
+        consumer.subscribe(&["products"]).unwrap();
+        let mut consumed = Vec::new();
+        for _ in 0..ids.len() {
+            let message = tokio::time::timeout(Duration::from_secs(10), consumer.recv())
+                .await
+                .expect("events were not consumed")
+                .unwrap();
+            let event: ProductEvent = serde_json::from_slice(message.payload().unwrap()).unwrap();
+            consumed.push(event.id);
+        }
+        ids.sort();
+        consumed.sort();
+        expect!(consumed).to(be_equal_to(ids));
+    }
+
+    #[actix_web::test]
+    async fn the_outbox_publishes_writes_accepted_while_kafka_is_down() {
+        let (cluster, settings) = products_topic(1, &[("KAFKA_PROPERTY_MESSAGE_TIMEOUT_MS", "200")]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbox.db");
+        cluster.broker_down(1).unwrap();
+
+        // The write is accepted and stored, but cannot be published yet
+        let outbox = Arc::new(Outbox::open(&path).unwrap());
+        let service = Arc::new(super::ProductEventService::new(&settings).await.with_outbox(outbox.clone()));
+        let app = app(service.clone()).await;
+        let request = test::TestRequest::post()
+            .uri("/products")
+            .set_json(json!({ "name": "Some Product", "type": "Product Range" }))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        expect!(response.status()).to(be_equal_to(StatusCode::ACCEPTED));
+        let accepted: Value = test::read_body_json(response).await;
+        let id = accepted["event"]["id"].as_str().unwrap().to_string();
+        expect!(accepted["outbox_id"].as_i64()).to(be_some().value(1));
+        expect!(service.relay().unwrap().relay_pending().await.is_err()).to(be_true());
+        expect!(outbox.pending(10).await.unwrap()[0].attempts).to(be_equal_to(1));
+        drop(app);
+        drop(service);
+        drop(outbox);
+
+        // After a restart the relay publishes it once Kafka is back
+        cluster.broker_up(1).unwrap();
+        let outbox = Arc::new(Outbox::open(&path).unwrap());
+        let service = super::ProductEventService::new(&settings).await.with_outbox(outbox.clone());
+        let relay = service.relay().unwrap();
+        let stopped = Shutdown::new();
+        let relaying = {
+            let stopped = stopped.clone();
+            tokio::spawn(async move { relay.run(&stopped).await })
+        };
+
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .set("group.id", "products-group")
+            .set("auto.offset.reset", "earliest")
+            .create()
+            .unwrap();
+        consumer.subscribe(&["products"]).unwrap();
+        let message = tokio::time::timeout(Duration::from_secs(10), consumer.recv())
+            .await
+            .expect("the outbox was not relayed")
+            .unwrap();
+        let event: ProductEvent = serde_json::from_slice(message.payload().unwrap()).unwrap();
+        expect!(event.id).to(be_equal_to(id));
+        expect!(EventMetadata::from_headers(headers::pairs(&message)).event_type).to(be_some().value("CREATED".to_string()));
+
+        stopped.trigger();
+        relaying.await.unwrap();
+        expect!(outbox.pending(10).await.unwrap().is_empty()).to(be_true());
+    }
+
+    #[actix_web::test]
+    async fn unpublished_events_are_service_unavailable() {
+        let (_cluster, settings) = products_topic(
+            1,
+            &[
+                ("KAFKA_BOOTSTRAP_SERVERS", "127.0.0.1:1"),
+                ("KAFKA_PROPERTY_MESSAGE_TIMEOUT_MS", "100"),
+            ],
+        );
+        let service = Arc::new(super::ProductEventService::new(&settings).await);
+        let app = app(service.clone()).await;
+
+        let request = test::TestRequest::post()
+            .uri("/products")
+            .set_json(json!({ "name": "Some Product", "type": "Product Range" }))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        expect!(response.status()).to(be_equal_to(StatusCode::SERVICE_UNAVAILABLE));
+        expect!(response.headers().get("retry-after").and_then(|v| v.to_str().ok())).to(be_some().value("5"));
+        let body: Value = test::read_body_json(response).await;
+        expect!(body["title"].as_str()).to(be_some().value("Product event not published"));
+        expect!(service.failed_deliveries.load(Ordering::Relaxed)).to(be_equal_to(1));
+    }
+
+    #[actix_web::test]
+    async fn path_ids_are_authoritative() {
+        let (_cluster, settings) = products_topic(1, &[]);
+        let app = app(Arc::new(super::ProductEventService::new(&settings).await)).await;
+
+        let request = test::TestRequest::put()
+            .uri("/products/some-uuid-1234-5678")
+            .set_json(json!({
+                "id": "other-uuid",
+                "name": "Some Product",
+                "type": "Product Range",
+                "version": "v1",
+            }))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        expect!(response.status()).to(be_equal_to(StatusCode::BAD_REQUEST));
+        let body: Value = test::read_body_json(response).await;
+        expect!(body["title"].as_str()).to(be_some().value("Product id mismatch"));
+
+        let request = test::TestRequest::delete()
+            .uri("/products/some-uuid-1234-5678")
+            .insert_header(("If-Match", "\"vX\""))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        expect!(response.status()).to(be_equal_to(StatusCode::BAD_REQUEST));
+        let body: Value = test::read_body_json(response).await;
+        expect!(body["title"].as_str()).to(be_some().value("Invalid product version"));
+    }
+
+    #[actix_web::test]
+    async fn rejects_invalid_versions_with_a_problem_response() {
+        let (_cluster, settings) = products_topic(1, &[]);
+        let app = app(Arc::new(super::ProductEventService::new(&settings).await)).await;
+
+        for (version, title) in [
+            ("vX", "Invalid product"),
+            ("v4294967295", "Invalid product version"),
+        ] {
+            let request = test::TestRequest::post()
+                .uri("/products")
+                .set_json(json!({
+                    "id": "some-uuid-1234-5678",
+                    "name": "Some Product",
+                    "type": "Product Range",
+                    "version": version,
+                }))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+
+            expect!(response.status()).to(be_equal_to(StatusCode::BAD_REQUEST));
+            let content_type = response.headers().get("content-type").unwrap();
+            expect!(content_type.to_str().unwrap()).to(be_equal_to("application/problem+json"));
+            let body: Value = test::read_body_json(response).await;
+            expect!(body["status"].as_u64()).to(be_some().value(400));
+            expect!(body["title"].as_str()).to(be_some().value(title));
+        }
+    }
 
     #[tokio::test]
     async fn verifies_api_produces_correct_messages_for_consumers() {
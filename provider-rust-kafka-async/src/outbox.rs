@@ -0,0 +1,466 @@
+//! A transactional outbox, so a write the provider has accepted is published
+//! even if Kafka is down when it is made.
+//!
+//! Handlers add the event they would publish to a SQLite table and answer
+//! once it is stored. A [`Relay`] task publishes pending rows in the order
+//! they were added and marks each one sent once the brokers acknowledge it.
+//! When a publish fails the relay stops at that row and retries it with
+//! backoff, so events for a product are never published out of order. Rows
+//! survive restarts, so delivery is at least once: a crash between the
+//! acknowledgement and marking the row sent publishes it again. Sent rows
+//! are kept for [`SENT_RETENTION`], to trace what was published, and then
+//! deleted by the relay.
+//!
+//! SQLite calls wait for the disk and for each other, so they run on tokio's
+//! blocking thread pool rather than on the workers serving requests.
+
+use kafka_support::headers;
+use kafka_support::shutdown::Shutdown;
+use rdkafka::error::KafkaError;
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rusqlite::types::Type;
+use rusqlite::{params, Connection};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+/// How many pending rows the relay reads at a time.
+const BATCH_SIZE: usize = 100;
+
+/// How often the relay looks for pending rows when nothing wakes it.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the relay waits after its first failure to publish a row. The
+/// wait doubles with every failure in a row, up to [`MAX_BACKOFF`].
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How long a publish waits for room in the producer's queue.
+const ENQUEUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long sent rows are kept before the relay deletes them.
+pub const SENT_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often the relay deletes sent rows past their retention.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS outbox (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        topic TEXT NOT NULL,
+        key TEXT NOT NULL,
+        partition INTEGER,
+        payload TEXT NOT NULL,
+        headers TEXT NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        last_error TEXT,
+        sent_partition INTEGER,
+        sent_offset INTEGER,
+        sent_at INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS outbox_pending ON outbox (id) WHERE sent_at IS NULL;
+    CREATE INDEX IF NOT EXISTS outbox_sent ON outbox (sent_at) WHERE sent_at IS NOT NULL;
+";
+
+/// A product event ready to be sent to Kafka.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EventRecord {
+    pub topic: String,
+    pub key: String,
+    /// The partition chosen when the event was written, or `None` to let the
+    /// producer hash the key.
+    pub partition: Option<i32>,
+    pub payload: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl EventRecord {
+    pub fn future_record(&self) -> FutureRecord<'_, str, str> {
+        let headers = headers::with_pairs(
+            OwnedHeaders::new(),
+            self.headers.iter().map(|(k, v)| (k.as_str(), v.as_str())),
+        );
+        let record = FutureRecord::to(&self.topic)
+            .key(self.key.as_str())
+            .payload(self.payload.as_str())
+            .headers(headers);
+        match self.partition {
+            Some(partition) => record.partition(partition),
+            None => record,
+        }
+    }
+}
+
+/// A row waiting to be published.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingEvent {
+    pub id: i64,
+    pub record: EventRecord,
+    /// How many times publishing it has failed.
+    pub attempts: u32,
+}
+
+/// The outbox table of one provider.
+pub struct Outbox {
+    connection: Arc<Mutex<Connection>>,
+    added: Notify,
+}
+
+impl Outbox {
+    /// Opens the outbox at `path`, creating it if needed. It blocks, so it is
+    /// meant for startup.
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Outbox {
+            connection: Arc::new(Mutex::new(connection)),
+            added: Notify::new(),
+        })
+    }
+
+    /// Stores `records`, all or none, and wakes the relay. Returns the ids of
+    /// their rows.
+    pub async fn add_all(&self, records: &[EventRecord]) -> rusqlite::Result<Vec<i64>> {
+        let records = records.to_vec();
+        let ids = self
+            .blocking(move |connection| {
+                let transaction = connection.transaction()?;
+                let mut ids = Vec::with_capacity(records.len());
+                for record in &records {
+                    let headers = serde_json::to_string(&record.headers).unwrap();
+                    transaction.execute(
+                        "INSERT INTO outbox (topic, key, partition, payload, headers)
+                         VALUES (?1, ?2, ?3, ?4, ?5)",
+                        params![
+                            record.topic,
+                            record.key,
+                            record.partition,
+                            record.payload,
+                            headers
+                        ],
+                    )?;
+                    ids.push(transaction.last_insert_rowid());
+                }
+                transaction.commit()?;
+                Ok(ids)
+            })
+            .await?;
+        self.added.notify_one();
+        Ok(ids)
+    }
+
+    /// Up to `limit` unsent rows, oldest first.
+    pub async fn pending(&self, limit: usize) -> rusqlite::Result<Vec<PendingEvent>> {
+        self.blocking(move |connection| {
+            let mut statement = connection.prepare_cached(
+                "SELECT id, topic, key, partition, payload, headers, attempts FROM outbox
+                 WHERE sent_at IS NULL ORDER BY id LIMIT ?1",
+            )?;
+            let rows = statement.query_map(params![limit as i64], |row| {
+                let headers: String = row.get(5)?;
+                Ok(PendingEvent {
+                    id: row.get(0)?,
+                    record: EventRecord {
+                        topic: row.get(1)?,
+                        key: row.get(2)?,
+                        partition: row.get(3)?,
+                        payload: row.get(4)?,
+                        headers: serde_json::from_str(&headers).map_err(|e| {
+                            rusqlite::Error::FromSqlConversionFailure(5, Type::Text, Box::new(e))
+                        })?,
+                    },
+                    attempts: row.get(6)?,
+                })
+            })?;
+            rows.collect()
+        })
+        .await
+    }
+
+    /// Records that row `id` was delivered to `partition` at `offset`.
+    pub async fn mark_sent(&self, id: i64, partition: i32, offset: i64) -> rusqlite::Result<()> {
+        let sent_at = millis_since_epoch(SystemTime::now());
+        self.blocking(move |connection| {
+            connection.execute(
+                "UPDATE outbox SET sent_partition = ?2, sent_offset = ?3, sent_at = ?4,
+                 last_error = NULL WHERE id = ?1",
+                params![id, partition, offset, sent_at],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Records a failed attempt to publish row `id`.
+    pub async fn mark_failed(&self, id: i64, error: &str) -> rusqlite::Result<()> {
+        let error = error.to_string();
+        self.blocking(move |connection| {
+            connection.execute(
+                "UPDATE outbox SET attempts = attempts + 1, last_error = ?2 WHERE id = ?1",
+                params![id, error],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Deletes the rows sent more than `retention` ago and returns how many.
+    pub async fn prune(&self, retention: Duration) -> rusqlite::Result<usize> {
+        let cutoff = millis_since_epoch(SystemTime::now() - retention);
+        self.blocking(move |connection| {
+            connection.execute(
+                "DELETE FROM outbox WHERE sent_at IS NOT NULL AND sent_at < ?1",
+                params![cutoff],
+            )
+        })
+        .await
+    }
+
+    /// Runs `call` with the connection on the blocking thread pool.
+    async fn blocking<T, F>(&self, call: F) -> rusqlite::Result<T>
+    where
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || call(&mut connection.lock().unwrap()))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+}
+
+fn millis_since_epoch(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// Why the relay stopped publishing a batch.
+#[derive(Debug)]
+pub enum RelayError {
+    Store(rusqlite::Error),
+    Publish { id: i64, error: KafkaError },
+}
+
+impl std::fmt::Display for RelayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RelayError::Store(e) => write!(f, "cannot read the outbox: {}", e),
+            RelayError::Publish { id, error } => {
+                write!(f, "cannot publish outbox row {}: {}", id, error)
+            }
+        }
+    }
+}
+
+impl From<rusqlite::Error> for RelayError {
+    fn from(e: rusqlite::Error) -> Self {
+        RelayError::Store(e)
+    }
+}
+
+/// Publishes the rows of an [`Outbox`].
+pub struct Relay {
+    outbox: Arc<Outbox>,
+    producer: FutureProducer,
+}
+
+impl Relay {
+    pub fn new(outbox: Arc<Outbox>, producer: FutureProducer) -> Self {
+        Relay { outbox, producer }
+    }
+
+    /// Publishes pending rows until `shutdown` is triggered, finishing the
+    /// row in hand first, and deletes sent rows past their retention. Rows
+    /// still pending are left for the next start.
+    pub async fn run(&self, shutdown: &Shutdown) {
+        let mut backoff = MIN_BACKOFF;
+        let mut next_prune = tokio::time::Instant::now();
+        while !shutdown.is_triggered() {
+            if tokio::time::Instant::now() >= next_prune {
+                match self.prune().await {
+                    Ok(0) => {}
+                    Ok(pruned) => println!("Deleted {} sent outbox rows", pruned),
+                    Err(e) => eprintln!("Error deleting sent outbox rows: {}", e),
+                }
+                next_prune += PRUNE_INTERVAL;
+            }
+            match self.relay_pending().await {
+                // A full batch means there may be more waiting
+                Ok(sent) if sent == BATCH_SIZE => backoff = MIN_BACKOFF,
+                Ok(_) => {
+                    backoff = MIN_BACKOFF;
+                    tokio::select! {
+                        _ = shutdown.triggered() => {}
+                        _ = self.outbox.added.notified() => {}
+                        _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error relaying outbox, retrying in {:?}: {}", backoff, e);
+                    tokio::select! {
+                        _ = shutdown.triggered() => {}
+                        _ = tokio::time::sleep(backoff) => {}
+                    }
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    /// Deletes the rows sent more than [`SENT_RETENTION`] ago and returns how
+    /// many.
+    pub async fn prune(&self) -> Result<usize, RelayError> {
+        Ok(self.outbox.prune(SENT_RETENTION).await?)
+    }
+
+    /// Publishes one batch of pending rows in order, stopping at the first
+    /// that fails. Returns how many were sent.
+    pub async fn relay_pending(&self) -> Result<usize, RelayError> {
+        let pending = self.outbox.pending(BATCH_SIZE).await?;
+        for event in &pending {
+            match self
+                .producer
+                .send(event.record.future_record(), ENQUEUE_TIMEOUT)
+                .await
+            {
+                Ok((partition, offset)) => {
+                    self.outbox.mark_sent(event.id, partition, offset).await?
+                }
+                Err((error, _)) => {
+                    self.outbox
+                        .mark_failed(event.id, &error.to_string())
+                        .await?;
+                    return Err(RelayError::Publish {
+                        id: event.id,
+                        error,
+                    });
+                }
+            }
+        }
+        Ok(pending.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::OptionalExtension;
+
+    fn sent(outbox: &Outbox, id: i64) -> Option<(i32, i64)> {
+        outbox
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT sent_partition, sent_offset FROM outbox
+                 WHERE id = ?1 AND sent_at IS NOT NULL",
+                params![id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .unwrap()
+    }
+
+    fn record(key: &str) -> EventRecord {
+        EventRecord {
+            topic: "products".to_string(),
+            key: key.to_string(),
+            partition: None,
+            payload: format!(r#"{{"id":"{}"}}"#, key),
+            headers: vec![("event-type".to_string(), "CREATED".to_string())],
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_rows_survive_reopening_the_outbox() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outbox.db");
+
+        let outbox = Outbox::open(&path).unwrap();
+        let ids = outbox
+            .add_all(&[record("first"), record("second")])
+            .await
+            .unwrap();
+        let (first, second) = (ids[0], ids[1]);
+        outbox.mark_failed(first, "broker down").await.unwrap();
+        outbox.mark_sent(second, 0, 7).await.unwrap();
+        drop(outbox);
+
+        let outbox = Outbox::open(&path).unwrap();
+        let pending = outbox.pending(10).await.unwrap();
+        assert_eq!(
+            pending,
+            vec![PendingEvent {
+                id: first,
+                record: record("first"),
+                attempts: 1,
+            }]
+        );
+        assert_eq!(sent(&outbox, first), None);
+        assert_eq!(sent(&outbox, second), Some((0, 7)));
+    }
+
+    #[tokio::test]
+    async fn rows_with_corrupt_headers_are_not_published_without_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Outbox::open(&dir.path().join("outbox.db")).unwrap();
+        let id = outbox.add_all(&[record("first")]).await.unwrap()[0];
+        outbox
+            .connection
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE outbox SET headers = 'not json' WHERE id = ?1",
+                params![id],
+            )
+            .unwrap();
+
+        assert!(matches!(
+            outbox.pending(10).await,
+            Err(rusqlite::Error::FromSqlConversionFailure(5, Type::Text, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn the_relay_deletes_rows_sent_before_the_retention_period() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = Arc::new(Outbox::open(&dir.path().join("outbox.db")).unwrap());
+        let ids = outbox
+            .add_all(&[record("old"), record("recent"), record("pending")])
+            .await
+            .unwrap();
+        let (old, recent, pending) = (ids[0], ids[1], ids[2]);
+        outbox.mark_sent(old, 0, 1).await.unwrap();
+        outbox.mark_sent(recent, 0, 2).await.unwrap();
+        let expired = millis_since_epoch(SystemTime::now() - SENT_RETENTION) - 1;
+        outbox
+            .connection
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE outbox SET sent_at = ?2 WHERE id = ?1",
+                params![old, expired],
+            )
+            .unwrap();
+        let producer: FutureProducer = rdkafka::ClientConfig::new()
+            .set("bootstrap.servers", "127.0.0.1:1")
+            .create()
+            .unwrap();
+        let relay = Relay::new(outbox.clone(), producer);
+
+        assert_eq!(relay.prune().await.unwrap(), 1);
+        let ids: Vec<i64> = {
+            let connection = outbox.connection.lock().unwrap();
+            let mut statement = connection
+                .prepare("SELECT id FROM outbox ORDER BY id")
+                .unwrap();
+            let ids = statement.query_map([], |row| row.get(0)).unwrap();
+            ids.collect::<rusqlite::Result<_>>().unwrap()
+        };
+        assert_eq!(ids, vec![recent, pending]);
+        assert_eq!(relay.prune().await.unwrap(), 0);
+    }
+}
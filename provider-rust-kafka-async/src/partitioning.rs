@@ -0,0 +1,56 @@
+//! How product events are spread over the partitions of the products topic.
+//!
+//! Every record is keyed by its product id, so by default the producer hashes
+//! the id and all events for one product land on one partition, where
+//! consumers read them in the order they were published. The `partitioning`
+//! setting can pin events to a partition instead, and a service can be given
+//! any other [`Partitioner`].
+
+use kafka_support::config::Partitioning;
+use product_domain::ProductEvent;
+
+/// Chooses the partition for an event.
+pub trait Partitioner: Send + Sync {
+    /// The partition to publish `event` to, or `None` to let the producer
+    /// hash its key.
+    fn partition(&self, event: &ProductEvent) -> Option<i32>;
+}
+
+/// The strategies that can be configured with the `partitioning` setting.
+impl Partitioner for Partitioning {
+    fn partition(&self, _event: &ProductEvent) -> Option<i32> {
+        match self {
+            Partitioning::KeyHash => None,
+            Partitioning::Explicit(partition) => Some(*partition),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use product_domain::{EventType, Product};
+
+    struct ByEventType;
+
+    impl Partitioner for ByEventType {
+        fn partition(&self, event: &ProductEvent) -> Option<i32> {
+            match event.event {
+                EventType::Deleted => Some(1),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn strategies_choose_the_record_partition() {
+        let product = Product::reference("some-uuid-1234-5678", Some("v1".parse().unwrap()));
+        let updated = product.clone().into_event(EventType::Updated).unwrap();
+        let deleted = product.into_event(EventType::Deleted).unwrap();
+
+        assert_eq!(Partitioning::KeyHash.partition(&updated), None);
+        assert_eq!(Partitioning::Explicit(2).partition(&updated), Some(2));
+        assert_eq!(ByEventType.partition(&updated), None);
+        assert_eq!(ByEventType.partition(&deleted), Some(1));
+    }
+}
@@ -0,0 +1,530 @@
+//! Product commands sent to the sync consumer over Kafka request/reply.
+//!
+//! The HTTP handlers turn a request into a [`ProductCommand`] and hand it to
+//! the [`CommandExecutor`], which publishes it, waits for the consumer's reply
+//! and maps every way that can fail to a [`CommandError`]. Instrumentation and
+//! retries belong in [`CommandExecutor::execute`], so all commands get them.
+
+use actix_web::dev::Payload;
+use actix_web::http::{header, StatusCode};
+use actix_web::{FromRequest, HttpRequest, HttpResponse, ResponseError};
+use kafka_support::headers;
+use kafka_support::http::{problem, InvalidDelete};
+use kafka_support::request_reply::{ReplyError, ReplyRouter};
+use kafka_support::transaction::Transactions;
+use product_domain::{
+    ErrorCode, ErrorEnvelope, EventMetadata, EventType, IdMismatch, Product, ProductEvent,
+    ProductReply, VersionOverflow,
+};
+use rdkafka::error::KafkaError;
+use rdkafka::message::Message;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use serde::Serialize;
+use std::fmt;
+use std::future::{ready, Ready};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The header a client sets to wait more or less than the default for the
+/// consumer's reply, in milliseconds.
+pub const REQUEST_TIMEOUT_HEADER: &str = "Request-Timeout";
+
+/// Identifies this service in the `producer-id` header of its requests.
+const PRODUCER_ID: &str = env!("CARGO_PKG_NAME");
+
+/// The longest a client may ask to wait for a reply.
+const MAX_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The `Retry-After` given to clients when a request cannot be published.
+const RETRY_AFTER_SECS: u64 = 5;
+
+/// A change the provider asks the consumer to make to a product.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProductCommand {
+    Create(Product),
+    Update(Product),
+    Delete(Product),
+}
+
+impl ProductCommand {
+    pub fn event_type(&self) -> EventType {
+        match self {
+            ProductCommand::Create(_) => EventType::Created,
+            ProductCommand::Update(_) => EventType::Updated,
+            ProductCommand::Delete(_) => EventType::Deleted,
+        }
+    }
+
+    /// The event the consumer is asked to apply.
+    pub fn into_event(self) -> Result<ProductEvent, VersionOverflow> {
+        let event_type = self.event_type();
+        match self {
+            ProductCommand::Create(product)
+            | ProductCommand::Update(product)
+            | ProductCommand::Delete(product) => product.into_event(event_type),
+        }
+    }
+}
+
+/// The event a command published and where its request landed, as returned
+/// by the write endpoints once the consumer has applied it, so clients can
+/// correlate with what consumers see.
+#[derive(Debug, Serialize)]
+pub struct Published {
+    pub event: ProductEvent,
+    pub partition: i32,
+    pub offset: i64,
+}
+
+/// How long a client asked to wait for the reply, if it asked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RequestTimeout(pub Option<Duration>);
+
+impl FromRequest for RequestTimeout {
+    type Error = CommandError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let Some(value) = req.headers().get(REQUEST_TIMEOUT_HEADER) else {
+            return ready(Ok(RequestTimeout(None)));
+        };
+        let timeout = value
+            .to_str()
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .map(Duration::from_millis)
+            .filter(|timeout| !timeout.is_zero() && *timeout <= MAX_REQUEST_TIMEOUT);
+        ready(match timeout {
+            Some(timeout) => Ok(RequestTimeout(Some(timeout))),
+            None => Err(CommandError::InvalidTimeout(format!(
+                "{} must be between 1 and {} milliseconds, got {:?}",
+                REQUEST_TIMEOUT_HEADER,
+                MAX_REQUEST_TIMEOUT.as_millis(),
+                value
+            ))),
+        })
+    }
+}
+
+/// Publishes commands to the request topic and waits for their replies.
+pub struct CommandExecutor {
+    producer: Arc<FutureProducer>,
+    transactions: Option<Transactions>,
+    router: Arc<ReplyRouter>,
+    request_topic: String,
+    reply_timeout: Duration,
+    failed_deliveries: AtomicU64,
+}
+
+impl CommandExecutor {
+    pub fn new(
+        producer: Arc<FutureProducer>,
+        router: Arc<ReplyRouter>,
+        request_topic: &str,
+        reply_timeout: Duration,
+    ) -> Self {
+        CommandExecutor {
+            producer,
+            transactions: None,
+            router,
+            request_topic: request_topic.to_string(),
+            reply_timeout,
+            failed_deliveries: AtomicU64::new(0),
+        }
+    }
+
+    /// Sends each command in its own transaction through `transactions`,
+    /// which wrap the executor's transactional producer.
+    pub fn with_transactions(mut self, transactions: Transactions) -> Self {
+        self.transactions = Some(transactions);
+        self
+    }
+
+    /// Sends `command` and returns its event once the consumer replied that
+    /// it applied it, waiting up to `timeout` or the default reply timeout.
+    pub async fn execute(
+        &self,
+        command: ProductCommand,
+        timeout: RequestTimeout,
+    ) -> Result<Published, CommandError> {
+        let event = command.into_event().map_err(CommandError::InvalidVersion)?;
+        let payload = serde_json::to_string(&event).expect("Error serializing product event");
+
+        let pending = self
+            .router
+            .register(timeout.0.unwrap_or(self.reply_timeout));
+        let correlation_id = pending.correlation_id().to_string();
+        let metadata = EventMetadata::new(&event, PRODUCER_ID);
+        let record = FutureRecord::to(&self.request_topic)
+            .key(&correlation_id)
+            .headers(headers::with_pairs(
+                self.router.request_headers(&pending),
+                metadata.headers(),
+            ))
+            .payload(&payload);
+        println!("sending message {}", payload);
+        // The deadline covers publishing too, which waits for the brokers up
+        // to message.timeout.ms. A request delivered after it carries its
+        // deadline, so the consumer drops it.
+        let deadline = tokio::time::Instant::from_std(pending.deadline());
+        let (partition, offset) = match tokio::time::timeout_at(deadline, self.send(record)).await {
+            Ok(sent) => sent.map_err(|e| self.send_failed(&event, e))?,
+            Err(_) => {
+                return Err(CommandError::NoReply {
+                    correlation_id,
+                    error: ReplyError::Timeout,
+                })
+            }
+        };
+
+        let reply = pending
+            .wait()
+            .await
+            .map_err(|error| CommandError::NoReply {
+                correlation_id: correlation_id.clone(),
+                error,
+            })?;
+        let reply = reply
+            .payload()
+            .ok_or_else(|| CommandError::InvalidReply("the reply has no payload".to_string()))?;
+        match serde_json::from_slice(reply) {
+            Ok(ProductReply::Product(_)) => Ok(Published {
+                event,
+                partition,
+                offset,
+            }),
+            Ok(ProductReply::Error { error }) => Err(CommandError::Rejected(error)),
+            Err(e) => Err(CommandError::InvalidReply(e.to_string())),
+        }
+    }
+
+    /// Publishes a request, in a transaction of its own in transactional
+    /// mode, and returns the partition and offset it landed at. Dropping the
+    /// future leaves an open transaction to be aborted.
+    async fn send(
+        &self,
+        record: FutureRecord<'_, String, String>,
+    ) -> Result<(i32, i64), KafkaError> {
+        match &self.transactions {
+            Some(transactions) => {
+                let transaction = transactions.begin().await?;
+                match transaction
+                    .producer()
+                    .send(record, Duration::from_secs(0))
+                    .await
+                {
+                    Ok(delivery) => {
+                        transaction.commit().await?;
+                        Ok(delivery)
+                    }
+                    Err((e, _)) => Err(transaction.abort_with(e).await),
+                }
+            }
+            None => self
+                .producer
+                .send(record, Duration::from_secs(0))
+                .await
+                .map_err(|(e, _)| e),
+        }
+    }
+
+    /// Counts and logs a request that could not be published.
+    fn send_failed(&self, event: &ProductEvent, error: KafkaError) -> CommandError {
+        let failed = self.failed_deliveries.fetch_add(1, Ordering::Relaxed) + 1;
+        eprintln!(
+            "Error publishing request for product {} ({} failed so far): {}",
+            event.id, failed, error
+        );
+        CommandError::Send(error)
+    }
+}
+
+/// Why a command did not produce a product.
+#[derive(Debug)]
+pub enum CommandError {
+    /// The product's version cannot be bumped.
+    InvalidVersion(VersionOverflow),
+    /// The client asked for a timeout out of range.
+    InvalidTimeout(String),
+    /// The request body names a different product than its path.
+    IdMismatch(IdMismatch),
+    /// A delete does not name the product it removes.
+    InvalidDelete(InvalidDelete),
+    /// The request could not be published.
+    Send(KafkaError),
+    /// The consumer did not reply in time.
+    NoReply {
+        correlation_id: String,
+        error: ReplyError,
+    },
+    /// The consumer could not apply the command.
+    Rejected(ErrorEnvelope),
+    /// The consumer's reply could not be understood.
+    InvalidReply(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::InvalidVersion(e) => e.fmt(f),
+            CommandError::InvalidTimeout(e) => f.write_str(e),
+            CommandError::IdMismatch(e) => e.fmt(f),
+            CommandError::InvalidDelete(e) => e.fmt(f),
+            CommandError::Send(e) => write!(f, "cannot send request: {}", e),
+            CommandError::NoReply {
+                correlation_id,
+                error,
+            } => write!(f, "no reply to request {}: {}", correlation_id, error),
+            CommandError::Rejected(e) => e.fmt(f),
+            CommandError::InvalidReply(e) => write!(f, "invalid reply: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<InvalidDelete> for CommandError {
+    fn from(e: InvalidDelete) -> Self {
+        CommandError::InvalidDelete(e)
+    }
+}
+
+/// The HTTP status for an error the consumer replied with.
+fn error_status(code: ErrorCode) -> StatusCode {
+    match code {
+        ErrorCode::NotFound => StatusCode::NOT_FOUND,
+        ErrorCode::StaleVersion => StatusCode::CONFLICT,
+        ErrorCode::UnknownEventType | ErrorCode::MalformedEvent => StatusCode::UNPROCESSABLE_ENTITY,
+    }
+}
+
+impl ResponseError for CommandError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            CommandError::InvalidVersion(_)
+            | CommandError::InvalidTimeout(_)
+            | CommandError::IdMismatch(_) => StatusCode::BAD_REQUEST,
+            CommandError::InvalidDelete(e) => e.status_code(),
+            CommandError::Send(_) => StatusCode::SERVICE_UNAVAILABLE,
+            CommandError::NoReply { .. } => StatusCode::GATEWAY_TIMEOUT,
+            CommandError::Rejected(e) => error_status(e.code),
+            CommandError::InvalidReply(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        match self {
+            CommandError::InvalidVersion(e) => {
+                problem(status, "Invalid product version", &e.to_string())
+            }
+            CommandError::InvalidTimeout(e) => problem(status, "Invalid request timeout", e),
+            CommandError::IdMismatch(e) => problem(status, "Product id mismatch", &e.to_string()),
+            CommandError::InvalidDelete(e) => e.error_response(),
+            CommandError::Send(_) => {
+                let mut response = problem(status, "Request not sent", &self.to_string());
+                response.headers_mut().insert(
+                    header::RETRY_AFTER,
+                    header::HeaderValue::from(RETRY_AFTER_SECS),
+                );
+                response
+            }
+            // The correlation id lets the request be traced through Kafka
+            CommandError::NoReply { correlation_id, .. } => {
+                eprintln!("{}", self);
+                HttpResponse::build(status)
+                    .content_type("application/problem+json")
+                    .json(serde_json::json!({
+                        "type": "about:blank",
+                        "title": "No reply before the deadline",
+                        "status": status.as_u16(),
+                        "detail": self.to_string(),
+                        "correlation_id": correlation_id,
+                    }))
+            }
+            CommandError::Rejected(e) => problem(status, e.code.as_str(), &e.message),
+            CommandError::InvalidReply(e) => problem(status, "Invalid reply", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(code: ErrorCode) -> CommandError {
+        CommandError::Rejected(ErrorEnvelope {
+            code,
+            message: "rejected".to_string(),
+        })
+    }
+
+    #[test]
+    fn maps_errors_to_statuses() {
+        assert_eq!(
+            rejected(ErrorCode::NotFound).status_code(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            rejected(ErrorCode::StaleVersion).status_code(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            rejected(ErrorCode::UnknownEventType).status_code(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            rejected(ErrorCode::MalformedEvent).status_code(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            CommandError::InvalidReply("not a reply".to_string()).status_code(),
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(
+            CommandError::NoReply {
+                correlation_id: "some-correlation-id".to_string(),
+                error: ReplyError::Timeout,
+            }
+            .status_code(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+    }
+
+    #[actix_web::test]
+    async fn unsent_requests_are_counted_and_can_be_retried() {
+        use rdkafka::mocking::MockCluster;
+        use rdkafka::producer::Producer;
+
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("product_request", 1, 1).unwrap();
+        let producer: FutureProducer = rdkafka::ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .set("message.timeout.ms", "100")
+            .create()
+            .unwrap();
+        let executor = CommandExecutor::new(
+            Arc::new(producer.clone()),
+            Arc::new(ReplyRouter::new("product_reply")),
+            "product_request",
+            Duration::from_secs(1),
+        );
+        let command = ProductCommand::Create(Product {
+            id: None,
+            name: "Some Product".to_string(),
+            r#type: "Product Range".to_string(),
+            version: None,
+        });
+
+        cluster.broker_down(1).unwrap();
+        let error = executor
+            .execute(command.clone(), RequestTimeout(None))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, CommandError::Send(_)), "{:?}", error);
+        assert_eq!(executor.failed_deliveries.load(Ordering::Relaxed), 1);
+        let response = error.error_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers().get(header::RETRY_AFTER).unwrap(),
+            &RETRY_AFTER_SECS.to_string()
+        );
+
+        // Retried once the brokers are back, the request is published; no
+        // consumer replies to it here
+        cluster.broker_up(1).unwrap();
+        producer
+            .client()
+            .fetch_watermarks("product_request", 0, Duration::from_secs(5))
+            .unwrap();
+        let error = executor
+            .execute(command, RequestTimeout(Some(Duration::from_secs(1))))
+            .await
+            .unwrap_err();
+        assert!(matches!(error, CommandError::NoReply { .. }), "{:?}", error);
+        assert_eq!(executor.failed_deliveries.load(Ordering::Relaxed), 1);
+        let (_, high) = producer
+            .client()
+            .fetch_watermarks("product_request", 0, Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(high, 1);
+    }
+
+    #[actix_web::test]
+    async fn requests_the_brokers_cannot_take_time_out_at_their_deadline() {
+        // Without message.timeout.ms, librdkafka retries for five minutes
+        let producer: FutureProducer = rdkafka::ClientConfig::new()
+            .set("bootstrap.servers", "127.0.0.1:1")
+            .create()
+            .unwrap();
+        let executor = CommandExecutor::new(
+            Arc::new(producer),
+            Arc::new(ReplyRouter::new("product_reply")),
+            "product_request",
+            Duration::from_secs(60),
+        );
+        let command = ProductCommand::Create(Product {
+            id: None,
+            name: "Some Product".to_string(),
+            r#type: "Product Range".to_string(),
+            version: None,
+        });
+
+        let error = tokio::time::timeout(
+            Duration::from_secs(5),
+            executor.execute(command, RequestTimeout(Some(Duration::from_millis(200)))),
+        )
+        .await
+        .expect("the request outlived its deadline")
+        .unwrap_err();
+        let CommandError::NoReply { correlation_id, .. } = &error else {
+            panic!("{:?}", error);
+        };
+        let response = error.error_response();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["correlation_id"], correlation_id.as_str());
+    }
+
+    #[actix_web::test]
+    async fn reads_the_request_timeout_header() {
+        let timeout = |value: Option<&str>| {
+            let mut req = actix_web::test::TestRequest::default();
+            if let Some(value) = value {
+                req = req.insert_header((REQUEST_TIMEOUT_HEADER, value));
+            }
+            let (req, mut payload) = req.to_http_parts();
+            RequestTimeout::from_request(&req, &mut payload).into_inner()
+        };
+
+        assert_eq!(timeout(None).unwrap(), RequestTimeout(None));
+        assert_eq!(
+            timeout(Some("250")).unwrap(),
+            RequestTimeout(Some(Duration::from_millis(250)))
+        );
+        for invalid in ["0", "soon", "600000"] {
+            assert_eq!(
+                timeout(Some(invalid)).unwrap_err().status_code(),
+                StatusCode::BAD_REQUEST
+            );
+        }
+    }
+
+    #[test]
+    fn commands_bump_the_version_of_their_event() {
+        let product = Product {
+            id: Some("some-uuid-1234-5678".to_string()),
+            name: "Some Product".to_string(),
+            r#type: "Product Range".to_string(),
+            version: Some("v1".parse().unwrap()),
+        };
+
+        let event = ProductCommand::Delete(product).into_event().unwrap();
+        assert_eq!(event.event, EventType::Deleted);
+        assert_eq!(event.version.to_string(), "v2");
+    }
+}
@@ -1,188 +1,166 @@
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
-use futures::TryStreamExt;
-use rdkafka::config::ClientConfig;
-use rdkafka::producer::{FutureProducer, FutureRecord};
-use rdkafka::consumer::{StreamConsumer, Consumer};
-use rdkafka::message::Message;
-use serde::{Deserialize, Serialize};
+use actix_web::http::header;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use kafka_support::config::{ProducerMode, Settings};
+use kafka_support::health::{self, ProducerProbe};
+use kafka_support::http;
+use kafka_support::request_reply::{self, ReplyRouter};
+use kafka_support::shutdown::{self, Shutdown};
+use kafka_support::transaction::Transactions;
+use product_domain::Product;
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::producer::{FutureProducer, Producer};
+use std::process::ExitCode;
 use std::sync::Arc;
-use tokio::time::timeout;
 use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Product {
-    id: Option<String>,
-    name: String,
-    r#type: String,
-    version: Option<String>,
-}
+mod command;
 
-#[derive(Serialize, Deserialize)]
-pub struct ProductEvent {
-    id: String,
-    name: String,
-    r#type: String,
-    version: String,
-    event: String,
-}
+/// How long each call to the brokers in a request transaction may take.
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(10);
 
-pub fn create_event(product: Product, event_type: &str) -> ProductEvent {
-    let version = increment_version(product.version);
-    ProductEvent {
-        id: product
-            .id
-            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
-        name: product.name,
-        r#type: product.r#type,
-        event: event_type.to_string(),
-        version,
-    }
-}
-fn increment_version(version: Option<String>) -> String {
-    match version {
-        Some(v) => {
-            let num: u32 = v[1..].parse().unwrap();
-            format!("v{}", num + 1)
-        }
-        None => "v1".to_string(),
-    }
-}
+use command::{CommandError, CommandExecutor, ProductCommand, RequestTimeout};
 
 async fn create_product(
     product: web::Json<Product>,
-    producer: web::Data<Arc<FutureProducer>>,
-    consumer: web::Data<Arc<StreamConsumer>>,
-) -> impl Responder {
-    let request_topic = "product_request";
-    let correlation_id = uuid::Uuid::new_v4().to_string();
-    let event = create_event(product.into_inner(),"CREATED");
-    let payload = serde_json::to_string(&event).unwrap();
-    let record = FutureRecord::to(request_topic)
-        .key(&correlation_id)
-        .payload(&payload);
-    println!("sending message {}", payload);
-    producer.send(record, Duration::from_secs(0)).await.unwrap();
-
-    let mut stream = consumer.stream();
-    'outer: while let Some(message) = match timeout(Duration::from_secs(5), stream.try_next()).await {
-        Ok(Ok(msg)) => msg,
-        Ok(Err(_)) | Err(_) => return HttpResponse::InternalServerError().body("Failed to get response"),
-    } {
-        if let Some(payload) = message.payload() {
-            consumer.commit_message(&message, rdkafka::consumer::CommitMode::Async).unwrap();
-            let response: Product = serde_json::from_slice(payload).unwrap();
-            return HttpResponse::Ok().json(response);
-        } else {
-            continue 'outer;
-        }
-    }
-
-    HttpResponse::InternalServerError().body("Failed to get response")
+    timeout: RequestTimeout,
+    executor: web::Data<CommandExecutor>,
+) -> Result<HttpResponse, CommandError> {
+    let command = ProductCommand::Create(product.into_inner());
+    let published = executor.execute(command, timeout).await?;
+    Ok(HttpResponse::Created()
+        .insert_header((header::LOCATION, format!("/products/{}", published.event.id)))
+        .json(published))
 }
+
 async fn update_product(
+    id: web::Path<String>,
     product: web::Json<Product>,
-    producer: web::Data<Arc<FutureProducer>>,
-    consumer: web::Data<Arc<StreamConsumer>>,
-) -> impl Responder {
-    let request_topic = "product_request";
-    let correlation_id = uuid::Uuid::new_v4().to_string();
-    let event = create_event(product.into_inner(),"UPDATED");
-
-    let payload = serde_json::to_string(&event).unwrap();
-    let record = FutureRecord::to(request_topic)
-        .key(&correlation_id)
-        .payload(&payload);
-    println!("sending message {}", payload);
-    producer.send(record, Duration::from_secs(0)).await.unwrap();
-
-    let mut stream = consumer.stream();
-    'outer: while let Some(message) = match timeout(Duration::from_secs(5), stream.try_next()).await {
-        Ok(Ok(msg)) => msg,
-        Ok(Err(_)) | Err(_) => return HttpResponse::InternalServerError().body("Failed to get response"),
-    } {
-        if let Some(payload) = message.payload() {
-            consumer.commit_message(&message, rdkafka::consumer::CommitMode::Async).unwrap();
-            let response: Product = serde_json::from_slice(payload).unwrap();
-            return HttpResponse::Ok().json(response);
-        } else {
-            continue 'outer;
-        }
-    }
-
-    HttpResponse::InternalServerError().body("Failed to get response")
+    timeout: RequestTimeout,
+    executor: web::Data<CommandExecutor>,
+) -> Result<HttpResponse, CommandError> {
+    let product = product.into_inner().with_id(&id).map_err(CommandError::IdMismatch)?;
+    let command = ProductCommand::Update(product);
+    let published = executor.execute(command, timeout).await?;
+    Ok(HttpResponse::Ok().json(published))
 }
-async fn delete_product(
-    product: web::Json<Product>,
-    producer: web::Data<Arc<FutureProducer>>,
-    consumer: web::Data<Arc<StreamConsumer>>,
-) -> impl Responder {
-    let request_topic = "product_request";
-    let correlation_id = uuid::Uuid::new_v4().to_string();
-    let event = create_event(product.into_inner(),"DELETED");
-
-    let payload = serde_json::to_string(&event).unwrap();
-    let record = FutureRecord::to(request_topic)
-        .key(&correlation_id)
-        .payload(&payload);
-    println!("sending message {}", payload);
-    producer.send(record, Duration::from_secs(0)).await.unwrap();
-
-    let mut stream = consumer.stream();
-    'outer: while let Some(message) = match timeout(Duration::from_secs(5), stream.try_next()).await {
-        Ok(Ok(msg)) => msg,
-        Ok(Err(_)) | Err(_) => return HttpResponse::InternalServerError().body("Failed to get response"),
-    } {
-        if let Some(payload) = message.payload() {
-            consumer.commit_message(&message, rdkafka::consumer::CommitMode::Async).unwrap();
-            let response: Product = serde_json::from_slice(payload).unwrap();
-            return HttpResponse::Ok().json(response);
-        } else {
-            continue 'outer;
-        }
-    }
 
-    HttpResponse::InternalServerError().body("Failed to get response")
+async fn delete_product(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Bytes,
+    timeout: RequestTimeout,
+    executor: web::Data<CommandExecutor>,
+) -> Result<HttpResponse, CommandError> {
+    let command = ProductCommand::Delete(http::product_to_delete(&req, &id, &body)?);
+    let published = executor.execute(command, timeout).await?;
+    Ok(HttpResponse::Ok().json(published))
 }
 
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let product_reply_topic = "product_reply";
+async fn main() -> std::io::Result<ExitCode> {
+    let settings = Settings::builder("127.0.0.1:8081")
+        .group_id("product_group")
+        .topic("request", "product_request")
+        .topic("reply", "product_reply")
+        .load()
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(2)
+        });
+    println!("Kafka client settings: {}", settings);
 
-    let producer: FutureProducer = ClientConfig::new()
-        .set("bootstrap.servers", "localhost:9092")
+    let producer: FutureProducer = settings
+        .producer_config()
         .create()
         .expect("Producer creation error");
 
-    let consumer: StreamConsumer = ClientConfig::new()
-        .set("group.id", "product_group")
-        .set("bootstrap.servers", "localhost:9092")
-        .set("enable.auto.commit", "false")
-        .set("auto.offset.reset", "earliest")
+    let consumer: StreamConsumer = request_reply::listener_config(&settings)
         .create()
         .expect("Consumer creation error");
 
-    consumer.subscribe(&[product_reply_topic]).expect("Subscription error");
+    consumer.subscribe(&[settings.topic("reply")]).expect("Subscription error");
 
     let producer = Arc::new(producer);
     let consumer = Arc::new(consumer);
-
-    HttpServer::new(move || {
+    let bind = settings.bind();
+    let shutdown_timeout = settings.shutdown_timeout();
+    let shutdown = Shutdown::new();
+    let probe = web::Data::new(
+        ProducerProbe::new(
+            producer.as_ref().clone(),
+            settings.topics().map(str::to_string).collect(),
+            shutdown.clone(),
+            Duration::from_secs(5),
+        )
+        .with_reply_consumer(consumer.clone()),
+    );
+    let router = Arc::new(ReplyRouter::new(settings.topic("reply")));
+    let mut executor = CommandExecutor::new(
+        producer.clone(),
+        router.clone(),
+        settings.topic("request"),
+        settings.reply_timeout(),
+    );
+    if settings.producer_mode() == ProducerMode::Transactional {
+        let transactions = Transactions::init(producer.as_ref().clone(), TRANSACTION_TIMEOUT)
+            .await
+            .expect("Transactional producer initialisation error");
+        executor = executor.with_transactions(transactions);
+    }
+    let executor = web::Data::new(executor);
+
+    // One listener reads every reply and hands it to the request waiting for
+    // it. It keeps listening until the server has stopped, so requests still
+    // in flight at shutdown get their replies.
+    let server_stopped = Shutdown::new();
+    let listener = actix_web::rt::spawn({
+        let router = router.clone();
+        let consumer = consumer.clone();
+        let server_stopped = server_stopped.clone();
+        async move { router.listen(&consumer, &server_stopped).await }
+    });
+
+    let server = HttpServer::new(move || {
         App::new()
-            .app_data(web::Data::new(producer.clone()))
-            .app_data(web::Data::new(consumer.clone()))
+            .app_data(probe.clone())
+            .app_data(executor.clone())
+            .app_data(web::JsonConfig::default().error_handler(http::json_error_handler))
+            .route("/healthz", web::get().to(health::healthz))
+            .route("/readyz", web::get().to(health::readyz::<ProducerProbe>))
             .route("/products", web::post().to(create_product))
             .route("/products/{id}", web::put().to(update_product))
             .route("/products/{id}", web::delete().to(delete_product))
     })
-    .bind("127.0.0.1:8081")?
-    .run()
-    .await
+    .bind(bind)?
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout.as_secs())
+    .run();
+
+    // In-flight requests wait for their replies before the server stops
+    let server_handle = server.handle();
+    let on_signal = shutdown.clone();
+    actix_web::rt::spawn(shutdown::stop_on_signal(async move {
+        on_signal.trigger();
+        server_handle.stop(true).await;
+    }));
+
+    server.await?;
+    server_stopped.trigger();
+    let _ = listener.await;
+
+    let clean = match producer.flush(shutdown_timeout) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Error flushing producer on shutdown: {}", e);
+            false
+        }
+    };
+    Ok(shutdown::exit_code(clean))
 }
 
 #[cfg(test)]
 mod tests {
 
-    use crate::{create_event, Product};
     use actix_web::http::header::HeaderName;
     use actix_web::http::header::HeaderValue;
     use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
@@ -196,6 +174,7 @@ mod tests {
         NullRequestFilterExecutor, PactSource, ProviderInfo, ProviderTransport,
         VerificationOptions,
     };
+    use product_domain::{EventType, ProcessError, Product, ProductReply};
     use reqwest::Client;
     use serde_json::json;
     use serde_json::Value;
@@ -216,8 +195,8 @@ mod tests {
             Ok(hashmap! {})
         }
 
-        fn teardown(self: &Self) -> bool {
-            return false;
+        fn teardown(&self) -> bool {
+            false
         }
     }
 
@@ -244,10 +223,9 @@ mod tests {
                         id: Some("some-uuid-1234-5678".to_string()),
                         name: "Some Product".to_string(),
                         r#type: "Product Range".to_string(),
-                        version: Some("v1".to_string()),
+                        version: Some("v1".parse().unwrap()),
                     };
-                    let event_type = "UPDATED";
-                    let product_event = create_event(product, event_type);
+                    let product_event = product.into_event(EventType::Updated).unwrap();
                     let mut response = HttpResponse::Ok().json(product_event);
                     let response_metadata = json!({
                       "kafka_reply_topic": "product_reply"
@@ -260,6 +238,24 @@ mod tests {
                     );
                     response
                 }
+                Some("a stale product event update with error reply") => {
+                    let stale = ProcessError::StaleVersion {
+                        id: "some-uuid-1234-5678".to_string(),
+                        stored: "v2".parse().unwrap(),
+                        received: "v1".parse().unwrap(),
+                    };
+                    let mut response = HttpResponse::Ok().json(ProductReply::from(Err(&stale)));
+                    let response_metadata = json!({
+                      "kafka_reply_topic": "product_reply"
+                    });
+                    let encoded_metadata =
+                        general_purpose::STANDARD.encode(response_metadata.to_string());
+                    response.headers_mut().insert(
+                        HeaderName::from_static("pact-message-metadata"),
+                        HeaderValue::from_str(&encoded_metadata).unwrap(),
+                    );
+                    response
+                }
                 _ => HttpResponse::NotFound().finish(),
             }
         }